@@ -0,0 +1,131 @@
+use std::io::Read;
+
+use flate2::read::{DeflateDecoder, GzDecoder};
+
+use crate::error::GbaError;
+use crate::memory::MAX_ROM_SIZE;
+use crate::utils::crc32;
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const ZIP_LOCAL_HEADER: u32 = 0x0403_4B50;
+const ZIP_CENTRAL_HEADER: u32 = 0x0201_4B50;
+const ZIP_END_OF_DIRECTORY: u32 = 0x0605_4B50;
+const ZIP_STORED: u16 = 0;
+const ZIP_DEFLATED: u16 = 8;
+
+/// Pulls the image out of a .zip or .gz file, anything else is passed through untouched. `name` is
+/// the file name, returned with the name of the extracted entry in its place. Zips use `entry` if
+/// given, the first .gba file otherwise.
+pub fn extract(data: Vec<u8>, name: &str, entry: Option<&str>) -> Result<(Vec<u8>, String), GbaError> {
+    if data.starts_with(&GZIP_MAGIC) {
+        return extract_gzip(&data, name);
+    }
+    if data.len() >= 4 && read_u32(&data, 0) == ZIP_LOCAL_HEADER {
+        return extract_zip(&data, entry);
+    }
+    Ok((data, name.to_string()))
+}
+
+fn extract_gzip(data: &[u8], name: &str) -> Result<(Vec<u8>, String), GbaError> {
+    let mut decoder = GzDecoder::new(data);
+    let image = decompress(&mut decoder)?;
+    let inner_name = decoder.header()
+        .and_then(|header| header.filename())
+        .map(|filename| String::from_utf8_lossy(filename).into_owned())
+        .unwrap_or_else(|| name.strip_suffix(".gz").unwrap_or(name).to_string());
+    Ok((image, inner_name))
+}
+
+struct ZipEntry<'a> {
+    name: String,
+    method: u16,
+    encrypted: bool,
+    crc: u32,
+    data: &'a [u8]
+}
+
+fn extract_zip(data: &[u8], entry: Option<&str>) -> Result<(Vec<u8>, String), GbaError> {
+    let entries = zip_entries(data)?;
+    let found = match entry {
+        Some(wanted) => entries.into_iter()
+            .find(|candidate| candidate.name == wanted)
+            .ok_or_else(|| GbaError::ArchiveEntryNotFound(wanted.to_string()))?,
+        None => entries.into_iter()
+            .find(|candidate| candidate.name.to_ascii_lowercase().ends_with(".gba"))
+            .ok_or_else(|| GbaError::ArchiveEntryNotFound("*.gba".to_string()))?,
+    };
+    if found.encrypted {
+        return Err(GbaError::InvalidArchive("Encrypted entries aren't supported"));
+    }
+    let image = match found.method {
+        ZIP_STORED => found.data.to_vec(),
+        ZIP_DEFLATED => decompress(&mut DeflateDecoder::new(found.data))?,
+        _ => return Err(GbaError::InvalidArchive("The entry uses an unsupported compression method")),
+    };
+    if crc32(&image) != found.crc {
+        return Err(GbaError::InvalidArchive("The entry's checksum doesn't match"));
+    }
+    Ok((image, found.name))
+}
+
+// Reads the central directory at the end of the file, the local headers can leave the sizes out
+fn zip_entries(data: &[u8]) -> Result<Vec<ZipEntry<'_>>, GbaError> {
+    const TRUNCATED: GbaError = GbaError::InvalidArchive("The zip file is truncated");
+    // The end record is 22 bytes followed by a comment of up to 64 KiB
+    let end = (0..data.len().saturating_sub(21))
+        .rev()
+        .take(0x10000)
+        .find(|&offset| read_u32(data, offset) == ZIP_END_OF_DIRECTORY)
+        .ok_or(GbaError::InvalidArchive("The zip file has no central directory"))?;
+    let count = read_u16(data, end + 10) as usize;
+    let mut offset = read_u32(data, end + 16) as usize;
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        if offset + 46 > data.len() || read_u32(data, offset) != ZIP_CENTRAL_HEADER {
+            return Err(TRUNCATED);
+        }
+        let flags = read_u16(data, offset + 8);
+        let method = read_u16(data, offset + 10);
+        let crc = read_u32(data, offset + 16);
+        let compressed_size = read_u32(data, offset + 20) as usize;
+        let name_length = read_u16(data, offset + 28) as usize;
+        let extra_length = read_u16(data, offset + 30) as usize;
+        let comment_length = read_u16(data, offset + 32) as usize;
+        let local_offset = read_u32(data, offset + 42) as usize;
+        let name = data.get(offset + 46..offset + 46 + name_length).ok_or(TRUNCATED)?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        offset += 46 + name_length + extra_length + comment_length;
+
+        if compressed_size == u32::MAX as usize || local_offset == u32::MAX as usize {
+            return Err(GbaError::InvalidArchive("Zip64 archives aren't supported"));
+        }
+        if local_offset + 30 > data.len() || read_u32(data, local_offset) != ZIP_LOCAL_HEADER {
+            return Err(TRUNCATED);
+        }
+        let data_start = local_offset + 30
+            + read_u16(data, local_offset + 26) as usize
+            + read_u16(data, local_offset + 28) as usize;
+        let entry_data = data.get(data_start..data_start + compressed_size).ok_or(TRUNCATED)?;
+        if !name.ends_with('/') {
+            entries.push(ZipEntry { name, method, encrypted: flags & 1 != 0, crc, data: entry_data });
+        }
+    }
+    Ok(entries)
+}
+
+// Stops one byte past the largest ROM, so archives that expand to gigabytes fail early
+fn decompress(decoder: &mut impl Read) -> Result<Vec<u8>, GbaError> {
+    let mut image = Vec::new();
+    decoder.take(MAX_ROM_SIZE as u64 + 1)
+        .read_to_end(&mut image)
+        .map_err(|_| GbaError::InvalidArchive("The compressed data is corrupt"))?;
+    Ok(image)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    data.get(offset..offset + 2).map_or(0, |bytes| u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    data.get(offset..offset + 4).map_or(0, |bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+}
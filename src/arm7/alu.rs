@@ -1,11 +1,11 @@
-use crate::{ check_bit, get_register_number_at };
+use crate::{ bus::Bus, check_bit, get_register_number_at };
 
 use super::constants::*;
 use super::cpu::Cpu;
 
 impl Cpu {
     pub(super) fn get_operand2(
-        &mut self,
+        &mut self, bus: &mut dyn Bus,
         operand2_type: Operand2Type,
         shift_type: ShiftType,
         set_condition_codes: bool,
@@ -15,7 +15,7 @@ impl Cpu {
         match operand2_type {
             Operand2Type::RegisterWithRegisterShift => {
                 self.registers[15] += 4;
-                self.memory.borrow_mut().add_clock_cycles(1);
+                bus.add_clock_cycles(1);
                 let operand = self.registers[get_register_number_at!(opcode, 0)];
                 let value = self.registers[get_register_number_at!(opcode, 8)] & 0xff;
                 self.barrel_shifter(value, operand, shift_type, true, set_condition_codes)
@@ -36,7 +36,7 @@ impl Cpu {
                     set_condition_codes
                 )
             }
-            Operand2Type::Immediate => opcode & 0xff,
+            Operand2Type::Immediate => opcode & 0xfff,
         }
     }
 
@@ -73,7 +73,8 @@ impl Cpu {
                 if value == 0 && !register_specified_shift {
                     value = 32;
                 }
-                carry_bit = (1u32).checked_shl(value.wrapping_sub(1)).unwrap_or(0);
+                // Shifts past 32 keep filling with the sign bit, which is also the carry
+                carry_bit = (1u32).checked_shl(value.wrapping_sub(1)).unwrap_or(0x8000_0000);
                 new_operand = (operand as i32)
                     .checked_shr(value)
                     .unwrap_or((operand as i32) >> 31) as u32;
@@ -207,6 +208,7 @@ impl Cpu {
         )
     }
 
+    // Operand 2 is the minuend, so overflow is a result whose sign differs from operand 2
     fn right_subtract(
         &mut self,
         operand_1: u32,
@@ -219,7 +221,7 @@ impl Cpu {
             result,
             !carry,
             check_bit!(operand_2, 31) == check_bit!(!operand_1, 31) &&
-                check_bit!(operand_1, 31) != check_bit!(result, 31),
+                check_bit!(operand_2, 31) != check_bit!(result, 31),
         )
     }
 
@@ -245,20 +247,26 @@ impl Cpu {
         destination_register: usize,
         operand_2: u32
     ) -> (u32, bool, bool) {
-        let operand_2 = operand_2.wrapping_add(check_bit!(self.cpsr_register, 29) as u32);
-        self.add(operand_1, destination_register, operand_2)
+        // Added in two steps, folding the carry into an operand first can overflow it
+        let (partial, carry_1) = operand_1.overflowing_add(operand_2);
+        let (result, carry_2) = partial.overflowing_add(check_bit!(self.cpsr_register, 29) as u32);
+        self.registers[destination_register] = result;
+        (
+            result,
+            carry_1 || carry_2,
+            check_bit!(operand_1, 31) == check_bit!(operand_2, 31) &&
+                check_bit!(operand_1, 31) != check_bit!(result, 31),
+        )
     }
 
+    // x - y - !C is x + !y + C, whose carry out is the ARM's inverted borrow
     fn subtract_carry(
         &mut self,
         operand_1: u32,
         destination_register: usize,
         operand_2: u32
     ) -> (u32, bool, bool) {
-        let operand_2 = operand_2.wrapping_sub(
-            u32::MAX * (!check_bit!(self.cpsr_register, 29) as u32)
-        );
-        self.subtract(operand_1, destination_register, operand_2)
+        self.add_carry(operand_1, destination_register, !operand_2)
     }
 
     fn right_subtract_carry(
@@ -267,10 +275,7 @@ impl Cpu {
         destination_register: usize,
         operand_2: u32
     ) -> (u32, bool, bool) {
-        let operand_1 = operand_1.wrapping_sub(
-            u32::MAX * (!check_bit!(self.cpsr_register, 29) as u32)
-        );
-        self.right_subtract(operand_1, destination_register, operand_2)
+        self.add_carry(operand_2, destination_register, !operand_1)
     }
 
     fn tst_and(operand_1: u32, operand_2: u32) -> (u32, bool, bool) {
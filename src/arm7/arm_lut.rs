@@ -1,10 +1,10 @@
 use core::panic;
 
-use crate::{check_bit, get_register_number_at};
+use crate::{bus::Bus, check_bit, get_register_number_at};
 
 use super::{constants::*, cpu::Cpu};
 
-fn dummy(cpu: &mut Cpu, foo: u32) { }
+fn dummy(cpu: &mut Cpu, _bus: &mut dyn Bus, foo: u32) { }
 
 pub const fn arm_instruction_lut() -> [InstructionHandler; 4096] {
     let dummy: InstructionHandler = dummy;
@@ -84,20 +84,20 @@ pub(super) const fn decode_arm(bits27_20: u8, bits7_4: u8) -> InstructionHandler
     }
 }
 
-fn branch_handler(cpu: &mut Cpu, opcode: u32) {
+fn branch_handler(cpu: &mut Cpu, _bus: &mut dyn Bus, opcode: u32) {
     let link = check_bit!(opcode, 24);
     let offset = (opcode & 0xff_ffff) as i32;
 
     cpu.branch(link, offset, 2, 24);
 }
 
-fn branch_and_exchange_handler(cpu: &mut Cpu, opcode: u32) {
+fn branch_and_exchange_handler(cpu: &mut Cpu, _bus: &mut dyn Bus, opcode: u32) {
     let source_register = get_register_number_at!(opcode, 0);
 
     cpu.branch_and_exchange(source_register);
 }
 
-fn msr_transfer_handler(cpu: &mut Cpu, opcode: u32) {
+fn msr_transfer_handler(cpu: &mut Cpu, bus: &mut dyn Bus, opcode: u32) {
     let operand2_type = 
         if check_bit!(opcode, 25) {
             Operand2Type::ImmediateWithRotation
@@ -124,7 +124,7 @@ fn msr_transfer_handler(cpu: &mut Cpu, opcode: u32) {
         0xf_0000 => 0xffffffff,
         _ => panic!(),
     };
-    let operand_2: u32 = cpu.get_operand2(
+    let operand_2: u32 = cpu.get_operand2(bus,
         operand2_type,
         super::constants::ShiftType::LogicalLeft,
         false,
@@ -134,14 +134,14 @@ fn msr_transfer_handler(cpu: &mut Cpu, opcode: u32) {
     cpu.msr(destination_is_spsr, mask, operand_2);
 }
 
-fn mrs_transfer_handler(cpu: &mut Cpu, opcode: u32) {
+fn mrs_transfer_handler(cpu: &mut Cpu, _bus: &mut dyn Bus, opcode: u32) {
     let source_is_spsr = check_bit!(opcode, 22);
     let destination_register = get_register_number_at!(opcode, 12);
 
     cpu.mrs(source_is_spsr, destination_register);
 }
 
-fn alu_handler(cpu: &mut Cpu, opcode: u32) {
+fn alu_handler(cpu: &mut Cpu, bus: &mut dyn Bus, opcode: u32) {
     let operand2_type = 
         if check_bit!(opcode, 25) {
             Operand2Type::ImmediateWithRotation
@@ -160,7 +160,7 @@ fn alu_handler(cpu: &mut Cpu, opcode: u32) {
         AluOpcode::And | AluOpcode::ExclusiveOr | AluOpcode::TestAnd | AluOpcode::TestExclusiveOr | AluOpcode::Or | AluOpcode::Move | AluOpcode::BitClear | AluOpcode::MoveNot => set_condition_codes,
         _ => false
     };
-    let operand_2 = cpu.get_operand2(operand2_type, shift_type, set_condition_codes_operand_2, opcode);
+    let operand_2 = cpu.get_operand2(bus, operand2_type, shift_type, set_condition_codes_operand_2, opcode);
 
     cpu.decode_alu(alu_opcode, set_condition_codes, operand_1_register, destination_register, operand_2);
     if destination_register != 15 {
@@ -170,7 +170,7 @@ fn alu_handler(cpu: &mut Cpu, opcode: u32) {
     }
 }
 
-fn multiply_handler(cpu: &mut Cpu, opcode: u32) {
+fn multiply_handler(cpu: &mut Cpu, bus: &mut dyn Bus, opcode: u32) {
     let accumulate = check_bit!(opcode, 21);
     let set_conditions =  check_bit!(opcode, 20);
     let operand_1_register = get_register_number_at!(opcode, 12);
@@ -178,10 +178,10 @@ fn multiply_handler(cpu: &mut Cpu, opcode: u32) {
     let operand_3_register = get_register_number_at!(opcode, 0);
     let destination_register = get_register_number_at!(opcode, 16);
 
-    cpu.multiply(accumulate, set_conditions, operand_1_register, operand_2_register, operand_3_register, destination_register);
+    cpu.multiply(bus, accumulate, set_conditions, operand_1_register, operand_2_register, operand_3_register, destination_register);
 }
 
-fn multiply_long_hanlder(cpu: &mut Cpu, opcode: u32) {
+fn multiply_long_hanlder(cpu: &mut Cpu, bus: &mut dyn Bus, opcode: u32) {
     let signed = check_bit!(opcode, 22);
     let accumulate = check_bit!(opcode, 21);
     let set_conditions = check_bit!(opcode, 20);
@@ -191,10 +191,10 @@ fn multiply_long_hanlder(cpu: &mut Cpu, opcode: u32) {
     let operand_1_register = get_register_number_at!(opcode, 8);
     let operand_2_register = get_register_number_at!(opcode, 0);
 
-    cpu.multiply_long(signed, accumulate, set_conditions, register_hi, register_lo, operand_1_register, operand_2_register);
+    cpu.multiply_long(bus, signed, accumulate, set_conditions, register_hi, register_lo, operand_1_register, operand_2_register);
 }
 
-fn single_data_transfer(cpu: &mut Cpu, opcode: u32) {
+fn single_data_transfer(cpu: &mut Cpu, bus: &mut dyn Bus, opcode: u32) {
     let operand2_type =  
         if !check_bit!(opcode, 25) {
             Operand2Type::Immediate
@@ -211,11 +211,11 @@ fn single_data_transfer(cpu: &mut Cpu, opcode: u32) {
     let shift_type = to_shift_type((opcode >> 5) & 0x3);
 
     let base_register = get_register_number_at!(opcode, 16);
-    let offset = cpu.get_operand2(operand2_type, shift_type, false, opcode);
+    let offset = cpu.get_operand2(bus, operand2_type, shift_type, false, opcode);
     let src_dst_register = get_register_number_at!(opcode, 12);
     let old_r15 = cpu.registers[15];
 
-    cpu.single_data_transfer(pre_indexing, add_offset, transfer_byte, write_back, load, base_register, offset, src_dst_register, false);
+    cpu.single_data_transfer(bus, pre_indexing, add_offset, transfer_byte, write_back, load, base_register, offset, src_dst_register, false);
 
     if cpu.registers[15] != old_r15 + 4 && load {
         cpu.flush = true;
@@ -224,7 +224,7 @@ fn single_data_transfer(cpu: &mut Cpu, opcode: u32) {
     }
 }
 
-fn halfword_data_transfer_handler(cpu: &mut Cpu, opcode: u32) {
+fn halfword_data_transfer_handler(cpu: &mut Cpu, bus: &mut dyn Bus, opcode: u32) {
     let immediate = check_bit!(opcode, 22);
     let pre_indexing = check_bit!(opcode, 24); 
     let add_offset = check_bit!(opcode, 23); 
@@ -237,7 +237,7 @@ fn halfword_data_transfer_handler(cpu: &mut Cpu, opcode: u32) {
     let src_dst_register = get_register_number_at!(opcode, 12);
     let old_r15 = cpu.registers[15];
 
-    cpu.halfword_data_transfer(immediate, pre_indexing, add_offset, write_back, load, halfword_transfer_type, base_register, src_dst_register, offset_value, offset_register);
+    cpu.halfword_data_transfer(bus, immediate, pre_indexing, add_offset, write_back, load, halfword_transfer_type, base_register, src_dst_register, offset_value, offset_register);
     
     if cpu.registers[15] != old_r15 + 4 && load {
         cpu.flush = true;
@@ -246,7 +246,7 @@ fn halfword_data_transfer_handler(cpu: &mut Cpu, opcode: u32) {
     }
 }
 
-fn block_data_transfer_handler(cpu: &mut Cpu, opcode: u32) {
+fn block_data_transfer_handler(cpu: &mut Cpu, bus: &mut dyn Bus, opcode: u32) {
     let pre_indexing = check_bit!(opcode, 24);
     let add_offset = check_bit!(opcode, 23);
     let load_psr = check_bit!(opcode, 22);
@@ -256,7 +256,7 @@ fn block_data_transfer_handler(cpu: &mut Cpu, opcode: u32) {
     let register_mask = opcode & 0xffff;
     let old_r15 = cpu.registers[15];
 
-    cpu.block_data_transfer(pre_indexing, add_offset, load_psr, write_back, load, base_register, register_mask);
+    cpu.block_data_transfer(bus, pre_indexing, add_offset, load_psr, write_back, load, base_register, register_mask);
 
     if cpu.registers[15] != old_r15 + 4 && load {
         cpu.flush = true;
@@ -265,20 +265,20 @@ fn block_data_transfer_handler(cpu: &mut Cpu, opcode: u32) {
     }
 }
 
-fn single_data_swap(cpu: &mut Cpu, opcode: u32) {
+fn single_data_swap(cpu: &mut Cpu, bus: &mut dyn Bus, opcode: u32) {
     let transfer_byte = check_bit!(opcode, 22);
     let address_register = get_register_number_at!(opcode, 16);
     let dst_register = get_register_number_at!(opcode, 12);
     let src_register = get_register_number_at!(opcode, 0);
 
-    cpu.single_data_swap(transfer_byte, address_register, dst_register, src_register);
+    cpu.single_data_swap(bus, transfer_byte, address_register, dst_register, src_register);
 }
 
-fn software_interrupt_handler(cpu: &mut Cpu, opcode: u32) { 
-    cpu.software_interrupt();
+fn software_interrupt_handler(cpu: &mut Cpu, bus: &mut dyn Bus, opcode: u32) {
+    cpu.software_interrupt(bus, (opcode >> 16) & 0xFF);
 }
 
-fn undefinied_handler(cpu: &mut Cpu, opcode: u32) { }
+fn undefinied_handler(cpu: &mut Cpu, _bus: &mut dyn Bus, opcode: u32) { }
 
 pub(super) const fn condition_lut() -> [bool; 256] {
     const SIGN_FLAG: u8 = 0x8;
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use crate::memory::CodePage;
+
+use super::cpu::PipelineStage2;
+
+/// A decoded opcode with the non-sequential and sequential cycles of fetching it, `None` when the
+/// bus has to time each fetch.
+#[derive(Clone, Copy)]
+pub(super) struct CachedOpcode {
+    pub(super) instruction: PipelineStage2,
+    pub(super) cycles: Option<(usize, usize)>
+}
+
+/// Decoded opcodes from `start` up to the first one that can change the PC, within one page.
+pub(super) struct Block {
+    start: u32,
+    thumb: bool,
+    opcodes: Vec<CachedOpcode>
+}
+
+impl Block {
+    pub(super) fn new(start: u32, thumb: bool, opcodes: Vec<CachedOpcode>) -> Block {
+        Block { start, thumb, opcodes }
+    }
+
+    pub(super) fn get(&self, address: u32, thumb: bool) -> Option<CachedOpcode> {
+        if thumb != self.thumb || address < self.start {
+            return None;
+        }
+        let shift = if thumb { 1 } else { 2 };
+        self.opcodes.get(((address - self.start) >> shift) as usize).copied()
+    }
+}
+
+// Entries of the table checked before the map, indexed by the low bits of the start address
+const RECENT_BLOCKS: usize = 0x1000;
+
+/// Blocks are kept in slots so the CPU can hold on to the current one by index.
+pub(super) struct BlockCache {
+    slots: Vec<Option<Block>>,
+    free_slots: Vec<usize>,
+    blocks: HashMap<(u32, bool), usize>,
+    recent: Vec<usize>,
+    // Blocks decoded from each writable page, dropped when the page is written
+    page_blocks: HashMap<usize, Vec<(u32, bool)>>
+}
+
+impl BlockCache {
+    pub(super) fn new() -> BlockCache {
+        BlockCache {
+            slots: Vec::new(),
+            free_slots: Vec::new(),
+            blocks: HashMap::new(),
+            recent: vec![usize::MAX; RECENT_BLOCKS],
+            page_blocks: HashMap::new()
+        }
+    }
+
+    /// Slot of the block starting at `address`.
+    pub(super) fn find(&mut self, address: u32, thumb: bool) -> Option<usize> {
+        let recent = (address >> 1) as usize % RECENT_BLOCKS;
+        let slot = self.recent[recent];
+        if let Some(Some(block)) = self.slots.get(slot) {
+            if block.start == address && block.thumb == thumb {
+                return Some(slot);
+            }
+        }
+        let slot = *self.blocks.get(&(address, thumb))?;
+        self.recent[recent] = slot;
+        Some(slot)
+    }
+
+    pub(super) fn opcode(&self, slot: usize, address: u32, thumb: bool) -> Option<CachedOpcode> {
+        self.slots[slot].as_ref()?.get(address, thumb)
+    }
+
+    pub(super) fn insert(&mut self, block: Block, page: CodePage) -> usize {
+        let key = (block.start, block.thumb);
+        let slot = match self.free_slots.pop() {
+            Some(slot) => {
+                self.slots[slot] = Some(block);
+                slot
+            }
+            None => {
+                self.slots.push(Some(block));
+                self.slots.len() - 1
+            }
+        };
+        if let CodePage::Writable(page) = page {
+            self.page_blocks.entry(page).or_default().push(key);
+        }
+        self.blocks.insert(key, slot);
+        self.recent[(key.0 >> 1) as usize % RECENT_BLOCKS] = slot;
+        slot
+    }
+
+    pub(super) fn invalidate_page(&mut self, page: usize) {
+        for key in self.page_blocks.remove(&page).unwrap_or_default() {
+            if let Some(slot) = self.blocks.remove(&key) {
+                self.slots[slot] = None;
+                self.free_slots.push(slot);
+            }
+        }
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.slots.clear();
+        self.free_slots.clear();
+        self.blocks.clear();
+        self.recent.fill(usize::MAX);
+        self.page_blocks.clear();
+    }
+}
+
+/// Whether an instruction may branch, so the block has to stop after it.
+pub(super) fn ends_block(opcode: u32, thumb: bool) -> bool {
+    if thumb {
+        let opcode = opcode & 0xFFFF;
+        let hi_register_destination = ((opcode >> 4) & 0x8) | (opcode & 0x7);
+        opcode >> 12 == 0xD
+            || opcode >> 11 == 0x1C
+            || opcode >> 11 == 0x1F
+            || (opcode >> 10 == 0x11 && (opcode & 0x300 == 0x300 || hi_register_destination == 15))
+            || opcode & 0xFF00 == 0xBD00
+    } else {
+        let load = opcode & 0x0010_0000 != 0;
+        let destination = (opcode >> 12) & 0xF;
+        match (opcode >> 25) & 0x7 {
+            0b101 => true,
+            0b100 => load && opcode & 0x8000 != 0,
+            0b111 => opcode & 0x0F00_0000 == 0x0F00_0000,
+            0b010 | 0b011 => load && destination == 15,
+            _ => opcode & 0x0FFF_FFF0 == 0x012F_FF10 || destination == 15,
+        }
+    }
+}
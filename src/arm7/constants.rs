@@ -1,3 +1,5 @@
+use crate::bus::Bus;
+
 use super::cpu::Cpu;
 
 // CPU modes
@@ -24,7 +26,7 @@ pub const IRQ_BIT: u32 = 0x80;
 pub const FIQ_BIT: u32 = 0x40;
 pub const STATE_BIT: u32 = 0x20;
 
-pub(super) type InstructionHandler = fn(&mut Cpu, u32);
+pub(super) type InstructionHandler = fn(&mut Cpu, &mut dyn Bus, u32);
 
 #[derive(Clone, Copy)]
 pub enum AluOpcode {
@@ -1,8 +1,10 @@
-use std::{ cell::RefCell, rc::Rc, fs::{ File, OpenOptions }, io::{ Write, BufWriter } };
-
-use crate::{ memory::Memory, check_bit };
+use crate::{ bus::Bus, error::{CpuFault, GbaError}, memory::{Access, CodePage}, check_bit };
+use crate::state::{StateReader, StateWriter};
 
 use super::{constants::*, thumb_lut::thumb_instruction_lut};
+use super::block_cache::{ends_block, Block, BlockCache, CachedOpcode};
+use super::hle;
+use super::tracer::{Tracer, TraceRecord};
 use super::arm_lut::{
     condition_lut,
     arm_instruction_lut
@@ -12,13 +14,35 @@ pub const CONDITION_LUT: [bool; 256] = condition_lut();
 const ARM_INSTRUCTION_LUT: [InstructionHandler; 4096] = arm_instruction_lut();
 const THUMB_INSTRUCTION_LUT: [InstructionHandler; 256] = thumb_instruction_lut();
 
-struct PipelineStage2 {
+#[derive(Clone, Copy)]
+pub(super) struct PipelineStage2 {
     handler: InstructionHandler,
     opcode: u32
 }
 
+/// Every register of the CPU including the banked ones, as used by the mode they belong to, plus the
+/// two prefetched opcodes. Lets tests set up and check the CPU without running code to get there.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CpuSnapshot {
+    /// R0 to R15 of the current mode, R15 is two instructions ahead of the one executing next.
+    pub registers: [u32; 16],
+    pub cpsr: u32,
+    /// In the order FIQ, IRQ, supervisor, abort, undefined.
+    pub spsr: [u32; 5],
+    /// R8 to R14 of user and system mode.
+    pub user_registers: [u32; 7],
+    /// R8 to R14 of FIQ mode.
+    pub fiq_registers: [u32; 7],
+    /// R13 and R14 of the remaining modes.
+    pub supervisor_registers: [u32; 2],
+    pub abort_registers: [u32; 2],
+    pub irq_registers: [u32; 2],
+    pub undefined_registers: [u32; 2],
+    /// The opcode that executes next, followed by the one after it.
+    pub pipeline: [u32; 2]
+}
+
 pub struct Cpu {
-    pub(super) memory: Rc<RefCell<Memory>>,
     pub(super) registers: [u32; 16],
     // Current Program Status Register
     pub(super) cpsr_register: u32,
@@ -32,18 +56,32 @@ pub struct Cpu {
     abort_banked: [u32; 2],
     irq_banked: [u32; 2],
     undefinied_banked: [u32; 2],
-    pipeline_stage_1: Option<u32>,
+    pipeline_stage_1: Option<PipelineStage2>,
     pipeline_stage_2: Option<PipelineStage2>,
     pub(super) flush: bool,
-    log: File,
+    tracer: Option<Tracer>,
     last_data_bus_read: u32,
+    // Code fetches are non-sequential after a store or a branch, loads end on an internal cycle instead
+    next_fetch_access: Access,
+    block_cache: BlockCache,
+    // Block the last fetch came from, sequential fetches usually stay in it
+    block: Option<usize>,
+    fault: Option<CpuFault>,
+    // BIOS calls run natively until a BIOS image is loaded
+    hle_bios: bool,
+    // Set by the native Halt and IntrWait calls, the next VBlank clears it
+    pub(super) halted: bool,
+}
+
+impl Default for Cpu {
+    fn default() -> Cpu {
+        Cpu::new()
+    }
 }
 
 impl Cpu {
-    pub fn new(memory: Rc<RefCell<Memory>>) -> Cpu {
-        let lmao = OpenOptions::new().write(true).create(true).open("log.bin").unwrap();
+    pub fn new() -> Cpu {
         let mut arm7 = Cpu {
-            memory,
             registers: [0; 16],
             cpsr_register: SYSTEM_MODE | IRQ_BIT | FIQ_BIT,
             saved_psr: [0; 5],
@@ -57,8 +95,14 @@ impl Cpu {
             pipeline_stage_1: None,
             pipeline_stage_2: None,
             flush: false,
-            log: lmao,
-            last_data_bus_read: 0
+            tracer: None,
+            last_data_bus_read: 0,
+            next_fetch_access: Access::NonSequential,
+            block_cache: BlockCache::new(),
+            block: None,
+            fault: None,
+            hle_bios: true,
+            halted: false
         };
         arm7.registers[13] = STACK_USER_SYSTEM_START;
         arm7.irq_banked[0] = STACK_IRQ_START;
@@ -67,99 +111,361 @@ impl Cpu {
         arm7
     }
 
-    pub fn next(&mut self) {
-        self.output_registers();
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_words(&self.registers);
+        state.write_u32(self.cpsr_register);
+        state.write_words(&self.saved_psr);
+        state.write_words(&self.fiq_lo_banked);
+        for banked in [
+            &self.user_banked,
+            &self.fiq_hi_banked,
+            &self.supervisor_banked,
+            &self.abort_banked,
+            &self.irq_banked,
+            &self.undefinied_banked
+        ] {
+            state.write_words(banked);
+        }
+        // Only the opcodes, the handlers are looked up again on load
+        state.write_option_u32(self.pipeline_stage_1.map(|instruction| instruction.opcode));
+        state.write_option_u32(self.pipeline_stage_2.map(|instruction| instruction.opcode));
+        state.write_bool(self.flush);
+        state.write_u32(self.last_data_bus_read);
+        state.write_bool(self.next_fetch_access == Access::Sequential);
+        state.write_bool(self.halted);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), GbaError> {
+        state.read_words(&mut self.registers)?;
+        self.cpsr_register = state.read_u32()?;
+        state.read_words(&mut self.saved_psr)?;
+        state.read_words(&mut self.fiq_lo_banked)?;
+        for banked in [
+            &mut self.user_banked,
+            &mut self.fiq_hi_banked,
+            &mut self.supervisor_banked,
+            &mut self.abort_banked,
+            &mut self.irq_banked,
+            &mut self.undefinied_banked
+        ] {
+            state.read_words(banked)?;
+        }
+        let decode = if self.is_thumb() { decode_thumb } else { decode_arm };
+        self.pipeline_stage_1 = state.read_option_u32()?.map(decode);
+        self.pipeline_stage_2 = state.read_option_u32()?.map(decode);
+        self.flush = state.read_bool()?;
+        self.last_data_bus_read = state.read_u32()?;
+        self.next_fetch_access = if state.read_bool()? { Access::Sequential } else { Access::NonSequential };
+        self.halted = state.read_bool()?;
+        self.block = None;
+        self.block_cache.clear();
+        self.fault = None;
+        Ok(())
+    }
+
+    pub fn set_hle_bios(&mut self, enabled: bool) {
+        self.hle_bios = enabled;
+    }
+
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn registers(&self) -> &[u32; 16] {
+        &self.registers
+    }
+
+    /// Writing R15 refills the pipeline from the new address.
+    pub fn set_register(&mut self, bus: &mut dyn Bus, register: usize, value: u32) {
+        self.registers[register] = value;
+        if register == 15 {
+            self.pipeline_flush(bus);
+        }
+    }
+
+    pub fn cpsr(&self) -> u32 {
+        self.cpsr_register
+    }
+
+    pub fn set_cpsr(&mut self, value: u32) {
+        let old_mode = self.cpsr_register & 0x1f;
+        self.cpsr_register = value;
+        if old_mode != (self.cpsr_register & 0x1f) {
+            self.switch_modes(old_mode);
+        }
+    }
+
+    pub fn spsr(&mut self) -> u32 {
+        *self.get_current_saved_psr()
+    }
+
+    /// Set once the CPU hit a state it can't continue from, `next` does nothing after that.
+    pub fn fault(&self) -> Option<&CpuFault> {
+        self.fault.as_ref()
+    }
+
+    /// True while the CPU waits for the next VBlank, after a HALTCNT write or a native Halt,
+    /// IntrWait or VBlankIntrWait.
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn halt(&mut self) {
+        self.halted = true;
+    }
+
+    pub fn wake(&mut self) {
+        self.halted = false;
+    }
+
+    pub fn snapshot(&self) -> CpuSnapshot {
+        let mode = self.cpsr_register & 0x1f;
+        let current = [self.registers[13], self.registers[14]];
+        let banked = |modes: &[u32], stored: [u32; 2]| if modes.contains(&mode) { current } else { stored };
+        let (user_low, fiq_low) = if mode == FIQ_MODE {
+            (self.fiq_lo_banked, self.registers[8..13].try_into().unwrap())
+        } else {
+            (self.registers[8..13].try_into().unwrap(), self.fiq_lo_banked)
+        };
+        let mut user_registers = [0; 7];
+        user_registers[..5].copy_from_slice(&user_low);
+        user_registers[5..].copy_from_slice(&banked(&[USER_MODE, SYSTEM_MODE], self.user_banked));
+        let mut fiq_registers = [0; 7];
+        fiq_registers[..5].copy_from_slice(&fiq_low);
+        fiq_registers[5..].copy_from_slice(&banked(&[FIQ_MODE], self.fiq_hi_banked));
+        let opcode = |stage: Option<PipelineStage2>| stage.map_or(0, |instruction| instruction.opcode);
+        CpuSnapshot {
+            registers: self.registers,
+            cpsr: self.cpsr_register,
+            spsr: self.saved_psr,
+            user_registers,
+            fiq_registers,
+            supervisor_registers: banked(&[SUPERVISOR_MODE], self.supervisor_banked),
+            abort_registers: banked(&[ABORT_MODE], self.abort_banked),
+            irq_registers: banked(&[IRQ_MODE], self.irq_banked),
+            undefined_registers: banked(&[UNDEFINED_MODE], self.undefinied_banked),
+            pipeline: [opcode(self.pipeline_stage_2), opcode(self.pipeline_stage_1)]
+        }
+    }
+
+    /// Puts the CPU in the state of `snapshot`, the next call to `next` fetches from R15 and executes
+    /// the first opcode of the pipeline. For the current mode `registers` wins over its bank.
+    pub fn restore(&mut self, snapshot: &CpuSnapshot) {
+        let fiq = snapshot.cpsr & 0x1f == FIQ_MODE;
+        self.registers = snapshot.registers;
+        self.cpsr_register = snapshot.cpsr;
+        self.saved_psr = snapshot.spsr;
+        let low = if fiq { &snapshot.user_registers } else { &snapshot.fiq_registers };
+        self.fiq_lo_banked.copy_from_slice(&low[..5]);
+        self.user_banked.copy_from_slice(&snapshot.user_registers[5..]);
+        self.fiq_hi_banked.copy_from_slice(&snapshot.fiq_registers[5..]);
+        self.supervisor_banked = snapshot.supervisor_registers;
+        self.abort_banked = snapshot.abort_registers;
+        self.irq_banked = snapshot.irq_registers;
+        self.undefinied_banked = snapshot.undefined_registers;
+        let decode = if self.is_thumb() { decode_thumb } else { decode_arm };
+        self.pipeline_stage_2 = Some(decode(snapshot.pipeline[0]));
+        self.pipeline_stage_1 = Some(decode(snapshot.pipeline[1]));
+        self.flush = false;
+        self.next_fetch_access = Access::Sequential;
+        self.halted = false;
+        self.block = None;
+        self.block_cache.clear();
+        self.fault = None;
+    }
+
+    pub fn is_thumb(&self) -> bool {
+        (self.cpsr_register & STATE_BIT) == STATE_BIT
+    }
+
+    /// Address and opcode of the instruction that executes on the next call to `next`.
+    pub fn current_instruction(&self) -> Option<(u32, u32)> {
+        let pipeline_offset = if self.is_thumb() { 4 } else { 8 };
+        self.pipeline_stage_2
+            .map(|instruction| (self.registers[15].wrapping_sub(pipeline_offset), instruction.opcode))
+    }
+
+    pub fn next(&mut self, bus: &mut dyn Bus) {
+        if self.fault.is_some() || self.halted {
+            return;
+        }
         if (self.cpsr_register & STATE_BIT) == STATE_BIT {
             // THUMB MODE
-            if self.registers[15] == 0x800018e {
-                println!("aiushdgasiydgh");
-            }
+            let temp_pipeline_1 = Some(self.fetch_thumb(bus));
             if self.pipeline_stage_2.is_some() {
-                let instruction = self.pipeline_stage_2.as_ref().unwrap();
-                (instruction.handler)(self, instruction.opcode);
+                let instruction = self.pipeline_stage_2.unwrap();
+                if self.tracer.is_some() {
+                    self.trace_instruction(instruction.opcode, 4);
+                }
+                (instruction.handler)(self, bus, instruction.opcode);
                 if self.flush {
-                    self.pipeline_flush();
+                    self.pipeline_flush(bus);
                     return;
                 }
             }
             if self.pipeline_stage_1.is_some() {
-                let opcode = self.pipeline_stage_1.unwrap();
-                let bits15_8 = opcode >> 8;
-                self.pipeline_stage_2 = Some(PipelineStage2 { handler: THUMB_INSTRUCTION_LUT[bits15_8 as usize], opcode });
+                self.pipeline_stage_2 = self.pipeline_stage_1;
             }
-            self.pipeline_stage_1 = Some(self.fetch_thumb());
+            self.pipeline_stage_1 = temp_pipeline_1;
             self.registers[15] += 2;
         } else {
             // ARM MODE
-            if self.registers[15] == 0x000168 {
-                println!("aiushdgasiydgh");
-            }
-            let temp_pipeline_1 = Some(self.fetch_arm());
+            let temp_pipeline_1 = Some(self.fetch_arm(bus));
             if self.pipeline_stage_2.is_some() {
-                let instruction = self.pipeline_stage_2.as_ref().unwrap();
+                let instruction = self.pipeline_stage_2.unwrap();
+                if self.tracer.is_some() {
+                    self.trace_instruction(instruction.opcode, 8);
+                }
                 if CONDITION_LUT[(((instruction.opcode >> 24) & 0xf0) | (self.cpsr_register >> 28)) as usize] {
-                    (instruction.handler)(self, instruction.opcode);
+                    (instruction.handler)(self, bus, instruction.opcode);
                 }
                 if self.flush {
-                    self.pipeline_flush();
+                    self.pipeline_flush(bus);
                     return;
                 }
             }
             if self.pipeline_stage_1.is_some() {
-                let opcode = self.pipeline_stage_1.unwrap();
-                let bits27_20 = (opcode >> 20) & 0xff;
-                let bits7_4 = (opcode >> 4) & 0xf;
-                self.pipeline_stage_2 = Some(PipelineStage2 { handler: ARM_INSTRUCTION_LUT[((bits27_20 << 4) | bits7_4) as usize], opcode });
+                self.pipeline_stage_2 = self.pipeline_stage_1;
             }
             self.pipeline_stage_1 = temp_pipeline_1;
             self.registers[15] += 4;
         }
     }
 
-    fn output_registers(&mut self) {
-        let spsr = self.get_current_saved_psr().to_owned();
-        let mut lmao = BufWriter::new(&self.log);
-        for i in 0..16 {
-            lmao.write(&self.registers[i].to_le_bytes()).unwrap();
-        }
-        lmao.write(&self.cpsr_register.to_le_bytes()).unwrap();
-        lmao.write(&spsr.to_le_bytes()).unwrap();
+    fn trace_instruction(&mut self, opcode: u32, pipeline_offset: u32) {
+        let record = TraceRecord {
+            registers: self.registers,
+            cpsr: self.cpsr_register,
+            spsr: *self.get_current_saved_psr(),
+            opcode,
+            address: self.registers[15].wrapping_sub(pipeline_offset)
+        };
+        self.tracer.as_mut().unwrap().record(record);
     }
 
-    fn fetch_arm(&mut self) -> u32 {
+    fn fetch_arm(&mut self, bus: &mut dyn Bus) -> PipelineStage2 {
         //println!("(ARM) Fetching at {:#08x}", self.registers[15]);
-        let instruction = self.memory.borrow_mut().get_word(self.registers[15] & 0xffff_fffc, true);
-        self.last_data_bus_read = instruction;
+        let address = self.registers[15] & 0xffff_fffc;
+        let access = std::mem::replace(&mut self.next_fetch_access, Access::Sequential);
+        let instruction = match self.fetch_from_cache(bus, address, false, access) {
+            Some(instruction) => instruction,
+            None => decode_arm(bus.fetch_word(address, access)),
+        };
+        self.last_data_bus_read = instruction.opcode;
         instruction
     }
 
-    fn fetch_thumb(&mut self) -> u32 {
+    fn fetch_thumb(&mut self, bus: &mut dyn Bus) -> PipelineStage2 {
         //println!("(THUMB) Fetching at {:#08x}", self.registers[15]);
-        let instruction = self.memory.borrow_mut().get_halfword(self.registers[15] & 0xffff_fffe, true) as u32;
-        self.last_data_bus_read = instruction;
+        let address = self.registers[15] & 0xffff_fffe;
+        let access = std::mem::replace(&mut self.next_fetch_access, Access::Sequential);
+        let instruction = match self.fetch_from_cache(bus, address, true, access) {
+            Some(instruction) => instruction,
+            None => decode_thumb(bus.fetch_halfword(address, access) as u32),
+        };
+        self.last_data_bus_read = instruction.opcode;
         instruction
     }
 
-    pub(super) fn pipeline_flush(&mut self) {
+    // A hit only advances the clock by the cycles the block stored, without reading the bus
+    fn fetch_from_cache(&mut self, bus: &mut dyn Bus, address: u32, thumb: bool, access: Access) -> Option<PipelineStage2> {
+        loop {
+            let opcode = self.cached_opcode(bus, address, thumb)?;
+            let cycles = opcode.cycles.map(|(non_sequential, sequential)| match access {
+                Access::NonSequential => non_sequential,
+                Access::Sequential => sequential,
+            });
+            if bus.fetch_cached(address, thumb, access, cycles) {
+                return Some(opcode.instruction);
+            }
+            self.invalidate_blocks(bus);
+        }
+    }
+
+    fn cached_opcode(&mut self, bus: &mut dyn Bus, address: u32, thumb: bool) -> Option<CachedOpcode> {
+        if let Some(opcode) = self.block.and_then(|block| self.block_cache.opcode(block, address, thumb)) {
+            return Some(opcode);
+        }
+        let block = match self.block_cache.find(address, thumb) {
+            Some(block) => block,
+            None => self.build_block(bus, address, thumb)?,
+        };
+        self.block = Some(block);
+        self.block_cache.opcode(block, address, thumb)
+    }
+
+    fn invalidate_blocks(&mut self, bus: &mut dyn Bus) {
+        self.block = None;
+        match bus.take_code_invalidations() {
+            Some(pages) => {
+                for page in pages {
+                    self.block_cache.invalidate_page(page);
+                }
+            }
+            None => self.block_cache.clear(),
+        }
+    }
+
+    fn build_block(&mut self, bus: &mut dyn Bus, start: u32, thumb: bool) -> Option<usize> {
+        let page = bus.code_page(start)?;
+        let size = if thumb { 2 } else { 4 };
+        let page_end = (start | 0xFF) + 1;
+        let mut opcodes = Vec::new();
+        let mut address = start;
+        while address < page_end {
+            let Some(opcode) = bus.peek(address, size) else {
+                break;
+            };
+            opcodes.push(CachedOpcode {
+                instruction: if thumb { decode_thumb(opcode) } else { decode_arm(opcode) },
+                cycles: bus.code_timing(address, thumb)
+            });
+            if ends_block(opcode, thumb) {
+                break;
+            }
+            address += size as u32;
+        }
+        if opcodes.is_empty() {
+            return None;
+        }
+        if let CodePage::Writable(page) = page {
+            bus.mark_code_page(page);
+        }
+        Some(self.block_cache.insert(Block::new(start, thumb, opcodes), page))
+    }
+
+    pub(super) fn pipeline_flush(&mut self, bus: &mut dyn Bus) {
+        self.next_fetch_access = Access::NonSequential;
         if (self.cpsr_register & STATE_BIT) == STATE_BIT {
-            let opcode = self.fetch_thumb();
-            let bits15_8 = opcode >> 8;
-            self.pipeline_stage_2 = Some(PipelineStage2 { handler: THUMB_INSTRUCTION_LUT[bits15_8 as usize], opcode });
+            self.pipeline_stage_2 = Some(self.fetch_thumb(bus));
             self.registers[15] += 2;
-            self.pipeline_stage_1 = Some(self.fetch_thumb());
+            self.pipeline_stage_1 = Some(self.fetch_thumb(bus));
             self.registers[15] += 2;
         } else {
-            let opcode = self.fetch_arm();
-            let bits27_20 = (opcode >> 20) & 0xff;
-            let bits7_4 = (opcode >> 4) & 0xf;
-            self.pipeline_stage_2 = Some(PipelineStage2 { handler: ARM_INSTRUCTION_LUT[((bits27_20 << 4) | bits7_4) as usize], opcode });
+            self.pipeline_stage_2 = Some(self.fetch_arm(bus));
             self.registers[15] += 4;
-            self.pipeline_stage_1 = Some(self.fetch_arm());
+            self.pipeline_stage_1 = Some(self.fetch_arm(bus));
             self.registers[15] += 4;
         }
         self.flush = false;
     }
 
+    // Keeps the first fault, the ones after it are usually consequences
+    pub(super) fn raise_fault(&mut self, reason: &'static str) {
+        if self.fault.is_some() {
+            return;
+        }
+        let (pc, opcode) = self.current_instruction().unwrap_or((self.registers[15], 0));
+        self.fault = Some(CpuFault {
+            reason,
+            pc,
+            opcode,
+            thumb: self.is_thumb(),
+            cpsr: self.cpsr_register,
+            registers: self.registers
+        });
+    }
+
     fn switch_modes(&mut self, old_mode: u32) {
         match old_mode {
             USER_MODE | SYSTEM_MODE =>
@@ -173,7 +479,7 @@ impl Cpu {
             ABORT_MODE => self.abort_banked.copy_from_slice(&mut self.registers[13..15]),
             IRQ_MODE => self.irq_banked.copy_from_slice(&mut self.registers[13..15]),
             UNDEFINED_MODE => self.undefinied_banked.copy_from_slice(&mut self.registers[13..15]),
-            _ => panic!("Unrecognized mode"),
+            _ => return self.raise_fault("Left an unrecognized mode"),
         }
         match self.cpsr_register & 0x1f {
             USER_MODE | SYSTEM_MODE => self.registers[13..15].copy_from_slice(&self.user_banked),
@@ -186,7 +492,7 @@ impl Cpu {
             ABORT_MODE => self.registers[13..15].copy_from_slice(&self.abort_banked),
             IRQ_MODE => self.registers[13..15].copy_from_slice(&self.irq_banked),
             UNDEFINED_MODE => self.registers[13..15].copy_from_slice(&self.undefinied_banked),
-            _ => panic!("Unrecognized mode"),
+            _ => self.raise_fault("Switched to an unrecognized mode"),
         }
     }
 
@@ -216,7 +522,10 @@ impl Cpu {
             ABORT_MODE => &mut self.saved_psr[3],
             UNDEFINED_MODE => &mut self.saved_psr[4],
             SYSTEM_MODE => &mut self.cpsr_register,
-            _ => panic!("CPU is in an unrecognized mode"),
+            _ => {
+                self.raise_fault("Accessed the SPSR in an unrecognized mode");
+                &mut self.cpsr_register
+            }
         }
     }
 
@@ -228,9 +537,10 @@ impl Cpu {
         }
     }
 
-    pub(super) fn msr(&mut self, destination_is_spsr: bool, mask: u32, operand_2: u32) {
-        if (self.cpsr_register & 0x1f) == USER_MODE && (mask & 0xff) == 0xff {
-            panic!("Tried to set control flags in user mode");
+    pub(super) fn msr(&mut self, destination_is_spsr: bool, mut mask: u32, operand_2: u32) {
+        // User mode can only change the condition flags
+        if (self.cpsr_register & 0x1f) == USER_MODE {
+            mask &= 0xF000_0000;
         }
 
         if destination_is_spsr {
@@ -252,7 +562,7 @@ impl Cpu {
         }
     }
 
-    pub(super) fn multiply(&mut self, accumulate: bool, set_conditions: bool, operand_1_register: usize, operand_2_register: usize, operand_3_register: usize, destination_register: usize) {
+    pub(super) fn multiply(&mut self, bus: &mut dyn Bus, accumulate: bool, set_conditions: bool, operand_1_register: usize, operand_2_register: usize, operand_3_register: usize, destination_register: usize) {
         let mut cycles_to_add = 0;
         let operand_1 = if accumulate {
             cycles_to_add += 1;
@@ -261,38 +571,22 @@ impl Cpu {
             0
         };
         let operand_2 = self.registers[operand_2_register];
-        if (operand_2 & 0xFFFF_FF00) == 0 || (operand_2 & 0xFFFF_FF00) == 0xFFFF_FF00 {
-            cycles_to_add += 1;
-        } else if (operand_2 & 0xFFFF_0000) == 0 || (operand_2 & 0xFFFF_0000) == 0xFFFF_0000 {
-            cycles_to_add += 2;
-        } else if (operand_2 & 0xFF00_0000) == 0 || (operand_2 & 0xFF00_0000) == 0xFF00_0000 {
-            cycles_to_add += 3;
-        } else {
-            cycles_to_add += 4;
-        }
+        cycles_to_add += multiplier_cycles(operand_2, true);
         let operand_3 = self.registers[operand_3_register];
         let result = operand_3.wrapping_mul(operand_2).wrapping_add(operand_1);
         if set_conditions {
             self.set_multiply_flags(result);
         }
-        self.memory.borrow_mut().add_clock_cycles(cycles_to_add);
+        bus.add_clock_cycles(cycles_to_add);
         self.registers[destination_register] = result;
     }
 
-    pub(super) fn multiply_long(&mut self, signed: bool, accumulate: bool, set_conditions: bool, register_hi: usize, register_lo: usize, operand_1_register: usize, operand_2_register: usize) {
+    pub(super) fn multiply_long(&mut self, bus: &mut dyn Bus, signed: bool, accumulate: bool, set_conditions: bool, register_hi: usize, register_lo: usize, operand_1_register: usize, operand_2_register: usize) {
         let mut cycles_to_add = 1;
         let operand_1 = self.registers[operand_1_register];
 
         let operand_2 = self.registers[operand_2_register];
-        if (operand_2 & 0xFFFF_FF00) == 0 || ((operand_2 & 0xFFFF_FF00) == 0xFFFF_FF00 && signed) {
-            cycles_to_add += 1;
-        } else if (operand_2 & 0xFFFF_0000) == 0 || ((operand_2 & 0xFFFF_0000) == 0xFFFF_0000 && signed) {
-            cycles_to_add += 2;
-        } else if (operand_2 & 0xFF00_0000) == 0 || ((operand_2 & 0xFF00_0000) == 0xFF00_0000 && signed) {
-            cycles_to_add += 3;
-        } else {
-            cycles_to_add += 4;
-        }
+        cycles_to_add += multiplier_cycles(operand_1, signed);
 
         let operand_3 = if accumulate {
             cycles_to_add += 1;
@@ -312,7 +606,7 @@ impl Cpu {
         if set_conditions {
             self.set_long_multiply_flags(result);
         }
-        self.memory.borrow_mut().add_clock_cycles(cycles_to_add);
+        bus.add_clock_cycles(cycles_to_add);
     }
 
     fn set_long_multiply_flags(&mut self, result: u64) {
@@ -336,7 +630,7 @@ impl Cpu {
     }
 
     pub(super) fn single_data_transfer(
-        &mut self,
+        &mut self, bus: &mut dyn Bus,
         pre_indexing: bool,
         add_offset: bool,
         transfer_byte: bool,
@@ -362,9 +656,9 @@ impl Cpu {
         }
 
         if load {
-            self.load_memory(address, src_dst_register, transfer_byte);
+            self.load_memory(bus, address, src_dst_register, transfer_byte);
         } else {
-            self.store_memory(address, src_dst_register, transfer_byte);
+            self.store_memory(bus, address, src_dst_register, transfer_byte);
         }
 
         if !pre_indexing {
@@ -380,39 +674,30 @@ impl Cpu {
         }
     }
 
-    fn load_memory(&mut self, address: u32, dst_register: usize, is_byte: bool) {
-        if address > 0x1000_0000 {
-            self.registers[dst_register] = self.last_data_bus_read;
-            return;
-        }
-        let mut memory = self.memory.borrow_mut();
+    fn load_memory(&mut self, bus: &mut dyn Bus, address: u32, dst_register: usize, is_byte: bool) {
         self.registers[dst_register] = if is_byte {
-            memory[address as usize] as u32
+            bus.get_byte(address, Access::NonSequential) as u32
         } else {
-            let mut value = memory.get_byte(address, true) as u32;
-            let mut address_rotate = (address & 0xFFFF_FFFC) + (address + 1) % 4;
-            value |= (memory.get_byte(address_rotate, false) as u32) << 8;
-            address_rotate = (address & 0xFFFF_FFFC) + (address + 2) % 4;
-            value |= (memory.get_byte(address_rotate, false) as u32) << 16;
-            address_rotate = (address & 0xFFFF_FFFC) + (address + 3) % 4;
-            value |= (memory.get_byte(address_rotate, false) as u32) << 24;
-            value
+            // Misaligned words are rotated so the addressed byte ends up in the low lane
+            bus.get_word(address & 0xFFFF_FFFC, Access::NonSequential).rotate_right((address & 0x3) * 8)
         };
+        bus.add_clock_cycles(1);
         self.last_data_bus_read = self.registers[dst_register];
-        memory.add_clock_cycles(1);
+        self.next_fetch_access = Access::Sequential;
     }
 
-    fn store_memory(&mut self, address: u32, src_register: usize, is_byte: bool) {
+    fn store_memory(&mut self, bus: &mut dyn Bus, address: u32, src_register: usize, is_byte: bool) {
         let value_to_store = self.registers[src_register];
         if !is_byte {
-            self.memory.borrow_mut().store_word(address & 0xFFFF_FFFC, value_to_store, true);
+            bus.store_word(address & 0xFFFF_FFFC, value_to_store, Access::NonSequential);
         } else {
-            self.memory.borrow_mut().store_byte(address, value_to_store as u8, true);
+            bus.store_byte(address, value_to_store as u8, Access::NonSequential);
         }
+        self.next_fetch_access = Access::NonSequential;
     }
 
     pub(super) fn halfword_data_transfer(
-        &mut self,
+        &mut self, bus: &mut dyn Bus,
         immediate: bool,
         pre_indexing: bool,
         add_offset: bool,
@@ -440,9 +725,9 @@ impl Cpu {
         }
 
         if load {
-            self.load_halfword(address, src_dst_register, halfword_transfer_type);
+            self.load_halfword(bus, address, src_dst_register, halfword_transfer_type);
         } else {
-            self.store_halfword(address, src_dst_register);
+            self.store_halfword(bus, address, src_dst_register);
         }
 
         if !pre_indexing {
@@ -458,62 +743,48 @@ impl Cpu {
     }
 
     fn load_halfword(
-        &mut self,
+        &mut self, bus: &mut dyn Bus,
         address: u32,
         dst_register: usize,
         halfword_transfer_type: HalfwordTransferType
     ) {
-        if address > 0x1000_0000 {
-            match halfword_transfer_type {
-                HalfwordTransferType::UnsignedHalfwords => {
-                    self.registers[dst_register] = self.last_data_bus_read & 0xFFFF;
-                }
-                HalfwordTransferType::SignedByte => {
-                    self.registers[dst_register] = (self.last_data_bus_read & 0xFF) as i8 as i32 as u32;
-                }
-                HalfwordTransferType::SignedHalfwords => {
-                    self.registers[dst_register] = (self.last_data_bus_read & 0xFFFF) as i16 as i32 as u32;
-                }
-                HalfwordTransferType::NoOp =>
-                    panic!("Something went terribly wrong while loading a halfword"),
+        let value = bus.get_halfword(address & !1, Access::NonSequential);
+        match (halfword_transfer_type, check_bit!(address, 0)) {
+            (HalfwordTransferType::UnsignedHalfwords, false) => {
+                self.registers[dst_register] = value as u32;
             }
-        } else {
-            let value = self.memory.borrow_mut().get_halfword(address & !1, true);
-            match (halfword_transfer_type, check_bit!(address, 0)) {
-                (HalfwordTransferType::UnsignedHalfwords, false) => {
-                    self.registers[dst_register] = value as u32;
-                }
-                (HalfwordTransferType::UnsignedHalfwords, true) => {
-                    self.registers[dst_register] = (value as u32).rotate_right(8);
-                }
-                (HalfwordTransferType::SignedByte, _) => {
-                    self.registers[dst_register] = (value & 0xff) as i8 as i32 as u32;
-                }
-                (HalfwordTransferType::SignedHalfwords, false) => {
-                    self.registers[dst_register] = value as i16 as i32 as u32;
-                }
-                (HalfwordTransferType::SignedHalfwords, true) => {
-                    self.registers[dst_register] = (value as i16 as i32 >> 8) as u32;
-                }
-                (HalfwordTransferType::NoOp, _) =>
-                    panic!("Something went terribly wrong while loading a halfword"),
+            (HalfwordTransferType::UnsignedHalfwords, true) => {
+                self.registers[dst_register] = (value as u32).rotate_right(8);
+            }
+            (HalfwordTransferType::SignedByte, _) => {
+                self.registers[dst_register] = (value & 0xff) as i8 as i32 as u32;
             }
-            self.last_data_bus_read = self.memory.borrow_mut().get_word(address & 0xFFFF_FFFC, false);
+            (HalfwordTransferType::SignedHalfwords, false) => {
+                self.registers[dst_register] = value as i16 as i32 as u32;
+            }
+            (HalfwordTransferType::SignedHalfwords, true) => {
+                self.registers[dst_register] = (value as i16 as i32 >> 8) as u32;
+            }
+            (HalfwordTransferType::NoOp, _) =>
+                return self.raise_fault("Decoded a halfword load without a transfer type"),
         }
-        self.memory.borrow_mut().add_clock_cycles(1);
+        self.last_data_bus_read = self.registers[dst_register];
+        bus.add_clock_cycles(1);
+        self.next_fetch_access = Access::Sequential;
     }
 
     fn store_halfword(
-        &mut self,
+        &mut self, bus: &mut dyn Bus,
         address: u32,
         src_register: usize
     ) {
         let value = self.registers[src_register] as u16;
-        self.memory.borrow_mut().store_halfword(address / 2 * 2, value, true);
+        bus.store_halfword(address / 2 * 2, value, Access::NonSequential);
+        self.next_fetch_access = Access::NonSequential;
     }
 
     pub(super) fn block_data_transfer(
-        &mut self,
+        &mut self, bus: &mut dyn Bus,
         pre_indexing: bool,
         add_offset: bool,
         load_psr: bool,
@@ -524,7 +795,6 @@ impl Cpu {
     ) {
         let mut address = self.registers[base_register];
         self.registers[15] += 4;
-        self.memory.borrow_mut().add_clock_cycles(1);
         let mut number_registers = register_mask.count_ones();
         let mut registers = Vec::with_capacity(number_registers as usize);
         let mut final_address = 0;
@@ -569,9 +839,9 @@ impl Cpu {
         }
 
         if load {
-            self.load_multiple(address, &registers, old_mode, load_psr);
+            self.load_multiple(bus, address, &registers, old_mode, load_psr);
         } else {
-            self.store_multiple(address, &registers);
+            self.store_multiple(bus, address, &registers);
         }
 
         if load_psr && old_mode != USER_MODE {
@@ -584,7 +854,8 @@ impl Cpu {
         }
     }
 
-    fn load_multiple(&mut self, mut address: u32, registers: &[u8], old_mode: u32, psr_bit: bool) {
+    fn load_multiple(&mut self, bus: &mut dyn Bus, mut address: u32, registers: &[u8], old_mode: u32, psr_bit: bool) {
+        let mut access = Access::NonSequential;
         for register in registers {
             if *register == 15 {
                 self.flush = true;
@@ -594,37 +865,83 @@ impl Cpu {
                     self.cpsr_register = (self.cpsr_register & 0xffff_ffe0) | USER_MODE;
                 }
             }
-            self.registers[*register as usize] = self.memory.borrow_mut().get_word(address, true);
+            self.registers[*register as usize] = bus.get_word(address, access);
+            access = Access::Sequential;
             address += 4;
         }
-        self.memory.borrow_mut().add_clock_cycles(1);
+        bus.add_clock_cycles(1);
+        self.next_fetch_access = Access::Sequential;
     }
 
-    fn store_multiple(&mut self, mut address: u32, registers: &[u8]) {
+    fn store_multiple(&mut self, bus: &mut dyn Bus, mut address: u32, registers: &[u8]) {
+        let mut access = Access::NonSequential;
         for register in registers {
             let value_to_store = self.registers[*register as usize];
-            self.memory.borrow_mut().store_word(address, value_to_store, true);
+            bus.store_word(address, value_to_store, access);
+            access = Access::Sequential;
             address += 4;
         }
+        self.next_fetch_access = Access::NonSequential;
     }
 
-    pub(super) fn single_data_swap(&mut self, transfer_byte: bool, address_register: usize, dst_register: usize, src_register: usize) {
+    pub(super) fn single_data_swap(&mut self, bus: &mut dyn Bus, transfer_byte: bool, address_register: usize, dst_register: usize, src_register: usize) {
         let address = self.registers[address_register];
-        let old_dst_register = self.registers[dst_register];
-        self.load_memory(address, dst_register, transfer_byte);
-        let new_dst_register = self.registers[dst_register];
-        self.registers[dst_register] = old_dst_register;
-        self.store_memory(address, src_register, transfer_byte);
-        self.registers[dst_register] = new_dst_register;
+        let value_to_store = self.registers[src_register];
+        // The read and the write are back to back, the internal cycle comes last
+        let value = if transfer_byte {
+            let value = bus.get_byte(address, Access::NonSequential) as u32;
+            bus.store_byte(address, value_to_store as u8, Access::NonSequential);
+            value
+        } else {
+            let value = bus.get_word(address & 0xFFFF_FFFC, Access::NonSequential).rotate_right((address & 0x3) * 8);
+            bus.store_word(address & 0xFFFF_FFFC, value_to_store, Access::NonSequential);
+            value
+        };
+        bus.add_clock_cycles(1);
+        self.registers[dst_register] = value;
+        self.last_data_bus_read = value;
+        self.next_fetch_access = Access::Sequential;
     }
 
-    pub(super) fn software_interrupt(&mut self) {
-        self.supervisor_banked[1] = self.registers[15] - 4;
+    pub(super) fn software_interrupt(&mut self, bus: &mut dyn Bus, function: u32) {
+        if self.hle_bios {
+            if !hle::software_interrupt(self, bus, function) {
+                self.raise_fault("Unsupported BIOS call without a BIOS image");
+            }
+            return;
+        }
+        // Return to the instruction after the SWI
+        let instruction_size = if self.is_thumb() { 2 } else { 4 };
+        self.supervisor_banked[1] = self.registers[15] - instruction_size;
         let old_mode = self.cpsr_register & 0x1F;
         self.saved_psr[2] = self.cpsr_register;
-        self.cpsr_register = SUPERVISOR_MODE;
+        self.cpsr_register = (self.cpsr_register & !(STATE_BIT | 0x1F)) | IRQ_BIT | SUPERVISOR_MODE;
         self.switch_modes(old_mode);
         self.registers[15] = 0x8;
         self.flush = true;
     }
 }
+
+/// Internal cycles the multiplier needs for an operand, it stops early when the upper bytes are all 0 (or all 1 when signed).
+pub(super) fn multiplier_cycles(operand: u32, signed: bool) -> usize {
+    let upper_bits_unused = |mask: u32| (operand & mask) == 0 || ((operand & mask) == mask && signed);
+    if upper_bits_unused(0xFFFF_FF00) {
+        1
+    } else if upper_bits_unused(0xFFFF_0000) {
+        2
+    } else if upper_bits_unused(0xFF00_0000) {
+        3
+    } else {
+        4
+    }
+}
+
+fn decode_arm(opcode: u32) -> PipelineStage2 {
+    let bits27_20 = (opcode >> 20) & 0xff;
+    let bits7_4 = (opcode >> 4) & 0xf;
+    PipelineStage2 { handler: ARM_INSTRUCTION_LUT[((bits27_20 << 4) | bits7_4) as usize], opcode }
+}
+
+fn decode_thumb(opcode: u32) -> PipelineStage2 {
+    PipelineStage2 { handler: THUMB_INSTRUCTION_LUT[(opcode >> 8) as usize], opcode }
+}
@@ -0,0 +1,344 @@
+// Textual disassembly of ARM and THUMB opcodes, used by the tracer and the debugging tools.
+// This file only depends on core, so tools that just need mnemonics can include it directly.
+
+const CONDITIONS: [&str; 16] = [
+    "eq", "ne", "cs", "cc", "mi", "pl", "vs", "vc", "hi", "ls", "ge", "lt", "gt", "le", "", "nv"
+];
+const ALU_MNEMONICS: [&str; 16] = [
+    "and", "eor", "sub", "rsb", "add", "adc", "sbc", "rsc", "tst", "teq", "cmp", "cmn", "orr", "mov", "bic", "mvn"
+];
+const SHIFT_MNEMONICS: [&str; 4] = ["lsl", "lsr", "asr", "ror"];
+const THUMB_ALU_MNEMONICS: [&str; 16] = [
+    "and", "eor", "lsl", "lsr", "asr", "adc", "sbc", "ror", "tst", "neg", "cmp", "cmn", "orr", "mul", "bic", "mvn"
+];
+
+fn register_name(register: u32) -> String {
+    match register & 0xF {
+        13 => "sp".to_string(),
+        14 => "lr".to_string(),
+        15 => "pc".to_string(),
+        x => format!("r{}", x),
+    }
+}
+
+fn register_list(mask: u32) -> String {
+    let mut registers = Vec::new();
+    for i in 0..16 {
+        if mask & (1 << i) != 0 {
+            registers.push(register_name(i));
+        }
+    }
+    format!("{{{}}}", registers.join(", "))
+}
+
+fn shifted_register(opcode: u32) -> String {
+    let rm = register_name(opcode & 0xF);
+    let shift_type = (opcode >> 5) & 0x3;
+    if opcode & 0x10 != 0 {
+        return format!("{}, {} {}", rm, SHIFT_MNEMONICS[shift_type as usize], register_name(opcode >> 8));
+    }
+    let amount = (opcode >> 7) & 0x1F;
+    match (shift_type, amount) {
+        (0, 0) => rm,
+        (3, 0) => format!("{}, rrx", rm),
+        (1, 0) | (2, 0) => format!("{}, {} #32", rm, SHIFT_MNEMONICS[shift_type as usize]),
+        _ => format!("{}, {} #{}", rm, SHIFT_MNEMONICS[shift_type as usize], amount),
+    }
+}
+
+/// Disassembles an ARM opcode located at `address`.
+pub fn disassemble_arm(opcode: u32, address: u32) -> String {
+    let condition = CONDITIONS[(opcode >> 28) as usize];
+    if opcode & 0x0FFF_FFF0 == 0x012F_FF10 {
+        format!("bx{} {}", condition, register_name(opcode))
+    } else if opcode & 0x0E00_0000 == 0x0A00_0000 {
+        let offset = ((opcode << 8) as i32) >> 6;
+        let target = address.wrapping_add(8).wrapping_add(offset as u32);
+        let link = if opcode & 0x0100_0000 != 0 { "l" } else { "" };
+        format!("b{}{} #0x{:08X}", link, condition, target)
+    } else if opcode & 0x0FC0_00F0 == 0x0000_0090 {
+        let set_flags = if opcode & 0x10_0000 != 0 { "s" } else { "" };
+        let rd = register_name(opcode >> 16);
+        let rm = register_name(opcode);
+        let rs = register_name(opcode >> 8);
+        if opcode & 0x20_0000 != 0 {
+            format!("mla{}{} {}, {}, {}, {}", condition, set_flags, rd, rm, rs, register_name(opcode >> 12))
+        } else {
+            format!("mul{}{} {}, {}, {}", condition, set_flags, rd, rm, rs)
+        }
+    } else if opcode & 0x0F80_00F0 == 0x0080_0090 {
+        let signed = if opcode & 0x40_0000 != 0 { "s" } else { "u" };
+        let operation = if opcode & 0x20_0000 != 0 { "mlal" } else { "mull" };
+        let set_flags = if opcode & 0x10_0000 != 0 { "s" } else { "" };
+        format!(
+            "{}{}{}{} {}, {}, {}, {}",
+            signed,
+            operation,
+            condition,
+            set_flags,
+            register_name(opcode >> 12),
+            register_name(opcode >> 16),
+            register_name(opcode),
+            register_name(opcode >> 8)
+        )
+    } else if opcode & 0x0FB0_0FF0 == 0x0100_0090 {
+        let byte = if opcode & 0x40_0000 != 0 { "b" } else { "" };
+        format!(
+            "swp{}{} {}, {}, [{}]",
+            condition,
+            byte,
+            register_name(opcode >> 12),
+            register_name(opcode),
+            register_name(opcode >> 16)
+        )
+    } else if opcode & 0x0E00_0090 == 0x0000_0090 && opcode & 0x60 != 0 {
+        disassemble_arm_halfword_transfer(opcode, condition)
+    } else if opcode & 0x0FBF_0FFF == 0x010F_0000 {
+        let psr = if opcode & 0x40_0000 != 0 { "spsr" } else { "cpsr" };
+        format!("mrs{} {}, {}", condition, register_name(opcode >> 12), psr)
+    } else if opcode & 0x0DB0_F000 == 0x0120_F000 {
+        let psr = if opcode & 0x40_0000 != 0 { "spsr" } else { "cpsr" };
+        let mut fields = String::new();
+        for (bit, field) in [(16, 'c'), (17, 'x'), (18, 's'), (19, 'f')] {
+            if opcode & (1 << bit) != 0 {
+                fields.push(field);
+            }
+        }
+        let operand = if opcode & 0x0200_0000 != 0 {
+            format!("#0x{:X}", (opcode & 0xFF).rotate_right(((opcode >> 8) & 0xF) * 2))
+        } else {
+            register_name(opcode)
+        };
+        format!("msr{} {}_{}, {}", condition, psr, fields, operand)
+    } else if opcode & 0x0C00_0000 == 0x0000_0000 {
+        disassemble_arm_data_processing(opcode, condition)
+    } else if opcode & 0x0E00_0010 == 0x0600_0010 {
+        format!("undefined{}", condition)
+    } else if opcode & 0x0C00_0000 == 0x0400_0000 {
+        disassemble_arm_single_data_transfer(opcode, condition)
+    } else if opcode & 0x0E00_0000 == 0x0800_0000 {
+        let load = opcode & 0x10_0000 != 0;
+        let mode = match ((opcode >> 23) & 0x1, (opcode >> 24) & 0x1) {
+            (1, 0) => "ia",
+            (1, _) => "ib",
+            (0, 0) => "da",
+            _ => "db",
+        };
+        let write_back = if opcode & 0x20_0000 != 0 { "!" } else { "" };
+        let user = if opcode & 0x40_0000 != 0 { "^" } else { "" };
+        format!(
+            "{}{}{} {}{}, {}{}",
+            if load { "ldm" } else { "stm" },
+            condition,
+            mode,
+            register_name(opcode >> 16),
+            write_back,
+            register_list(opcode & 0xFFFF),
+            user
+        )
+    } else if opcode & 0x0F00_0000 == 0x0F00_0000 {
+        format!("swi{} #0x{:X}", condition, opcode & 0xFF_FFFF)
+    } else {
+        format!("cop{} 0x{:08X}", condition, opcode)
+    }
+}
+
+fn disassemble_arm_data_processing(opcode: u32, condition: &str) -> String {
+    let alu_opcode = ((opcode >> 21) & 0xF) as usize;
+    let set_flags = opcode & 0x10_0000 != 0;
+    let operand_2 = if opcode & 0x0200_0000 != 0 {
+        format!("#0x{:X}", (opcode & 0xFF).rotate_right(((opcode >> 8) & 0xF) * 2))
+    } else {
+        shifted_register(opcode)
+    };
+    let rd = register_name(opcode >> 12);
+    let rn = register_name(opcode >> 16);
+    match alu_opcode {
+        0x8..=0xB => format!("{}{} {}, {}", ALU_MNEMONICS[alu_opcode], condition, rn, operand_2),
+        0xD | 0xF => format!(
+            "{}{}{} {}, {}",
+            ALU_MNEMONICS[alu_opcode],
+            condition,
+            if set_flags { "s" } else { "" },
+            rd,
+            operand_2
+        ),
+        _ => format!(
+            "{}{}{} {}, {}, {}",
+            ALU_MNEMONICS[alu_opcode],
+            condition,
+            if set_flags { "s" } else { "" },
+            rd,
+            rn,
+            operand_2
+        ),
+    }
+}
+
+fn addressing(base: String, offset: String, pre_indexing: bool, write_back: bool) -> String {
+    if pre_indexing {
+        format!("[{}, {}]{}", base, offset, if write_back { "!" } else { "" })
+    } else {
+        format!("[{}], {}", base, offset)
+    }
+}
+
+fn disassemble_arm_single_data_transfer(opcode: u32, condition: &str) -> String {
+    let load = opcode & 0x10_0000 != 0;
+    let byte = if opcode & 0x40_0000 != 0 { "b" } else { "" };
+    let sign = if opcode & 0x80_0000 != 0 { "" } else { "-" };
+    let offset = if opcode & 0x0200_0000 != 0 {
+        format!("{}{}", sign, shifted_register(opcode))
+    } else {
+        format!("#{}0x{:X}", sign, opcode & 0xFFF)
+    };
+    format!(
+        "{}{}{} {}, {}",
+        if load { "ldr" } else { "str" },
+        condition,
+        byte,
+        register_name(opcode >> 12),
+        addressing(register_name(opcode >> 16), offset, opcode & 0x0100_0000 != 0, opcode & 0x20_0000 != 0)
+    )
+}
+
+fn disassemble_arm_halfword_transfer(opcode: u32, condition: &str) -> String {
+    let load = opcode & 0x10_0000 != 0;
+    let suffix = match (opcode >> 5) & 0x3 {
+        1 => "h",
+        2 => "sb",
+        _ => "sh",
+    };
+    let sign = if opcode & 0x80_0000 != 0 { "" } else { "-" };
+    let offset = if opcode & 0x40_0000 != 0 {
+        format!("#{}0x{:X}", sign, (opcode & 0xF) | ((opcode >> 4) & 0xF0))
+    } else {
+        format!("{}{}", sign, register_name(opcode))
+    };
+    format!(
+        "{}{}{} {}, {}",
+        if load { "ldr" } else { "str" },
+        condition,
+        suffix,
+        register_name(opcode >> 12),
+        addressing(register_name(opcode >> 16), offset, opcode & 0x0100_0000 != 0, opcode & 0x20_0000 != 0)
+    )
+}
+
+/// Disassembles a THUMB opcode located at `address`.
+pub fn disassemble_thumb(opcode: u16, address: u32) -> String {
+    let opcode = opcode as u32;
+    let low_register = |bits: u32| register_name((opcode >> bits) & 0x7);
+    match opcode >> 13 {
+        0b000 => {
+            if opcode & 0x1800 == 0x1800 {
+                let mnemonic = if opcode & 0x200 != 0 { "sub" } else { "add" };
+                let operand = if opcode & 0x400 != 0 {
+                    format!("#0x{:X}", (opcode >> 6) & 0x7)
+                } else {
+                    low_register(6)
+                };
+                format!("{}s {}, {}, {}", mnemonic, low_register(0), low_register(3), operand)
+            } else {
+                format!(
+                    "{}s {}, {}, #{}",
+                    SHIFT_MNEMONICS[((opcode >> 11) & 0x3) as usize],
+                    low_register(0),
+                    low_register(3),
+                    (opcode >> 6) & 0x1F
+                )
+            }
+        }
+        0b001 => {
+            let mnemonic = ["movs", "cmp", "adds", "subs"][((opcode >> 11) & 0x3) as usize];
+            format!("{} {}, #0x{:X}", mnemonic, low_register(8), opcode & 0xFF)
+        }
+        0b010 => {
+            if opcode & 0x1000 != 0 {
+                let mnemonic = if opcode & 0x200 != 0 {
+                    ["strh", "ldrsb", "ldrh", "ldrsh"][((opcode >> 10) & 0x3) as usize]
+                } else {
+                    ["str", "strb", "ldr", "ldrb"][((opcode >> 10) & 0x3) as usize]
+                };
+                format!("{} {}, [{}, {}]", mnemonic, low_register(0), low_register(3), low_register(6))
+            } else if opcode & 0x800 != 0 {
+                let target = (address.wrapping_add(4) & !0x3).wrapping_add((opcode & 0xFF) << 2);
+                format!("ldr {}, [pc, #0x{:X}] ; =0x{:08X}", low_register(8), (opcode & 0xFF) << 2, target)
+            } else if opcode & 0x400 != 0 {
+                let rs = register_name((opcode >> 3) & 0xF);
+                let rd = register_name((opcode & 0x7) | ((opcode >> 4) & 0x8));
+                match (opcode >> 8) & 0x3 {
+                    0 => format!("add {}, {}", rd, rs),
+                    1 => format!("cmp {}, {}", rd, rs),
+                    2 => format!("mov {}, {}", rd, rs),
+                    _ => format!("bx {}", rs),
+                }
+            } else {
+                format!("{}s {}, {}", THUMB_ALU_MNEMONICS[((opcode >> 6) & 0xF) as usize], low_register(0), low_register(3))
+            }
+        }
+        0b011 => {
+            let byte = opcode & 0x1000 != 0;
+            let offset = if byte { (opcode >> 6) & 0x1F } else { ((opcode >> 6) & 0x1F) << 2 };
+            let mnemonic = match (opcode & 0x800 != 0, byte) {
+                (false, false) => "str",
+                (false, true) => "strb",
+                (true, false) => "ldr",
+                (true, true) => "ldrb",
+            };
+            format!("{} {}, [{}, #0x{:X}]", mnemonic, low_register(0), low_register(3), offset)
+        }
+        0b100 => {
+            let mnemonic = if opcode & 0x800 != 0 { "ldr" } else { "str" };
+            if opcode & 0x1000 != 0 {
+                format!("{} {}, [sp, #0x{:X}]", mnemonic, low_register(8), (opcode & 0xFF) << 2)
+            } else {
+                format!("{}h {}, [{}, #0x{:X}]", mnemonic, low_register(0), low_register(3), ((opcode >> 6) & 0x1F) << 1)
+            }
+        }
+        0b101 => {
+            if opcode & 0x1000 == 0 {
+                let source = if opcode & 0x800 != 0 { "sp" } else { "pc" };
+                format!("add {}, {}, #0x{:X}", low_register(8), source, (opcode & 0xFF) << 2)
+            } else if opcode & 0x600 == 0x400 {
+                let load = opcode & 0x800 != 0;
+                let mut mask = opcode & 0xFF;
+                if opcode & 0x100 != 0 {
+                    mask |= if load { 1 << 15 } else { 1 << 14 };
+                }
+                format!("{} {}", if load { "pop" } else { "push" }, register_list(mask))
+            } else if opcode & 0x600 == 0x0 {
+                let sign = if opcode & 0x80 != 0 { "-" } else { "" };
+                format!("add sp, #{}0x{:X}", sign, (opcode & 0x7F) << 2)
+            } else {
+                format!("undefined 0x{:04X}", opcode)
+            }
+        }
+        0b110 => {
+            if opcode & 0x1000 == 0 {
+                let mnemonic = if opcode & 0x800 != 0 { "ldmia" } else { "stmia" };
+                format!("{} {}!, {}", mnemonic, low_register(8), register_list(opcode & 0xFF))
+            } else if opcode & 0xF00 == 0xF00 {
+                format!("swi #0x{:X}", opcode & 0xFF)
+            } else {
+                let offset = (((opcode & 0xFF) << 24) as i32) >> 23;
+                let target = address.wrapping_add(4).wrapping_add(offset as u32);
+                format!("b{} #0x{:08X}", CONDITIONS[((opcode >> 8) & 0xF) as usize], target)
+            }
+        }
+        _ => {
+            match (opcode >> 11) & 0x3 {
+                0b00 => {
+                    let offset = (((opcode & 0x7FF) << 21) as i32) >> 20;
+                    format!("b #0x{:08X}", address.wrapping_add(4).wrapping_add(offset as u32))
+                }
+                0b10 => {
+                    let offset = (((opcode & 0x7FF) << 21) as i32) >> 9;
+                    format!("bl (hi) #0x{:08X}", address.wrapping_add(4).wrapping_add(offset as u32))
+                }
+                0b11 => format!("bl (lo) #0x{:X}", (opcode & 0x7FF) << 1),
+                _ => format!("undefined 0x{:04X}", opcode),
+            }
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use crate::bus::Bus;
+use crate::check_bit;
+use crate::memory::Access;
+
+use super::cpu::Cpu;
+
+/// Runs a BIOS call natively for systems without a BIOS image. Returns false for calls that
+/// aren't implemented.
+pub(super) fn software_interrupt(cpu: &mut Cpu, bus: &mut dyn Bus, function: u32) -> bool {
+    match function {
+        // Halt, IntrWait and VBlankIntrWait, VBlank is the only interrupt source so they all wait for it
+        0x02 | 0x04 | 0x05 => cpu.halted = true,
+        0x06 => return divide(cpu, 0, 1),
+        0x07 => return divide(cpu, 1, 0),
+        0x08 => cpu.registers[0] = integer_square_root(cpu.registers[0]),
+        0x0B => cpu_set(cpu, bus),
+        0x0C => cpu_fast_set(cpu, bus),
+        0x11 => lz77_decompress(cpu, bus, false),
+        0x12 => lz77_decompress(cpu, bus, true),
+        _ => return false,
+    }
+    true
+}
+
+// Div and DivArm only differ in the order of the operands
+fn divide(cpu: &mut Cpu, numerator_register: usize, denominator_register: usize) -> bool {
+    let numerator = cpu.registers[numerator_register] as i32;
+    let denominator = cpu.registers[denominator_register] as i32;
+    if denominator == 0 {
+        // The real BIOS never returns
+        return false;
+    }
+    let quotient = numerator.wrapping_div(denominator);
+    cpu.registers[0] = quotient as u32;
+    cpu.registers[1] = numerator.wrapping_rem(denominator) as u32;
+    cpu.registers[3] = quotient.unsigned_abs();
+    true
+}
+
+fn integer_square_root(value: u32) -> u32 {
+    let mut root = (value as f64).sqrt() as u32;
+    while root as u64 * root as u64 > value as u64 {
+        root -= 1;
+    }
+    while (root as u64 + 1) * (root as u64 + 1) <= value as u64 {
+        root += 1;
+    }
+    root
+}
+
+fn cpu_set(cpu: &mut Cpu, bus: &mut dyn Bus) {
+    let (mut source, mut destination, control) = (cpu.registers[0], cpu.registers[1], cpu.registers[2]);
+    let fill = check_bit!(control, 24);
+    let words = check_bit!(control, 26);
+    let step = if words { 4 } else { 2 };
+    for _ in 0..control & 0x1F_FFFF {
+        if words {
+            let value = bus.get_word(source & !3, Access::Sequential);
+            bus.store_word(destination & !3, value, Access::Sequential);
+        } else {
+            let value = bus.get_halfword(source & !1, Access::Sequential);
+            bus.store_halfword(destination & !1, value, Access::Sequential);
+        }
+        if !fill {
+            source = source.wrapping_add(step);
+        }
+        destination = destination.wrapping_add(step);
+    }
+}
+
+// Copies or fills words in blocks of 8
+fn cpu_fast_set(cpu: &mut Cpu, bus: &mut dyn Bus) {
+    let (mut source, mut destination, control) = (cpu.registers[0] & !3, cpu.registers[1] & !3, cpu.registers[2]);
+    let fill = check_bit!(control, 24);
+    let count = ((control & 0x1F_FFFF) + 7) & !7;
+    let fill_value = bus.get_word(source, Access::NonSequential);
+    for _ in 0..count {
+        let value = if fill { fill_value } else { bus.get_word(source, Access::Sequential) };
+        bus.store_word(destination, value, Access::Sequential);
+        source = source.wrapping_add(4);
+        destination = destination.wrapping_add(4);
+    }
+}
+
+// VRAM can't take byte stores, so the VRAM variant writes pairs of bytes as halfwords
+fn lz77_decompress(cpu: &mut Cpu, bus: &mut dyn Bus, halfwords: bool) {
+    let mut source = cpu.registers[0];
+    let destination = cpu.registers[1];
+    let header = bus.get_word(source & !3, Access::NonSequential);
+    source = source.wrapping_add(4);
+    let length = header >> 8;
+    let mut written = 0;
+    let mut read_byte = |bus: &mut dyn Bus| {
+        let value = bus.get_byte(source, Access::Sequential);
+        source = source.wrapping_add(1);
+        value
+    };
+    let write_byte = |bus: &mut dyn Bus, offset: u32, value: u8| {
+        let address = destination.wrapping_add(offset);
+        if halfwords {
+            let halfword = bus.peek(address & !1, 2).unwrap_or(0) as u16;
+            let shift = (address & 1) * 8;
+            let halfword = (halfword & !(0xFF << shift)) | ((value as u16) << shift);
+            bus.store_halfword(address & !1, halfword, Access::Sequential);
+        } else {
+            bus.store_byte(address, value, Access::Sequential);
+        }
+    };
+    while written < length {
+        let flags = read_byte(bus);
+        for bit in (0..8).rev() {
+            if written >= length {
+                break;
+            }
+            if flags & (1 << bit) == 0 {
+                let value = read_byte(bus);
+                write_byte(bus, written, value);
+                written += 1;
+                continue;
+            }
+            let high = read_byte(bus) as u32;
+            let low = read_byte(bus) as u32;
+            let run = (high >> 4) + 3;
+            let displacement = (((high & 0xF) << 8) | low) + 1;
+            for _ in 0..run.min(length - written) {
+                let value = bus.peek(destination.wrapping_add(written).wrapping_sub(displacement), 1).unwrap_or(0) as u8;
+                write_byte(bus, written, value);
+                written += 1;
+            }
+        }
+    }
+}
@@ -2,4 +2,8 @@ pub mod cpu;
 mod alu;
 mod constants;
 mod arm_lut;
-mod thumb_lut;
\ No newline at end of file
+mod thumb_lut;
+mod block_cache;
+mod hle;
+pub mod tracer;
+pub mod disassembler;
\ No newline at end of file
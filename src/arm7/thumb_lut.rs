@@ -1,10 +1,10 @@
-use crate::{check_bit, get_thumb_register_number_at};
+use crate::{bus::Bus, check_bit, get_thumb_register_number_at};
 
-use super::cpu::{Cpu, CONDITION_LUT};
+use super::cpu::{multiplier_cycles, Cpu, CONDITION_LUT};
 
 use super::constants::*;
 
-fn dummy(cpu: &mut Cpu, foo: u32) { }
+fn dummy(cpu: &mut Cpu, _bus: &mut dyn Bus, foo: u32) { }
 
 pub const fn thumb_instruction_lut() -> [InstructionHandler; 256] {
     let dummy: InstructionHandler = dummy;
@@ -62,7 +62,7 @@ const fn decode_thumb(bits15_8: u8) -> InstructionHandler {
     }
 }
 
-fn move_shifted_register_handler(cpu: &mut Cpu, opcode: u32) {
+fn move_shifted_register_handler(cpu: &mut Cpu, bus: &mut dyn Bus, opcode: u32) {
     let source_register = get_thumb_register_number_at!(opcode, 3);
     let destination_register = get_thumb_register_number_at!(opcode, 0);
     let shift_type = match opcode & 0x1800 {
@@ -72,12 +72,12 @@ fn move_shifted_register_handler(cpu: &mut Cpu, opcode: u32) {
         _ => panic!()
     };
 
-    let operand_2 = cpu.get_operand2(Operand2Type::RegisterWithImmediateShift, shift_type, true, ((opcode & 0x7C0) << 1) | source_register as u32);
+    let operand_2 = cpu.get_operand2(bus, Operand2Type::RegisterWithImmediateShift, shift_type, true, ((opcode & 0x7C0) << 1) | source_register as u32);
 
     cpu.decode_alu(AluOpcode::Move, true, 0, destination_register, operand_2)
 }
 
-fn add_subtract_handler(cpu: &mut Cpu, opcode: u32) {
+fn add_subtract_handler(cpu: &mut Cpu, bus: &mut dyn Bus, opcode: u32) {
     let operand_type = if check_bit!(opcode, 10) {
         Operand2Type::Immediate
     } else {
@@ -88,14 +88,14 @@ fn add_subtract_handler(cpu: &mut Cpu, opcode: u32) {
     } else {
         AluOpcode::Add
     };
-    let operand_2 = cpu.get_operand2(operand_type, ShiftType::LogicalLeft, false, (opcode & 0x7) >> 6);
+    let operand_2 = cpu.get_operand2(bus, operand_type, ShiftType::LogicalLeft, false, (opcode >> 6) & 0x7);
     let operand_1_register = get_thumb_register_number_at!(opcode, 3);
     let destination_register = get_thumb_register_number_at!(opcode, 0);
     
     cpu.decode_alu(alu_opcode, true, operand_1_register, destination_register, operand_2);
 }
 
-fn alu_immeddiate_handler(cpu: &mut Cpu, opcode: u32) {
+fn alu_immeddiate_handler(cpu: &mut Cpu, _bus: &mut dyn Bus, opcode: u32) {
     let alu_opcode = match opcode & 0x1800 {
         0x0 => AluOpcode::Move,
         0x800 => AluOpcode::CompareSubtract,
@@ -109,16 +109,23 @@ fn alu_immeddiate_handler(cpu: &mut Cpu, opcode: u32) {
     cpu.decode_alu(alu_opcode, true, src_dst_register, src_dst_register, operand_2);
 }
 
-fn alu_operations_handler(cpu: &mut Cpu, opcode: u32) {
+fn alu_operations_handler(cpu: &mut Cpu, bus: &mut dyn Bus, opcode: u32) {
     let alu_opcode = to_alu_opcode((opcode & 0x3C0) >> 6);
     let operand_1_register = get_thumb_register_number_at!(opcode, 0);
     let operand_2_register = get_thumb_register_number_at!(opcode, 3);
-    let operand_2 = cpu.get_operand2(Operand2Type::RegisterWithImmediateShift, ShiftType::LogicalLeft, false, operand_2_register as u32);
+    let operand_2 = cpu.get_operand2(bus, Operand2Type::RegisterWithImmediateShift, ShiftType::LogicalLeft, false, operand_2_register as u32);
+    // Shifts by register take an internal cycle, MUL one per multiplier byte
+    let internal_cycles = match (opcode & 0x3C0) >> 6 {
+        0x2 | 0x3 | 0x4 | 0x7 => 1,
+        0xD => multiplier_cycles(cpu.registers[operand_1_register], true),
+        _ => 0
+    };
+    bus.add_clock_cycles(internal_cycles);
 
     cpu.decode_alu(alu_opcode, true, operand_1_register, operand_1_register, operand_2);
 }
 
-fn hi_register_operation_handler(cpu: &mut Cpu, opcode: u32) {
+fn hi_register_operation_handler(cpu: &mut Cpu, bus: &mut dyn Bus, opcode: u32) {
     let source_register = ((opcode as usize & 0x40) >> 3) | get_thumb_register_number_at!(opcode, 3);
     let destination_register = ((opcode as usize & 0x80) >> 4) | get_thumb_register_number_at!(opcode, 0);
     if opcode & 0x300 == 0x300 {
@@ -130,66 +137,66 @@ fn hi_register_operation_handler(cpu: &mut Cpu, opcode: u32) {
             0x200 => (AluOpcode::Move, false),
             _ => panic!()
         };
-        let operand_2 = cpu.get_operand2(Operand2Type::RegisterWithImmediateShift, ShiftType::LogicalLeft, false, source_register as u32);
+        let operand_2 = cpu.get_operand2(bus, Operand2Type::RegisterWithImmediateShift, ShiftType::LogicalLeft, false, source_register as u32);
 
         cpu.decode_alu(alu_opcode, set_condition_codes, destination_register, destination_register, operand_2)
     }
 }
 
-fn pc_relative_handler(cpu: &mut Cpu, opcode: u32) {
+fn pc_relative_handler(cpu: &mut Cpu, bus: &mut dyn Bus, opcode: u32) {
     let destination_register = get_thumb_register_number_at!(opcode, 8);
     let offset = (opcode & 0xFF) << 2;
 
-    cpu.single_data_transfer(true, true, false, false, true, 15, offset, destination_register, false);
+    cpu.single_data_transfer(bus, true, true, false, false, true, 15, offset, destination_register, false);
 }
 
-fn load_store_with_register_offset_handler(cpu: &mut Cpu, opcode: u32) {
+fn load_store_with_register_offset_handler(cpu: &mut Cpu, bus: &mut dyn Bus, opcode: u32) {
     let halfword = check_bit!(opcode, 9);
     let register_offset = get_thumb_register_number_at!(opcode, 6);
-    let offset = cpu.get_operand2(Operand2Type::RegisterWithImmediateShift, ShiftType::LogicalLeft, false, register_offset as u32);
+    let offset = cpu.get_operand2(bus, Operand2Type::RegisterWithImmediateShift, ShiftType::LogicalLeft, false, register_offset as u32);
     let base_register = get_thumb_register_number_at!(opcode, 3);
     let src_dst_register = get_thumb_register_number_at!(opcode, 0);
 
     if halfword {
         let load = (opcode >> 10) & 0x3 != 0x0;
         let halfword_transfer_type = to_halfword_transfer_type((opcode >> 10) & 0x3);
-        cpu.halfword_data_transfer(true, true, true, false, load, halfword_transfer_type, base_register, src_dst_register, offset, offset as usize);
+        cpu.halfword_data_transfer(bus, true, true, true, false, load, halfword_transfer_type, base_register, src_dst_register, offset, offset as usize);
     } else {
         let load = check_bit!(opcode, 11);
         let transfer_byte = check_bit!(opcode, 10);
-        cpu.single_data_transfer(true, true, transfer_byte, false, load, base_register, offset, src_dst_register, false);
+        cpu.single_data_transfer(bus, true, true, transfer_byte, false, load, base_register, offset, src_dst_register, false);
     }
 }
 
-fn load_store_with_immediate_offset_handler(cpu: &mut Cpu, opcode: u32) {
+fn load_store_with_immediate_offset_handler(cpu: &mut Cpu, bus: &mut dyn Bus, opcode: u32) {
     let transfer_byte = check_bit!(opcode, 12);
     let load = check_bit!(opcode, 11);
     let offset = ((opcode >> 6) & 0x1F) << 2;
     let base_register = get_thumb_register_number_at!(opcode, 3);
     let src_dst_register = get_thumb_register_number_at!(opcode, 0);
 
-    cpu.single_data_transfer(true, true, transfer_byte, false, load, base_register, offset, src_dst_register, false);
+    cpu.single_data_transfer(bus, true, true, transfer_byte, false, load, base_register, offset, src_dst_register, false);
 }
 
-fn load_store_halfword_handler(cpu: &mut Cpu, opcode: u32) {
+fn load_store_halfword_handler(cpu: &mut Cpu, bus: &mut dyn Bus, opcode: u32) {
     let load = check_bit!(opcode, 11);
     let offset = (opcode >> 6) & 0x1F;
     let base_register = get_thumb_register_number_at!(opcode, 3);
     let src_dst_register = get_thumb_register_number_at!(opcode, 0);
     let halfword_transfer_type = HalfwordTransferType::UnsignedHalfwords;
 
-    cpu.halfword_data_transfer(true, true, true, false, load, halfword_transfer_type, base_register, src_dst_register, offset, offset as usize);
+    cpu.halfword_data_transfer(bus, true, true, true, false, load, halfword_transfer_type, base_register, src_dst_register, offset, offset as usize);
 }
 
-fn sp_relative_load_handler(cpu: &mut Cpu, opcode: u32) {
+fn sp_relative_load_handler(cpu: &mut Cpu, bus: &mut dyn Bus, opcode: u32) {
     let load = check_bit!(opcode, 11);
     let destination_register = get_thumb_register_number_at!(opcode, 8);
     let offset = opcode & 0xFF;
 
-    cpu.single_data_transfer(true, true, false, false, load, 13, offset, destination_register, false);
+    cpu.single_data_transfer(bus, true, true, false, false, load, 13, offset, destination_register, false);
 }
 
-fn load_address_handler(cpu: &mut Cpu, opcode: u32) {
+fn load_address_handler(cpu: &mut Cpu, _bus: &mut dyn Bus, opcode: u32) {
     let destination_register = get_thumb_register_number_at!(opcode, 8);
     let source_register = if check_bit!(opcode, 11) {
         13
@@ -199,7 +206,7 @@ fn load_address_handler(cpu: &mut Cpu, opcode: u32) {
     cpu.decode_alu(AluOpcode::Add, false, source_register, destination_register, operand_2);
 }
 
-fn add_offset_to_stack_pointer_handler(cpu: &mut Cpu, opcode: u32) {
+fn add_offset_to_stack_pointer_handler(cpu: &mut Cpu, _bus: &mut dyn Bus, opcode: u32) {
     let operand_2 = (opcode & 0x3F) << 2;
     let alu_opcode = if check_bit!(opcode, 7) {
         AluOpcode::Subtract
@@ -210,7 +217,7 @@ fn add_offset_to_stack_pointer_handler(cpu: &mut Cpu, opcode: u32) {
     cpu.decode_alu(alu_opcode, false, 13, 13, operand_2);
 }
 
-fn push_pop_register_handler(cpu: &mut Cpu, opcode: u32) {
+fn push_pop_register_handler(cpu: &mut Cpu, bus: &mut dyn Bus, opcode: u32) {
     let load = check_bit!(opcode, 11);
     let r_bit = check_bit!(opcode, 8);
     let mut register_mask = opcode << 8;
@@ -220,18 +227,18 @@ fn push_pop_register_handler(cpu: &mut Cpu, opcode: u32) {
         register_mask |= (r_bit as u32) << 1;
     }
 
-    cpu.block_data_transfer(!load, !load, false, true, load, 13, register_mask)
+    cpu.block_data_transfer(bus, !load, !load, false, true, load, 13, register_mask)
 }
 
-fn multiple_load_store_handler(cpu: &mut Cpu, opcode: u32) {
+fn multiple_load_store_handler(cpu: &mut Cpu, bus: &mut dyn Bus, opcode: u32) {
     let load = check_bit!(opcode, 11);
     let base_register = get_thumb_register_number_at!(opcode, 8);
     let register_mask = opcode << 8;
 
-    cpu.block_data_transfer(false, true, false, true, load, base_register, register_mask);
+    cpu.block_data_transfer(bus, false, true, false, true, load, base_register, register_mask);
 }
 
-fn conditional_branch_handler(cpu: &mut Cpu, opcode: u32) {
+fn conditional_branch_handler(cpu: &mut Cpu, _bus: &mut dyn Bus, opcode: u32) {
     let offset = ((opcode as i32 & 0xFF) << 24) >> 24;
     let condition = (opcode as usize & 0xF00) >> 4;
 
@@ -240,17 +247,17 @@ fn conditional_branch_handler(cpu: &mut Cpu, opcode: u32) {
     }
 }
 
-fn software_interrupt_handler(cpu: &mut Cpu, opcode: u32) {
-    cpu.software_interrupt();
+fn software_interrupt_handler(cpu: &mut Cpu, bus: &mut dyn Bus, opcode: u32) {
+    cpu.software_interrupt(bus, opcode & 0xFF);
 }
 
-fn unconditional_branch_handler(cpu: &mut Cpu, opcode: u32) {
+fn unconditional_branch_handler(cpu: &mut Cpu, _bus: &mut dyn Bus, opcode: u32) {
     let offset = ((opcode as i32 & 0x7FF) << 21) >> 21;
 
     cpu.branch(false, offset, 1, 11);
 }
 
-fn long_branch_with_link_handler(cpu: &mut Cpu, opcode: u32) {
+fn long_branch_with_link_handler(cpu: &mut Cpu, _bus: &mut dyn Bus, opcode: u32) {
     let offset = (opcode as i32) & 0x7FF;
     if check_bit!(opcode, 11) {
         let temp = cpu.registers[15];
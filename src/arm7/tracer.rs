@@ -0,0 +1,384 @@
+use std::collections::VecDeque;
+use std::io::Write;
+
+use crate::error::GbaError;
+
+use super::constants::*;
+use super::disassembler::{disassemble_arm, disassemble_thumb};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CpuState {
+    Arm,
+    Thumb
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TraceFormat {
+    // R0-R15, CPSR and SPSR as little endian words, 72 bytes per instruction
+    Binary,
+    // Address, opcode and disassembly followed by the registers
+    Text,
+    // One line per instruction laid out like the mGBA/NanoBoyAdvance trace logs
+    Reference
+}
+
+impl TraceFormat {
+    pub fn from_name(name: &str) -> Result<TraceFormat, GbaError> {
+        match name {
+            "binary" | "bin" => Ok(TraceFormat::Binary),
+            "text" | "txt" => Ok(TraceFormat::Text),
+            "reference" | "mgba" | "nba" => Ok(TraceFormat::Reference),
+            _ => Err(GbaError::UnknownTraceFormat(name.to_string()))
+        }
+    }
+}
+
+/// Which instructions end up in the trace. Every field that is set has to match.
+#[derive(Clone, Default)]
+pub struct TraceFilter {
+    // Inclusive range of instruction addresses
+    pub pc_range: Option<(u32, u32)>,
+    pub mode: Option<u32>,
+    pub state: Option<CpuState>
+}
+
+impl TraceFilter {
+    fn matches(&self, record: &TraceRecord) -> bool {
+        if let Some((start, end)) = self.pc_range {
+            if record.address < start || record.address > end {
+                return false;
+            }
+        }
+        if let Some(mode) = self.mode {
+            if record.cpsr & 0x1F != mode {
+                return false;
+            }
+        }
+        if let Some(state) = self.state {
+            if record.state() != state {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct TraceConfig {
+    pub format: Option<TraceFormat>,
+    pub filter: TraceFilter,
+    // Only keep the last N instructions, written out when the tracer is flushed or dropped
+    pub ring_buffer: Option<usize>,
+    // Tracing starts when this address is executed
+    pub start_pc: Option<u32>,
+    // Tracing stops after this address is executed
+    pub stop_pc: Option<u32>
+}
+
+/// The CPU state right before an instruction is executed.
+#[derive(Clone, Copy)]
+pub struct TraceRecord {
+    pub registers: [u32; 16],
+    pub cpsr: u32,
+    pub spsr: u32,
+    pub opcode: u32,
+    pub address: u32
+}
+
+impl TraceRecord {
+    pub fn state(&self) -> CpuState {
+        if self.cpsr & STATE_BIT == STATE_BIT {
+            CpuState::Thumb
+        } else {
+            CpuState::Arm
+        }
+    }
+
+    pub fn disassemble(&self) -> String {
+        match self.state() {
+            CpuState::Arm => disassemble_arm(self.opcode, self.address),
+            CpuState::Thumb => disassemble_thumb(self.opcode as u16, self.address),
+        }
+    }
+
+    fn formatted_opcode(&self) -> String {
+        match self.state() {
+            CpuState::Arm => format!("{:08X}", self.opcode),
+            CpuState::Thumb => format!("    {:04X}", self.opcode),
+        }
+    }
+
+    fn write(&self, format: TraceFormat, output: &mut dyn Write) -> std::io::Result<()> {
+        match format {
+            TraceFormat::Binary => {
+                for register in self.registers {
+                    output.write_all(&register.to_le_bytes())?;
+                }
+                output.write_all(&self.cpsr.to_le_bytes())?;
+                output.write_all(&self.spsr.to_le_bytes())
+            }
+            TraceFormat::Text => {
+                write!(output, "{:08X}: {}  {:<32}", self.address, self.formatted_opcode(), self.disassemble())?;
+                for (i, register) in self.registers.iter().enumerate() {
+                    write!(output, " r{}={:08X}", i, register)?;
+                }
+                writeln!(output, " cpsr={:08X} spsr={:08X}", self.cpsr, self.spsr)
+            }
+            TraceFormat::Reference => {
+                for register in self.registers {
+                    write!(output, "{:08X} ", register)?;
+                }
+                writeln!(output, "cpsr: {:08X} | {}: {}", self.cpsr, self.formatted_opcode(), self.disassemble())
+            }
+        }
+    }
+}
+
+pub struct Tracer {
+    output: Box<dyn Write + Send>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    ring_buffer: Option<(usize, VecDeque<TraceRecord>)>,
+    start_pc: Option<u32>,
+    stop_pc: Option<u32>,
+    active: bool
+}
+
+impl Tracer {
+    pub fn new(output: Box<dyn Write + Send>, config: TraceConfig) -> Tracer {
+        Tracer {
+            output,
+            format: config.format.unwrap_or(TraceFormat::Binary),
+            filter: config.filter,
+            ring_buffer: config.ring_buffer.map(|size| (size, VecDeque::with_capacity(size))),
+            start_pc: config.start_pc,
+            stop_pc: config.stop_pc,
+            active: config.start_pc.is_none()
+        }
+    }
+
+    pub fn record(&mut self, record: TraceRecord) {
+        if !self.active && self.start_pc == Some(record.address) {
+            self.active = true;
+        }
+        if !self.active {
+            return;
+        }
+        if self.stop_pc == Some(record.address) {
+            self.active = false;
+        }
+        if !self.filter.matches(&record) {
+            return;
+        }
+        match self.ring_buffer.as_mut() {
+            // A ring buffer of size 0 keeps nothing
+            Some((size, buffer)) => {
+                if *size > 0 {
+                    if buffer.len() >= *size {
+                        buffer.pop_front();
+                    }
+                    buffer.push_back(record);
+                }
+            }
+            None => {
+                if record.write(self.format, &mut self.output).is_err() {
+                    self.active = false;
+                }
+            }
+        }
+    }
+
+    /// Writes out whatever is in the ring buffer.
+    pub fn flush(&mut self) {
+        if let Some((_, buffer)) = self.ring_buffer.as_mut() {
+            for record in buffer.drain(..) {
+                if record.write(self.format, &mut self.output).is_err() {
+                    break;
+                }
+            }
+        }
+        let _ = self.output.flush();
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        // Also runs while unwinding, which is what gives us the instructions leading up to a crash
+        self.flush();
+    }
+}
+
+pub fn mode_from_name(name: &str) -> Result<u32, GbaError> {
+    match name {
+        "usr" | "user" => Ok(USER_MODE),
+        "fiq" => Ok(FIQ_MODE),
+        "irq" => Ok(IRQ_MODE),
+        "svc" | "supervisor" => Ok(SUPERVISOR_MODE),
+        "abt" | "abort" => Ok(ABORT_MODE),
+        "und" | "undefined" => Ok(UNDEFINED_MODE),
+        "sys" | "system" => Ok(SYSTEM_MODE),
+        _ => Err(GbaError::UnknownCpuMode(name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    // Lets the test read back what the tracer wrote into its boxed output
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedOutput {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    fn new_tracer(config: TraceConfig) -> (Tracer, SharedOutput) {
+        let output = SharedOutput::default();
+        (Tracer::new(Box::new(output.clone()), config), output)
+    }
+
+    fn reference_config() -> TraceConfig {
+        TraceConfig { format: Some(TraceFormat::Reference), ..TraceConfig::default() }
+    }
+
+    // mov r0, #1 at `address` in system mode, r1 holds the address so records can be told apart
+    fn arm_record(address: u32) -> TraceRecord {
+        let mut registers = [0; 16];
+        registers[1] = address;
+        registers[15] = address + 8;
+        TraceRecord { registers, cpsr: SYSTEM_MODE, spsr: 0, opcode: 0xE3A0_0001, address }
+    }
+
+    fn thumb_record(address: u32) -> TraceRecord {
+        // movs r0, #0x42
+        TraceRecord { cpsr: SYSTEM_MODE | STATE_BIT, opcode: 0x2042, ..arm_record(address) }
+    }
+
+    // The addresses of the traced instructions, read back from r1 in reference lines
+    fn traced_addresses(output: &SharedOutput) -> Vec<u32> {
+        output.text().lines().map(|line| u32::from_str_radix(&line[9..17], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn binary_records_are_the_registers_cpsr_and_spsr() {
+        let (mut tracer, output) = new_tracer(TraceConfig::default());
+        let mut record = arm_record(0x0800_0000);
+        record.spsr = 0x1234_5678;
+        tracer.record(record);
+        tracer.record(arm_record(0x0800_0004));
+        drop(tracer);
+        let data = output.0.lock().unwrap().clone();
+        assert_eq!(data.len(), 2 * 72);
+        let word = |index: usize| u32::from_le_bytes(data[index * 4..index * 4 + 4].try_into().unwrap());
+        assert_eq!((word(1), word(15), word(16), word(17)), (0x0800_0000, 0x0800_0008, SYSTEM_MODE, 0x1234_5678));
+        assert_eq!(word(18 + 1), 0x0800_0004);
+    }
+
+    #[test]
+    fn text_lines_start_with_the_address_and_opcode() {
+        let (mut tracer, output) = new_tracer(TraceConfig { format: Some(TraceFormat::Text), ..TraceConfig::default() });
+        tracer.record(arm_record(0x0800_0000));
+        tracer.record(thumb_record(0x0800_0100));
+        let text = output.text();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("08000000: E3A00001  "), "{}", lines[0]);
+        assert!(lines[0].contains(" r1=08000000 ") && lines[0].contains(" r15=08000008 "), "{}", lines[0]);
+        assert!(lines[0].ends_with(" cpsr=0000001F spsr=00000000"), "{}", lines[0]);
+        assert!(lines[1].starts_with("08000100:     2042  "), "{}", lines[1]);
+        assert!(lines[1].ends_with(" cpsr=0000003F spsr=00000000"), "{}", lines[1]);
+    }
+
+    #[test]
+    fn reference_lines_list_the_registers_then_the_instruction() {
+        let (mut tracer, output) = new_tracer(reference_config());
+        let record = arm_record(0x0800_0000);
+        tracer.record(record);
+        tracer.record(thumb_record(0x0800_0100));
+        let text = output.text();
+        let lines: Vec<&str> = text.lines().collect();
+        let registers = "00000000 08000000 ".to_string() + &"00000000 ".repeat(13) + "08000008 ";
+        assert_eq!(lines[0], format!("{}cpsr: 0000001F | E3A00001: {}", registers, record.disassemble()));
+        assert!(lines[1].contains("cpsr: 0000003F |     2042: "), "{}", lines[1]);
+    }
+
+    #[test]
+    fn filters_have_to_match_together() {
+        let config = TraceConfig {
+            filter: TraceFilter { pc_range: Some((0x0800_0004, 0x0800_000C)), mode: None, state: None },
+            ..reference_config()
+        };
+        let (mut tracer, output) = new_tracer(config);
+        for address in (0x0800_0000..0x0800_0014).step_by(4) {
+            tracer.record(arm_record(address));
+        }
+        assert_eq!(traced_addresses(&output), [0x0800_0004, 0x0800_0008, 0x0800_000C]);
+
+        let config = TraceConfig {
+            filter: TraceFilter { pc_range: None, mode: Some(USER_MODE), state: Some(CpuState::Thumb) },
+            ..reference_config()
+        };
+        let (mut tracer, output) = new_tracer(config);
+        tracer.record(TraceRecord { cpsr: USER_MODE, ..arm_record(0x0800_0000) });
+        tracer.record(thumb_record(0x0800_0002));
+        tracer.record(TraceRecord { cpsr: USER_MODE | STATE_BIT, ..thumb_record(0x0800_0004) });
+        assert_eq!(traced_addresses(&output), [0x0800_0004]);
+    }
+
+    #[test]
+    fn ring_buffers_keep_the_last_records_until_flushed() {
+        let (mut tracer, output) = new_tracer(TraceConfig { ring_buffer: Some(3), ..reference_config() });
+        for address in (0x0800_0000..0x0800_0014).step_by(4) {
+            tracer.record(arm_record(address));
+        }
+        assert_eq!(output.text(), "");
+        tracer.flush();
+        assert_eq!(traced_addresses(&output), [0x0800_0008, 0x0800_000C, 0x0800_0010]);
+        // Flushing empties the buffer
+        tracer.flush();
+        assert_eq!(traced_addresses(&output).len(), 3);
+
+        let (mut tracer, output) = new_tracer(TraceConfig { ring_buffer: Some(0), ..reference_config() });
+        tracer.record(arm_record(0x0800_0000));
+        drop(tracer);
+        assert_eq!(output.text(), "");
+    }
+
+    #[test]
+    fn start_and_stop_addresses_trace_the_span_between_them() {
+        let config = TraceConfig { start_pc: Some(0x0800_0004), stop_pc: Some(0x0800_0008), ..reference_config() };
+        let (mut tracer, output) = new_tracer(config);
+        for address in [0x0800_0000, 0x0800_0004, 0x0800_0006, 0x0800_0008, 0x0800_000C, 0x0800_0004, 0x0800_0010] {
+            tracer.record(arm_record(address));
+        }
+        // The stop address itself is traced, and hitting the start again traces on
+        assert_eq!(traced_addresses(&output), [0x0800_0004, 0x0800_0006, 0x0800_0008, 0x0800_0004, 0x0800_0010]);
+    }
+
+    #[test]
+    fn names_parse() {
+        assert_eq!(TraceFormat::from_name("bin").unwrap(), TraceFormat::Binary);
+        assert_eq!(TraceFormat::from_name("txt").unwrap(), TraceFormat::Text);
+        assert_eq!(TraceFormat::from_name("mgba").unwrap(), TraceFormat::Reference);
+        assert!(TraceFormat::from_name("json").is_err());
+        assert_eq!(mode_from_name("svc").unwrap(), SUPERVISOR_MODE);
+        assert_eq!(mode_from_name("system").unwrap(), SYSTEM_MODE);
+        assert!(mode_from_name("hyp").is_err());
+    }
+}
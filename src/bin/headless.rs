@@ -0,0 +1,178 @@
+// Runs a ROM without a window, for test ROMs in CI.
+//
+// Usage: headless <rom> [--bios path] [--frames N] [--until-pc addr] [--until-mem addr=value[,size]]
+//                 [--expect hash] [--png path]
+//
+// Runs for N frames (600 by default), or until the PC reaches an address or a memory location holds
+// a value, then prints the CRC-32 of the frame buffer. Without a BIOS, BIOS calls are emulated.
+// Exits with 1 if the ROM couldn't run, 2 on bad arguments, 3 if a stop condition was never met
+// and 4 if the hash doesn't match --expect.
+
+use std::{env, fs, process};
+
+use dees_nuts::bus::Bus;
+use dees_nuts::{crc32, Gba, GbaError, CYCLES_PER_FRAME, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+struct Options {
+    rom: String,
+    bios: Option<String>,
+    frames: usize,
+    until_pc: Option<u32>,
+    // Address, value and access size in bytes
+    until_memory: Option<(u32, u32, usize)>,
+    expect: Option<u32>,
+    png: Option<String>
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: headless <rom> [--bios path] [--frames N] [--until-pc addr] [--until-mem addr=value[,size]] [--expect hash] [--png path]"
+    );
+    process::exit(2);
+}
+
+fn parse_hex(value: &str) -> u32 {
+    let value = value.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(value, 16).unwrap_or_else(|_| usage())
+}
+
+fn parse_args() -> Options {
+    let mut rom = None;
+    let mut options = Options {
+        rom: String::new(),
+        bios: None,
+        frames: 600,
+        until_pc: None,
+        until_memory: None,
+        expect: None,
+        png: None
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--bios" => options.bios = Some(value()),
+            "--frames" => options.frames = value().parse().unwrap_or_else(|_| usage()),
+            "--until-pc" => options.until_pc = Some(parse_hex(&value())),
+            "--until-mem" => {
+                let condition = value();
+                let (address, rest) = condition.split_once('=').unwrap_or_else(|| usage());
+                let (expected, size) = rest.split_once(',').unwrap_or((rest, "4"));
+                let size = match size {
+                    "1" | "2" | "4" => size.parse().unwrap(),
+                    _ => usage(),
+                };
+                options.until_memory = Some((parse_hex(address), parse_hex(expected), size));
+            }
+            "--expect" => options.expect = Some(parse_hex(&value())),
+            "--png" => options.png = Some(value()),
+            _ if rom.is_none() => rom = Some(arg),
+            _ => usage(),
+        }
+    }
+    options.rom = rom.unwrap_or_else(|| usage());
+    options
+}
+
+// Runs until a stop condition holds, returns false if none was met within the frame budget
+fn run(gba: &mut Gba, options: &Options) -> Result<bool, GbaError> {
+    if options.until_pc.is_none() && options.until_memory.is_none() {
+        for _ in 0..options.frames {
+            gba.frame()?;
+        }
+        return Ok(true);
+    }
+    let end = gba.cycles() + options.frames * CYCLES_PER_FRAME;
+    while gba.cycles() < end {
+        gba.step()?;
+        let pc_reached = options.until_pc.is_some_and(|pc| gba.cpu().current_instruction().map(|(address, _)| address) == Some(pc));
+        let memory_matches = options.until_memory.is_some_and(|(address, value, size)| gba.memory().peek(address, size) == Some(value));
+        if pc_reached || memory_matches {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn main() {
+    let options = parse_args();
+    let mut gba = Gba::new();
+    let loaded = options.bios.as_ref().map_or(Ok(()), |bios| gba.load_bios_file(bios)).and_then(|_| gba.load_rom_file(&options.rom));
+    let finished = match loaded.and_then(|_| run(&mut gba, &options)) {
+        Ok(finished) => finished,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1);
+        }
+    };
+
+    let hash = crc32(gba.frame_buffer());
+    println!("{:08X}", hash);
+    if let Some(path) = &options.png {
+        if let Err(error) = fs::write(path, encode_png(gba.frame_buffer())) {
+            eprintln!("Could not write {}: {}", path, error);
+            process::exit(1);
+        }
+    }
+    if !finished {
+        eprintln!("Stopped after {} frames without meeting the stop condition", options.frames);
+        process::exit(3);
+    }
+    if options.expect.is_some_and(|expected| expected != hash) {
+        eprintln!("Expected {:08X}", options.expect.unwrap());
+        process::exit(4);
+    }
+}
+
+// An 8 bit RGB PNG with the image data in uncompressed deflate blocks
+fn encode_png(frame_buffer: &[u8]) -> Vec<u8> {
+    let mut scanlines = Vec::with_capacity(SCREEN_HEIGHT * (1 + SCREEN_WIDTH * 3));
+    for row in frame_buffer.chunks(SCREEN_WIDTH * 2) {
+        // No filter
+        scanlines.push(0);
+        for pixel in row.chunks(2) {
+            let color = u16::from_le_bytes([pixel[0], pixel[1]]);
+            for shift in [0, 5, 10] {
+                let channel = ((color >> shift) & 0x1F) as u8;
+                scanlines.push((channel << 3) | (channel >> 2));
+            }
+        }
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let blocks = scanlines.chunks(0xFFFF);
+    let block_count = blocks.len();
+    for (index, block) in blocks.enumerate() {
+        zlib.push((index + 1 == block_count) as u8);
+        zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&scanlines).to_be_bytes());
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(SCREEN_WIDTH as u32).to_be_bytes());
+    header.extend_from_slice(&(SCREEN_HEIGHT as u32).to_be_bytes());
+    // Bit depth 8, truecolor, deflate, no filter, no interlace
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    for (kind, data) in [(b"IHDR", header), (b"IDAT", zlib), (b"IEND", Vec::new())] {
+        png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        let chunk_start = png.len();
+        png.extend_from_slice(kind);
+        png.extend_from_slice(&data);
+        let crc = crc32(&png[chunk_start..]);
+        png.extend_from_slice(&crc.to_be_bytes());
+    }
+    png
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
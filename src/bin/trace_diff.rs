@@ -0,0 +1,279 @@
+// Compares two instruction traces and reports where they first diverge.
+//
+// Usage: trace_diff <ours> <reference> [--mask r0,r1,...,cpsr,spsr] [--ignore-undefined-spsr]
+//                   [--context N] [--rom path] [--bios path]
+//
+// Traces are either the 72 byte binary layout (R0-R15, CPSR, SPSR) or one of the text layouts
+// written by the tracer. The text layouts carry the opcode, for binary traces it is looked up
+// in the ROM/BIOS images when they are given.
+
+use std::{env, fs, process};
+
+use dees_nuts::arm7::disassembler::{disassemble_arm, disassemble_thumb};
+
+const RECORD_SIZE: usize = 72;
+const STATE_BIT: u32 = 0x20;
+const USER_MODE: u32 = 0x10;
+const SYSTEM_MODE: u32 = 0x1F;
+const REGISTER_NAMES: [&str; 18] = [
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15", "cpsr", "spsr"
+];
+
+#[derive(Clone, Copy)]
+struct Record {
+    // R0-R15, CPSR and SPSR
+    values: [u32; 18],
+    has_spsr: bool,
+    opcode: Option<u32>
+}
+
+impl Record {
+    fn thumb(&self) -> bool {
+        self.values[16] & STATE_BIT == STATE_BIT
+    }
+
+    fn address(&self) -> u32 {
+        self.values[15].wrapping_sub(if self.thumb() { 4 } else { 8 })
+    }
+}
+
+struct Options {
+    mask: [bool; 18],
+    ignore_undefined_spsr: bool,
+    context: usize,
+    rom: Vec<u8>,
+    bios: Vec<u8>
+}
+
+fn parse_hex(value: &str) -> Option<u32> {
+    let value = value.trim().trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(value, 16).ok()
+}
+
+fn parse_binary(data: &[u8]) -> Vec<Record> {
+    data.chunks_exact(RECORD_SIZE)
+        .map(|chunk| {
+            let mut values = [0; 18];
+            for (i, word) in chunk.chunks_exact(4).enumerate() {
+                values[i] = u32::from_le_bytes(word.try_into().unwrap());
+            }
+            Record { values, has_spsr: true, opcode: None }
+        })
+        .collect()
+}
+
+// "ADDRESS: OPCODE  disassembly r0=XXXXXXXX ... cpsr=XXXXXXXX spsr=XXXXXXXX"
+fn parse_text_line(line: &str) -> Option<Record> {
+    let mut values = [0; 18];
+    let mut has_spsr = false;
+    let mut found = 0;
+    for token in line.split_whitespace() {
+        if let Some((name, value)) = token.split_once('=') {
+            if let Some(index) = REGISTER_NAMES.iter().position(|register| *register == name) {
+                values[index] = parse_hex(value)?;
+                found += 1;
+                has_spsr |= index == 17;
+            }
+        }
+    }
+    let opcode = line.split_once(':').and_then(|(_, rest)| rest.split_whitespace().next()).and_then(parse_hex);
+    (found >= 17).then_some(Record { values, has_spsr, opcode })
+}
+
+// "R0 R1 ... R15 cpsr: CPSR | OPCODE: disassembly"
+fn parse_reference_line(line: &str) -> Option<Record> {
+    let (registers, rest) = line.split_once("cpsr:")?;
+    let mut values = [0; 18];
+    let mut count = 0;
+    for token in registers.split_whitespace() {
+        if count == 16 {
+            return None;
+        }
+        values[count] = parse_hex(token)?;
+        count += 1;
+    }
+    if count != 16 {
+        return None;
+    }
+    let (cpsr, instruction) = rest.split_once('|').unwrap_or((rest, ""));
+    values[16] = parse_hex(cpsr)?;
+    let opcode = instruction.split(':').next().and_then(parse_hex);
+    Some(Record { values, has_spsr: false, opcode })
+}
+
+fn parse_text(data: &str) -> Result<Vec<Record>, String> {
+    data.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| {
+            let record = if line.contains("r0=") {
+                parse_text_line(line)
+            } else {
+                parse_reference_line(line)
+            };
+            record.ok_or(format!("Could not parse line {}: {}", number + 1, line))
+        })
+        .collect()
+}
+
+fn load_trace(path: &str) -> Result<Vec<Record>, String> {
+    let data = fs::read(path).map_err(|error| format!("Could not read {}: {}", path, error))?;
+    let header = &data[..data.len().min(RECORD_SIZE)];
+    let is_text = header.iter().all(|byte| byte.is_ascii_graphic() || byte.is_ascii_whitespace());
+    if is_text {
+        parse_text(&String::from_utf8_lossy(&data))
+    } else {
+        if data.len() % RECORD_SIZE != 0 {
+            eprintln!("warning: {} has a truncated record at the end", path);
+        }
+        Ok(parse_binary(&data))
+    }
+}
+
+fn read_image(image: &[u8], offset: usize, size: usize) -> Option<u32> {
+    let bytes = image.get(offset..offset + size)?;
+    Some(bytes.iter().rev().fold(0, |value, byte| (value << 8) | *byte as u32))
+}
+
+fn opcode_of(record: &Record, options: &Options) -> Option<u32> {
+    if record.opcode.is_some() {
+        return record.opcode;
+    }
+    let address = record.address() as usize;
+    let size = if record.thumb() { 2 } else { 4 };
+    match address >> 24 {
+        0x0 => read_image(&options.bios, address, size),
+        0x8..=0xD => read_image(&options.rom, address & 0x1FF_FFFF, size),
+        _ => None,
+    }
+}
+
+fn describe(record: &Record, options: &Options) -> String {
+    let address = record.address();
+    match opcode_of(record, options) {
+        Some(opcode) if record.thumb() => {
+            format!("{:08X}:     {:04X}  {}", address, opcode, disassemble_thumb(opcode as u16, address))
+        }
+        Some(opcode) => format!("{:08X}: {:08X}  {}", address, opcode, disassemble_arm(opcode, address)),
+        None => format!("{:08X}: ????????", address),
+    }
+}
+
+fn spsr_defined(record: &Record) -> bool {
+    let mode = record.values[16] & 0x1F;
+    mode != USER_MODE && mode != SYSTEM_MODE
+}
+
+// Whether a register counts as different, skipping masked ones and SPSRs that can't be compared
+fn differs(ours: &Record, reference: &Record, register: usize, options: &Options) -> bool {
+    if options.mask[register] {
+        return false;
+    }
+    if register == 17 {
+        if !ours.has_spsr || !reference.has_spsr {
+            return false;
+        }
+        if options.ignore_undefined_spsr && !spsr_defined(reference) {
+            return false;
+        }
+    }
+    ours.values[register] != reference.values[register]
+}
+
+fn first_mismatch(ours: &Record, reference: &Record, options: &Options) -> Option<usize> {
+    (0..18).find(|&register| differs(ours, reference, register, options))
+}
+
+fn print_context(ours: &[Record], reference: &[Record], index: usize, options: &Options) {
+    let start = index.saturating_sub(options.context);
+    let end = (index + options.context + 1).min(ours.len()).min(reference.len());
+    for i in start..end {
+        let marker = if i == index { ">" } else { " " };
+        println!("{} #{:<8} ours: {}", marker, i, describe(&ours[i], options));
+        println!("{}           ref:  {}", marker, describe(&reference[i], options));
+        let differences: Vec<String> = (0..18)
+            .filter(|&r| differs(&ours[i], &reference[i], r, options))
+            .map(|r| format!("{}={:08X}/{:08X}", REGISTER_NAMES[r], ours[i].values[r], reference[i].values[r]))
+            .collect();
+        if !differences.is_empty() {
+            println!("             differs: {}", differences.join(" "));
+        }
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: trace_diff <ours> <reference> [--mask r0,...,cpsr,spsr] [--ignore-undefined-spsr] [--context N] [--rom path] [--bios path]");
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut paths = Vec::new();
+    let mut options = Options {
+        mask: [false; 18],
+        ignore_undefined_spsr: false,
+        context: 5,
+        rom: Vec::new(),
+        bios: Vec::new()
+    };
+
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--mask" => {
+                for name in value().split(',') {
+                    let name = match name.trim() {
+                        "sp" => "r13",
+                        "lr" => "r14",
+                        "pc" => "r15",
+                        x => x,
+                    };
+                    match REGISTER_NAMES.iter().position(|register| *register == name) {
+                        Some(index) => options.mask[index] = true,
+                        None => {
+                            eprintln!("Unknown register: {}", name);
+                            usage();
+                        }
+                    }
+                }
+            }
+            "--ignore-undefined-spsr" => options.ignore_undefined_spsr = true,
+            "--context" => options.context = value().parse().unwrap_or_else(|_| usage()),
+            "--rom" => options.rom = fs::read(value()).expect("Could not read the ROM"),
+            "--bios" => options.bios = fs::read(value()).expect("Could not read the BIOS"),
+            _ => paths.push(arg.clone()),
+        }
+    }
+    if paths.len() != 2 {
+        usage();
+    }
+
+    let (ours, reference) = match (load_trace(&paths[0]), load_trace(&paths[1])) {
+        (Ok(ours), Ok(reference)) => (ours, reference),
+        (Err(error), _) | (_, Err(error)) => {
+            eprintln!("{}", error);
+            process::exit(2);
+        }
+    };
+
+    for (index, (our_record, reference_record)) in ours.iter().zip(reference.iter()).enumerate() {
+        if let Some(register) = first_mismatch(our_record, reference_record, &options) {
+            println!(
+                "First divergence at instruction #{}: {} is {:08X}, reference has {:08X}\n",
+                index, REGISTER_NAMES[register], our_record.values[register], reference_record.values[register]
+            );
+            print_context(&ours, &reference, index, &options);
+            process::exit(1);
+        }
+    }
+
+    if ours.len() != reference.len() {
+        println!(
+            "Traces match for {} instructions, but ours has {} and the reference has {}",
+            ours.len().min(reference.len()), ours.len(), reference.len()
+        );
+        process::exit(1);
+    }
+    println!("Traces match ({} instructions)", ours.len());
+}
@@ -0,0 +1,32 @@
+use crate::memory::{Access, CodePage};
+
+/// The CPU's view of the system, every access is timed according to its cycle type.
+pub trait Bus {
+    fn get_byte(&mut self, address: u32, access: Access) -> u8;
+    fn get_halfword(&mut self, address: u32, access: Access) -> u16;
+    fn get_word(&mut self, address: u32, access: Access) -> u32;
+    fn store_byte(&mut self, address: u32, value: u8, access: Access);
+    fn store_halfword(&mut self, address: u32, value: u16, access: Access);
+    fn store_word(&mut self, address: u32, value: u32, access: Access);
+
+    /// Opcode fetches are timed like reads but never trip read watchpoints.
+    fn fetch_halfword(&mut self, address: u32, access: Access) -> u16;
+    fn fetch_word(&mut self, address: u32, access: Access) -> u32;
+    /// A fetch of an opcode the CPU already has decoded, taking `cycles` when they are known ahead
+    /// of time. Returns false without fetching when cached code changed since the last invalidation.
+    fn fetch_cached(&mut self, address: u32, thumb: bool, access: Access, cycles: Option<usize>) -> bool;
+
+    /// Internal CPU cycles.
+    fn add_clock_cycles(&mut self, cycles: usize);
+
+    /// Reads without side effects, `None` if nothing is mapped there.
+    fn peek(&self, address: u32, size: usize) -> Option<u32>;
+
+    /// The page decoded code at `address` belongs to, `None` where code is never cached.
+    fn code_page(&self, address: u32) -> Option<CodePage>;
+    /// Non-sequential and sequential cycles of an opcode fetch, `None` when they vary between fetches.
+    fn code_timing(&self, address: u32, thumb: bool) -> Option<(usize, usize)>;
+    fn mark_code_page(&mut self, page: usize);
+    /// Pages whose cached code was overwritten, `None` when the whole cache has to go.
+    fn take_code_invalidations(&mut self) -> Option<Vec<usize>>;
+}
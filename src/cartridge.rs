@@ -0,0 +1,199 @@
+use crate::error::GbaError;
+use crate::memory::MAX_MULTIBOOT_SIZE;
+use crate::utils::crc32;
+
+const HEADER_SIZE: usize = 0xC0;
+// Multiboot images have a second entry point here, the one the BIOS jumps to after the transfer
+const MULTIBOOT_ENTRY: usize = 0xC0;
+// How far past the multiboot entry to look for code that points into EWRAM
+const MULTIBOOT_SCAN_INSTRUCTIONS: usize = 80;
+
+// Compressed bitmap the BIOS checks before booting a cartridge
+const NINTENDO_LOGO: [u8; 156] = [
+    0x24, 0xFF, 0xAE, 0x51, 0x69, 0x9A, 0xA2, 0x21, 0x3D, 0x84, 0x82, 0x0A, 0x84, 0xE4, 0x09, 0xAD,
+    0x11, 0x24, 0x8B, 0x98, 0xC0, 0x81, 0x7F, 0x21, 0xA3, 0x52, 0xBE, 0x19, 0x93, 0x09, 0xCE, 0x20,
+    0x10, 0x46, 0x4A, 0x4A, 0xF8, 0x27, 0x31, 0xEC, 0x58, 0xC7, 0xE8, 0x33, 0x82, 0xE3, 0xCE, 0xBF,
+    0x85, 0xF4, 0xDF, 0x94, 0xCE, 0x4B, 0x09, 0xC1, 0x94, 0x56, 0x8A, 0xC0, 0x13, 0x72, 0xA7, 0xFC,
+    0x9F, 0x84, 0x4D, 0x73, 0xA3, 0xCA, 0x9A, 0x61, 0x58, 0x97, 0xA3, 0x27, 0xFC, 0x03, 0x98, 0x76,
+    0x23, 0x1D, 0xC7, 0x61, 0x03, 0x04, 0xAE, 0x56, 0xBF, 0x38, 0x84, 0x00, 0x40, 0xA7, 0x0E, 0xFD,
+    0xFF, 0x52, 0xFE, 0x03, 0x6F, 0x95, 0x30, 0xF1, 0x97, 0xFB, 0xC0, 0x85, 0x60, 0xD6, 0x80, 0x25,
+    0xA9, 0x63, 0xBE, 0x03, 0x01, 0x4E, 0x38, 0xE2, 0xF9, 0xA2, 0x34, 0xFF, 0xBB, 0x3E, 0x03, 0x44,
+    0x78, 0x00, 0x90, 0xCB, 0x88, 0x11, 0x3A, 0x94, 0x65, 0xC0, 0x7C, 0x63, 0x87, 0xF0, 0x3C, 0xAF,
+    0xD6, 0x25, 0xE4, 0x8B, 0x38, 0x0A, 0xAC, 0x72, 0x21, 0xD4, 0xF8, 0x07
+];
+
+/// The kind of backup memory on the cartridge. Only SRAM is emulated so far, the others are
+/// reported so frontends can warn about saves that won't work.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SaveType {
+    None,
+    Sram,
+    Eeprom,
+    Flash64K,
+    Flash128K
+}
+
+impl SaveType {
+    // The save library Nintendo's SDK links in leaves its version string in the ROM. The scan
+    // only looks closer at bytes that can start one of the library names.
+    fn detect(rom: &[u8]) -> SaveType {
+        type Versions = &'static [(&'static [u8], SaveType)];
+        const LIBRARIES: [(&[u8], Versions); 3] = [
+            (b"EEPROM_", &[(b"V", SaveType::Eeprom)]),
+            (b"SRAM_", &[(b"V", SaveType::Sram), (b"F_V", SaveType::Sram)]),
+            (b"FLASH", &[(b"_V", SaveType::Flash64K), (b"512_V", SaveType::Flash64K), (b"1M_V", SaveType::Flash128K)])
+        ];
+        rom.iter()
+            .enumerate()
+            .filter(|&(_, byte)| matches!(byte, b'E' | b'S' | b'F'))
+            .find_map(|(index, _)| {
+                let (name, versions) = LIBRARIES.iter().find(|(name, _)| rom[index..].starts_with(name))?;
+                versions.iter()
+                    .find(|(version, _)| rom[index + name.len()..].starts_with(version))
+                    .map(|&(_, save_type)| save_type)
+            })
+            .unwrap_or(SaveType::None)
+    }
+}
+
+/// Extra hardware some cartridges carry next to the ROM.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Hardware {
+    pub rtc: bool,
+    pub rumble: bool,
+    pub tilt: bool,
+    pub gyro: bool,
+    pub light_sensor: bool
+}
+
+struct KnownGame {
+    game_code: &'static str,
+    save_type: SaveType,
+    hardware: Hardware
+}
+
+const NO_HARDWARE: Hardware = Hardware { rtc: false, rumble: false, tilt: false, gyro: false, light_sensor: false };
+
+// Games whose save type can't be detected from the ROM, or that carry extra hardware
+const GAME_DATABASE: [KnownGame; 11] = [
+    // Pokemon Ruby, Sapphire and Emerald
+    KnownGame { game_code: "AXVE", save_type: SaveType::Flash128K, hardware: Hardware { rtc: true, ..NO_HARDWARE } },
+    KnownGame { game_code: "AXPE", save_type: SaveType::Flash128K, hardware: Hardware { rtc: true, ..NO_HARDWARE } },
+    KnownGame { game_code: "BPEE", save_type: SaveType::Flash128K, hardware: Hardware { rtc: true, ..NO_HARDWARE } },
+    // Pokemon FireRed and LeafGreen
+    KnownGame { game_code: "BPRE", save_type: SaveType::Flash128K, hardware: NO_HARDWARE },
+    KnownGame { game_code: "BPGE", save_type: SaveType::Flash128K, hardware: NO_HARDWARE },
+    // Boktai
+    KnownGame { game_code: "U3IE", save_type: SaveType::Eeprom, hardware: Hardware { rtc: true, light_sensor: true, ..NO_HARDWARE } },
+    // Yoshi Topsy-Turvy
+    KnownGame { game_code: "KYGE", save_type: SaveType::Eeprom, hardware: Hardware { tilt: true, ..NO_HARDWARE } },
+    // WarioWare: Twisted!
+    KnownGame { game_code: "RZWE", save_type: SaveType::Sram, hardware: Hardware { rumble: true, gyro: true, ..NO_HARDWARE } },
+    // Drill Dozer
+    KnownGame { game_code: "V49E", save_type: SaveType::Sram, hardware: Hardware { rumble: true, ..NO_HARDWARE } },
+    // Dragon Ball Z: The Legacy of Goku II
+    KnownGame { game_code: "ALFE", save_type: SaveType::Eeprom, hardware: NO_HARDWARE },
+    // Top Gun: Combat Zones, has a save library string but no backup chip
+    KnownGame { game_code: "A2YE", save_type: SaveType::None, hardware: NO_HARDWARE }
+];
+
+/// What the cartridge header says about a ROM, with the save type and extra hardware filled in
+/// from the game database or, for unknown games, guessed from the ROM.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomInfo {
+    /// Where the branch at the start of the ROM jumps to, relative to the start of the ROM.
+    pub entry_offset: Option<u32>,
+    pub logo_valid: bool,
+    pub title: String,
+    pub game_code: String,
+    pub maker_code: String,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub checksum_valid: bool,
+    /// Only read by the frontend to warn about saves that aren't emulated yet.
+    pub save_type: SaveType,
+    /// Not emulated or read anywhere yet, it's only reported.
+    pub hardware: Hardware,
+    /// Set when the save type and hardware came from the game database.
+    pub known_game: bool,
+    /// CRC-32 of the whole image as loaded, before any cheat patches it. Identifies the game in
+    /// save states.
+    pub crc: u32
+}
+
+impl RomInfo {
+    /// Never fails, missing header bytes read as zero and `validate` reports what's wrong.
+    pub fn parse(rom: &[u8]) -> RomInfo {
+        let mut header = [0; HEADER_SIZE];
+        let length = rom.len().min(HEADER_SIZE);
+        header[..length].copy_from_slice(&rom[..length]);
+
+        let entry_branch = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        // An always executed B
+        let entry_offset = (entry_branch >> 24 == 0xEA)
+            .then(|| ((((entry_branch << 8) as i32) >> 6) as u32).wrapping_add(8));
+        let game_code = text(&header[0xAC..0xB0]);
+        let known = GAME_DATABASE.iter().find(|game| game.game_code == game_code);
+        RomInfo {
+            entry_offset,
+            logo_valid: header[0x04..0xA0] == NINTENDO_LOGO,
+            title: text(&header[0xA0..0xAC]),
+            game_code,
+            maker_code: text(&header[0xB0..0xB2]),
+            version: header[0xBC],
+            header_checksum: header[0xBD],
+            checksum_valid: header[0xBD] == header_checksum(&header),
+            save_type: known.map_or_else(|| SaveType::detect(rom), |game| game.save_type),
+            hardware: known.map_or(NO_HARDWARE, |game| game.hardware),
+            known_game: known.is_some(),
+            crc: crc32(rom)
+        }
+    }
+
+    /// Checks the header the way the BIOS does before booting, homebrew often fails this and runs
+    /// fine anyway.
+    pub fn validate(&self) -> Result<(), GbaError> {
+        if !self.logo_valid {
+            return Err(GbaError::InvalidRomHeader("The Nintendo logo doesn't match"));
+        }
+        if !self.checksum_valid {
+            return Err(GbaError::InvalidRomHeader("The header checksum doesn't match"));
+        }
+        if self.entry_offset.is_none() {
+            return Err(GbaError::InvalidRomHeader("The ROM doesn't start with a branch"));
+        }
+        Ok(())
+    }
+}
+
+// Header strings are uppercase ASCII padded with zeros
+fn text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).trim_end_matches('\0').to_string()
+}
+
+// Complement of the sum of 0xA0 to 0xBC, minus 0x19
+fn header_checksum(header: &[u8]) -> u8 {
+    let sum = header[0xA0..0xBD].iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    0u8.wrapping_sub(sum).wrapping_sub(0x19)
+}
+
+/// Guesses whether `image` is linked to run from EWRAM rather than from the cartridge: it has to fit
+/// in EWRAM, and the start up code after the multiboot entry loads an EWRAM address from a literal
+/// pool. Small cartridge ROMs can look the same, so only use this when the file name doesn't tell.
+pub fn looks_like_multiboot(image: &[u8]) -> bool {
+    if image.len() <= HEADER_SIZE || image.len() > MAX_MULTIBOOT_SIZE {
+        return false;
+    }
+    let word = |offset: usize| image.get(offset..offset + 4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()));
+    (0..MULTIBOOT_SCAN_INSTRUCTIONS)
+        .map(|index| MULTIBOOT_ENTRY + index * 4)
+        .filter_map(|offset| Some((offset, word(offset)?)))
+        // LDR Rd, [PC, #offset]
+        .filter(|&(_, opcode)| opcode & 0x0F7F_0000 == 0x051F_0000)
+        .filter_map(|(offset, opcode)| {
+            let displacement = (opcode & 0xFFF) as usize;
+            let literal = if opcode & (1 << 23) != 0 { offset + 8 + displacement } else { (offset + 8).checked_sub(displacement)? };
+            word(literal)
+        })
+        .any(|address| address >> 24 == 0x02)
+}
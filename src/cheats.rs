@@ -0,0 +1,598 @@
+use crate::bus::Bus;
+use crate::error::GbaError;
+use crate::io::KEYINPUT;
+use crate::memory::Memory;
+
+const ROM_START: u32 = 0x0800_0000;
+// Hook addresses are offsets into the 32 MiB of cartridge space
+const ROM_MASK: u32 = 0x01FF_FFFF;
+const IO_START: u32 = 0x0400_0000;
+const TEA_DELTA: u32 = 0x9E37_79B9;
+const GAMESHARK_SEEDS: [u32; 4] = [0x09F4_FBBD, 0x9681_884A, 0x3520_27E9, 0xF3DE_E5A7];
+const ACTION_REPLAY3_SEEDS: [u32; 4] = [0x7AA9_648F, 0x7FAE_6994, 0xC0EF_AAD5, 0x4271_2C57];
+// A decrypted code starting with this switches to seeds derived from its second word through the
+// devices' seed tables. Those aren't supported, so cheats using it are rejected
+const RESEED_MARKER: u32 = 0xDEAD_FACE;
+// The second half of a master code, identifying the game
+const GAME_ID_MARKER: u32 = 0x001D_C0DE;
+
+/// How a cheat's codes are written.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CheatFormat {
+    /// `AAAAAAAA:VV`, the number of value digits gives the write size: 2, 4 or 8.
+    Raw,
+    /// GameShark and Action Replay v1 and v2, encrypted `XXXXXXXX YYYYYYYY`.
+    GameShark,
+    /// Action Replay v3, encrypted `XXXXXXXX YYYYYYYY`.
+    ActionReplay3,
+    /// Unencrypted CodeBreaker codes, `XXXXXXXX YYYY`. Encrypted CodeBreaker cheats aren't
+    /// supported and are rejected.
+    CodeBreaker
+}
+
+impl CheatFormat {
+    /// The name used in cheat files.
+    pub fn name(self) -> &'static str {
+        match self {
+            CheatFormat::Raw => "raw",
+            CheatFormat::GameShark => "gameshark",
+            CheatFormat::ActionReplay3 => "actionreplay3",
+            CheatFormat::CodeBreaker => "codebreaker",
+        }
+    }
+
+    /// Also takes `actionreplay` for v1 and v2 codes, which are GameShark codes.
+    pub fn from_name(name: &str) -> Option<CheatFormat> {
+        match name.to_ascii_lowercase().as_str() {
+            "raw" => Some(CheatFormat::Raw),
+            "gameshark" | "actionreplay" => Some(CheatFormat::GameShark),
+            "actionreplay3" => Some(CheatFormat::ActionReplay3),
+            "codebreaker" => Some(CheatFormat::CodeBreaker),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessUnsigned,
+    GreaterUnsigned,
+    // Any of the bits set
+    And,
+    // All of the bits clear, for the active low KEYINPUT
+    Clear
+}
+
+impl Comparison {
+    fn holds(self, current: u32, value: u32, size: usize) -> bool {
+        let signed = |x: u32| ((x << (32 - size * 8)) as i32) >> (32 - size * 8);
+        match self {
+            Comparison::Equal => current == value,
+            Comparison::NotEqual => current != value,
+            Comparison::Less => signed(current) < signed(value),
+            Comparison::Greater => signed(current) > signed(value),
+            Comparison::LessUnsigned => current < value,
+            Comparison::GreaterUnsigned => current > value,
+            Comparison::And => current & value != 0,
+            Comparison::Clear => current & value == 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Operator {
+    Add,
+    Or,
+    And
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Operation {
+    // `count` values in a row, for fills
+    Write { address: u32, size: usize, value: u32, count: u32 },
+    // Writes `offset` bytes past the address held in the word at `address`
+    WritePointer { address: u32, size: usize, value: u32, offset: u32 },
+    Modify { address: u32, size: usize, value: u32, operator: Operator },
+    // Skips the next `count` operations unless the value at `address` compares true
+    If { address: u32, size: usize, comparison: Comparison, value: u32, count: usize },
+    // Applied when the cheat is enabled and undone when it's disabled, see `Cheats::apply`
+    RomPatch { address: u32, size: usize, value: u32 }
+}
+
+/// A named list of codes that are applied together.
+#[derive(Clone, Debug)]
+pub struct Cheat {
+    pub name: String,
+    pub enabled: bool,
+    format: CheatFormat,
+    codes: Vec<String>,
+    operations: Vec<Operation>,
+    hook: Option<u32>
+}
+
+impl Cheat {
+    /// Decrypts and decodes `codes`, failing on the first one that is malformed or of a type that
+    /// isn't supported. The cheat starts enabled.
+    pub fn new(name: impl Into<String>, format: CheatFormat, codes: &[impl AsRef<str>]) -> Result<Cheat, GbaError> {
+        let codes: Vec<String> = codes.iter()
+            .map(|code| code.as_ref().split_whitespace().collect::<Vec<_>>().join(" ").to_ascii_uppercase())
+            .filter(|code| !code.is_empty())
+            .collect();
+        let mut decoded = Decoded { operations: Vec::new(), hook: None };
+        match format {
+            CheatFormat::Raw => {
+                for code in &codes {
+                    decoded.operations.push(decode_raw(code)?);
+                }
+            }
+            CheatFormat::GameShark => {
+                for code in &codes {
+                    let (op1, op2) = decrypt(words(code, 8)?, &GAMESHARK_SEEDS);
+                    decode_gameshark(&mut decoded, code, op1, op2)?;
+                }
+            }
+            CheatFormat::ActionReplay3 => decode_action_replay3(&mut decoded, &codes)?,
+            CheatFormat::CodeBreaker => {
+                for code in &codes {
+                    let (op1, op2) = words(code, 4)?;
+                    decode_codebreaker(&mut decoded, code, op1, op2)?;
+                }
+            }
+        }
+        Ok(Cheat { name: name.into(), enabled: true, format, codes, operations: decoded.operations, hook: decoded.hook })
+    }
+
+    pub fn format(&self) -> CheatFormat {
+        self.format
+    }
+
+    /// The codes as entered, with the whitespace tidied up.
+    pub fn codes(&self) -> &[String] {
+        &self.codes
+    }
+
+    /// Where the master code hooks the cheat device into the game. Codes are applied at VBlank
+    /// instead, so this is informational.
+    pub fn hook(&self) -> Option<u32> {
+        self.hook
+    }
+
+    fn run(&self, memory: &mut Memory) {
+        let mut index = 0;
+        while let Some(&operation) = self.operations.get(index) {
+            index += 1;
+            match operation {
+                Operation::Write { address, size, value, count } => {
+                    for step in 0..count {
+                        poke(memory, address.wrapping_add(step.wrapping_mul(size as u32)), size, value);
+                    }
+                }
+                Operation::WritePointer { address, size, value, offset } => {
+                    if let Some(pointer) = memory.peek(address, 4) {
+                        poke(memory, pointer.wrapping_add(offset), size, value);
+                    }
+                }
+                Operation::Modify { address, size, value, operator } => {
+                    if let Some(current) = memory.peek(address, size) {
+                        let result = match operator {
+                            Operator::Add => current.wrapping_add(value),
+                            Operator::Or => current | value,
+                            Operator::And => current & value,
+                        };
+                        poke(memory, address, size, result);
+                    }
+                }
+                Operation::If { address, size, comparison, value, count } => {
+                    let holds = memory.peek(address, size).is_some_and(|current| comparison.holds(current, value, size));
+                    if !holds {
+                        index += count;
+                    }
+                }
+                Operation::RomPatch { .. } => (),
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct AppliedPatch {
+    address: u32,
+    size: usize,
+    value: u32,
+    // `None` past the end of the ROM, where nothing was written
+    original: Option<u32>
+}
+
+/// The cheat list. Enabled cheats are applied every frame when VBlank starts.
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+    // ROM patches currently written into the ROM, in the order they were applied
+    applied_patches: Vec<AppliedPatch>
+}
+
+impl Cheats {
+    pub(crate) fn new() -> Cheats {
+        Cheats { cheats: Vec::new(), applied_patches: Vec::new() }
+    }
+
+    pub fn list(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Cheat> {
+        self.cheats.get_mut(index)
+    }
+
+    pub fn add(&mut self, cheat: Cheat) {
+        self.cheats.push(cheat);
+    }
+
+    /// Adds `cheat` unless one with the same format and codes is already listed, whatever its name.
+    /// Returns whether it was added.
+    pub fn add_if_new(&mut self, cheat: Cheat) -> bool {
+        let listed = self.cheats.iter().any(|other| other.format == cheat.format && other.codes == cheat.codes);
+        if !listed {
+            self.cheats.push(cheat);
+        }
+        !listed
+    }
+
+    pub fn remove(&mut self, index: usize) -> Cheat {
+        self.cheats.remove(index)
+    }
+
+    pub fn clear(&mut self) {
+        self.cheats.clear();
+    }
+
+    /// Replaces the list with the one in a cheat file. Each cheat starts with its name in brackets,
+    /// followed by `format = <name>` (raw if left out), an optional `enabled = true|false` and its
+    /// codes, one per line. Blank lines and lines starting with `#` are skipped.
+    pub fn load_text(&mut self, text: &str) -> Result<(), GbaError> {
+        struct Entry {
+            name: String,
+            format: CheatFormat,
+            enabled: bool,
+            codes: Vec<String>
+        }
+        let finish = |entry: Entry| {
+            Cheat::new(entry.name, entry.format, &entry.codes).map(|cheat| Cheat { enabled: entry.enabled, ..cheat })
+        };
+
+        let mut cheats = Vec::new();
+        let mut current: Option<Entry> = None;
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            let error = |reason| GbaError::InvalidCheatList { line: index + 1, reason };
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+                if let Some(entry) = current.take() {
+                    cheats.push(finish(entry)?);
+                }
+                current = Some(Entry { name: name.trim().to_string(), format: CheatFormat::Raw, enabled: true, codes: Vec::new() });
+                continue;
+            }
+            let entry = current.as_mut().ok_or_else(|| error("Expected a [name] line before the codes"))?;
+            match line.split_once('=') {
+                Some((key, value)) => match (key.trim(), value.trim()) {
+                    ("format", name) => entry.format = CheatFormat::from_name(name).ok_or_else(|| error("Unknown code format"))?,
+                    ("enabled", "true") => entry.enabled = true,
+                    ("enabled", "false") => entry.enabled = false,
+                    ("enabled", _) => return Err(error("enabled has to be true or false")),
+                    _ => return Err(error("Unknown setting")),
+                },
+                None => entry.codes.push(line.to_string()),
+            }
+        }
+        if let Some(entry) = current {
+            cheats.push(finish(entry)?);
+        }
+        self.cheats = cheats;
+        Ok(())
+    }
+
+    /// The list in the format `load_text` reads.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for cheat in &self.cheats {
+            if !text.is_empty() {
+                text.push('\n');
+            }
+            text.push_str(&format!("[{}]\nformat = {}\nenabled = {}\n", cheat.name, cheat.format.name(), cheat.enabled));
+            for code in &cheat.codes {
+                text.push_str(code);
+                text.push('\n');
+            }
+        }
+        text
+    }
+
+    /// Brings the ROM patches in line with the enabled cheats, then runs the enabled cheats' other
+    /// codes. The ROM is only touched when the patches change.
+    pub(crate) fn apply(&mut self, memory: &mut Memory) {
+        let wanted: Vec<(u32, usize, u32)> = self.cheats.iter()
+            .filter(|cheat| cheat.enabled)
+            .flat_map(|cheat| cheat.operations.iter())
+            .filter_map(|&operation| match operation {
+                Operation::RomPatch { address, size, value } => Some((address, size, value)),
+                _ => None,
+            })
+            .collect();
+        let applied = self.applied_patches.iter().map(|patch| (patch.address, patch.size, patch.value));
+        if !applied.eq(wanted.iter().copied()) {
+            // In reverse, so overlapping patches put back the bytes from before the first one
+            for patch in self.applied_patches.drain(..).rev() {
+                if let Some(original) = patch.original {
+                    poke(memory, patch.address, patch.size, original);
+                }
+            }
+            for (address, size, value) in wanted {
+                let original = memory.peek(address, size);
+                if original.is_some() {
+                    poke(memory, address, size, value);
+                }
+                self.applied_patches.push(AppliedPatch { address, size, value, original });
+            }
+        }
+        for cheat in self.cheats.iter().filter(|cheat| cheat.enabled) {
+            cheat.run(memory);
+        }
+    }
+
+    /// Called when a new ROM is loaded, the patches went with the old one.
+    pub(crate) fn forget_rom_patches(&mut self) {
+        self.applied_patches.clear();
+    }
+}
+
+struct Decoded {
+    operations: Vec<Operation>,
+    hook: Option<u32>
+}
+
+fn invalid(code: &str, reason: &'static str) -> GbaError {
+    GbaError::InvalidCheat { code: code.to_string(), reason }
+}
+
+fn hex(digits: &str) -> Option<u32> {
+    if digits.is_empty() || !digits.chars().all(|digit| digit.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(digits, 16).ok()
+}
+
+// Two hex words separated by a space, 8 digits and then `second_digits`
+fn words(code: &str, second_digits: usize) -> Result<(u32, u32), GbaError> {
+    let reason = if second_digits == 8 { "Expected two groups of 8 hex digits" } else { "Expected 8 hex digits and 4 hex digits" };
+    match code.split_once(' ') {
+        Some((first, second)) if first.len() == 8 && second.len() == second_digits => {
+            hex(first).zip(hex(second)).ok_or_else(|| invalid(code, reason))
+        }
+        _ => Err(invalid(code, reason)),
+    }
+}
+
+// TEA with 32 rounds
+fn decrypt((mut op1, mut op2): (u32, u32), seeds: &[u32; 4]) -> (u32, u32) {
+    let mut sum = TEA_DELTA.wrapping_mul(32);
+    for _ in 0..32 {
+        op2 = op2.wrapping_sub(
+            (op1 << 4).wrapping_add(seeds[2]) ^ op1.wrapping_add(sum) ^ (op1 >> 5).wrapping_add(seeds[3])
+        );
+        op1 = op1.wrapping_sub(
+            (op2 << 4).wrapping_add(seeds[0]) ^ op2.wrapping_add(sum) ^ (op2 >> 5).wrapping_add(seeds[1])
+        );
+        sum = sum.wrapping_sub(TEA_DELTA);
+    }
+    (op1, op2)
+}
+
+fn is_rom(address: u32) -> bool {
+    (0x8..=0xD).contains(&(address >> 24))
+}
+
+// Writes to the cartridge become ROM patches
+fn write(address: u32, size: usize, value: u32) -> Operation {
+    if is_rom(address) {
+        Operation::RomPatch { address, size, value }
+    } else {
+        Operation::Write { address, size, value, count: 1 }
+    }
+}
+
+fn poke(memory: &mut Memory, address: u32, size: usize, value: u32) {
+    for byte in 0..size {
+        memory.poke_byte(address.wrapping_add(byte as u32), (value >> (byte * 8)) as u8);
+    }
+}
+
+fn decode_raw(code: &str) -> Result<Operation, GbaError> {
+    let (address, value) = code.split_once(':').ok_or_else(|| invalid(code, "Raw codes are written address:value"))?;
+    let size = match value.len() {
+        2 => 1,
+        4 => 2,
+        8 => 4,
+        _ => return Err(invalid(code, "The value has to be 2, 4 or 8 hex digits")),
+    };
+    let address = hex(address).ok_or_else(|| invalid(code, "The address isn't hex"))?;
+    let value = hex(value).ok_or_else(|| invalid(code, "The value isn't hex"))?;
+    Ok(write(address, size, value))
+}
+
+// The code type is the top nibble of the first word, the rest of it is usually the address
+fn decode_gameshark(decoded: &mut Decoded, code: &str, op1: u32, op2: u32) -> Result<(), GbaError> {
+    let address = op1 & 0x0FFF_FFFF;
+    if op1 == RESEED_MARKER {
+        return Err(invalid(code, "Codes that change the encryption seeds aren't supported"));
+    }
+    if op2 == GAME_ID_MARKER {
+        return Ok(());
+    }
+    let operation = match op1 >> 28 {
+        0x0 => write(address, 1, op2 & 0xFF),
+        0x1 => write(address, 2, op2 & 0xFFFF),
+        0x2 => write(address, 4, op2),
+        // The address counts halfwords
+        0x6 => Operation::RomPatch { address: ROM_START | ((op1 & 0xFF_FFFF) << 1), size: 2, value: op2 & 0xFFFF },
+        0xD => Operation::If { address, size: 2, comparison: Comparison::Equal, value: op2 & 0xFFFF, count: 1 },
+        // Covers the next 0-255 codes, with the address in the second word
+        0xE => Operation::If {
+            address: op2 & 0x0FFF_FFFF,
+            size: 2,
+            comparison: Comparison::Equal,
+            value: op1 & 0xFFFF,
+            count: ((op1 >> 16) & 0xFF) as usize
+        },
+        // Master code
+        0xF => {
+            decoded.hook = Some(ROM_START | (op1 & ROM_MASK));
+            return Ok(());
+        }
+        _ => return Err(invalid(code, "Unsupported GameShark code type")),
+    };
+    decoded.operations.push(operation);
+    Ok(())
+}
+
+// Conditions either cover the next one or two codes, or a block up to an end if code
+fn decode_action_replay3(decoded: &mut Decoded, codes: &[String]) -> Result<(), GbaError> {
+    let mut open_blocks = Vec::new();
+    let mut codes = codes.iter();
+    while let Some(code) = codes.next() {
+        let (op1, op2) = decrypt(words(code, 8)?, &ACTION_REPLAY3_SEEDS);
+        if op1 == RESEED_MARKER {
+            return Err(invalid(code, "Codes that change the encryption seeds aren't supported"));
+        }
+        if op2 == GAME_ID_MARKER {
+            continue;
+        }
+        // Special codes have a zero first word and their type in the top byte of the second
+        if op1 == 0 {
+            match op2 >> 24 {
+                // End of the list
+                0x00 => (),
+                // The value is in the first word of the next code
+                0x18 | 0x1A | 0x1C | 0x1E => {
+                    let next = codes.next().ok_or_else(|| invalid(code, "The ROM patch is missing its value"))?;
+                    let (value, _) = decrypt(words(next, 8)?, &ACTION_REPLAY3_SEEDS);
+                    let address = ROM_START | ((op2 & 0xFF_FFFF) << 1);
+                    decoded.operations.push(Operation::RomPatch { address, size: 2, value: value & 0xFFFF });
+                }
+                // End if
+                0x40 => {
+                    let start = open_blocks.pop().ok_or_else(|| invalid(code, "End if without a condition"))?;
+                    let end = decoded.operations.len();
+                    if let Operation::If { count, .. } = &mut decoded.operations[start] {
+                        *count = end - start - 1;
+                    }
+                }
+                _ => return Err(invalid(code, "Unsupported Action Replay special code")),
+            }
+            continue;
+        }
+        // Master code
+        if op1 >> 24 == 0xC4 {
+            decoded.hook = Some(ROM_START | (op1 & 0xFF_FFFF));
+            continue;
+        }
+
+        // The region nibble sits below the code type
+        let address = ((op1 & 0xF0_0000) << 4) | (op1 & 0xF_FFFF);
+        let size = match op1 & 0x0600_0000 {
+            0x0000_0000 => 1,
+            0x0200_0000 => 2,
+            0x0400_0000 => 4,
+            _ => return Err(invalid(code, "Unsupported Action Replay code type")),
+        };
+        let mask = u32::MAX >> (32 - size * 8);
+        let comparison = match (op1 >> 27) & 7 {
+            0 => None,
+            1 => Some(Comparison::Equal),
+            2 => Some(Comparison::NotEqual),
+            3 => Some(Comparison::Less),
+            4 => Some(Comparison::Greater),
+            5 => Some(Comparison::LessUnsigned),
+            6 => Some(Comparison::GreaterUnsigned),
+            _ => Some(Comparison::And),
+        };
+        let operation = match (comparison, op1 >> 30) {
+            (Some(comparison), action) => {
+                let count = match action {
+                    0 => 1,
+                    1 => 2,
+                    2 => {
+                        open_blocks.push(decoded.operations.len());
+                        0
+                    }
+                    _ => return Err(invalid(code, "Conditions that disable the cheat aren't supported")),
+                };
+                Operation::If { address, size, comparison, value: op2 & mask, count }
+            }
+            // Byte and halfword writes repeat, with the count in the rest of the second word
+            (None, 0) if is_rom(address) => write(address, size, op2 & mask),
+            (None, 0) => {
+                let count = if size == 4 { 1 } else { (op2 >> (size * 8)) + 1 };
+                Operation::Write { address, size, value: op2 & mask, count }
+            }
+            (None, 1) => {
+                let offset = if size == 4 { 0 } else { op2 >> (size * 8) };
+                Operation::WritePointer { address, size, value: op2 & mask, offset }
+            }
+            (None, 2) => Operation::Modify { address, size, value: op2 & mask, operator: Operator::Add },
+            _ => return Err(invalid(code, "Unsupported Action Replay code type")),
+        };
+        decoded.operations.push(operation);
+    }
+    // Blocks left open run to the end of the cheat
+    let end = decoded.operations.len();
+    for start in open_blocks {
+        if let Operation::If { count, .. } = &mut decoded.operations[start] {
+            *count = end - start - 1;
+        }
+    }
+    Ok(())
+}
+
+fn decode_codebreaker(decoded: &mut Decoded, code: &str, op1: u32, op2: u32) -> Result<(), GbaError> {
+    let address = op1 & 0x0FFF_FFFF;
+    let condition = |comparison| Operation::If { address, size: 2, comparison, value: op2, count: 1 };
+    let modify = |operator| Operation::Modify { address, size: 2, value: op2, operator };
+    let operation = match op1 >> 28 {
+        // Game ID, the first half of the master code
+        0x0 => return Ok(()),
+        // Master code
+        0x1 => {
+            decoded.hook = Some(ROM_START | (op1 & ROM_MASK));
+            return Ok(());
+        }
+        0x2 => modify(Operator::Or),
+        0x3 => write(address, 1, op2 & 0xFF),
+        0x6 => modify(Operator::And),
+        0x7 => condition(Comparison::Equal),
+        0x8 => write(address, 2, op2),
+        // Turns on encryption for the codes after it, which isn't supported
+        0x9 => return Err(invalid(code, "Encrypted CodeBreaker codes aren't supported")),
+        0xA => condition(Comparison::NotEqual),
+        0xB => condition(Comparison::GreaterUnsigned),
+        0xC => condition(Comparison::LessUnsigned),
+        // Runs the next code while the keys in the value are held
+        0xD if address == 0x20 => Operation::If {
+            address: IO_START | KEYINPUT,
+            size: 2,
+            comparison: Comparison::Clear,
+            value: op2,
+            count: 1
+        },
+        0xE => modify(Operator::Add),
+        0xF => condition(Comparison::And),
+        _ => return Err(invalid(code, "Unsupported CodeBreaker code type")),
+    };
+    decoded.operations.push(operation);
+    Ok(())
+}
@@ -1,5 +1,6 @@
 pub const VISIBLE_V: usize = 197120;
 pub const V_BLANK: usize = 83776;
+pub const CYCLES_PER_FRAME: usize = VISIBLE_V + V_BLANK;
 pub const VISIBLE_H: usize = 960;
 pub const H_BLANK: usize = 272;
 pub const ACTUAL_VISIBLE_H: usize = 1006;
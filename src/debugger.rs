@@ -0,0 +1,549 @@
+use std::io::{self, BufRead, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::arm7::disassembler::{disassemble_arm, disassemble_thumb};
+use crate::constants::CYCLES_PER_FRAME;
+use crate::gba::Gba;
+use crate::memory::MEMORY_REGIONS;
+use crate::monitor::{AccessKind, MemoryAccess, Watchpoint, WatchpointHit};
+
+const HELP: &str = "\
+Commands:
+  s, step [n]                 execute n instructions (default 1)
+  n, next                     step over a BL
+  finish                      run until the current function returns to LR
+  c, continue [n]             run until a breakpoint is hit, at most n instructions.
+                              A key press in the window or Ctrl-C stops it
+  b, break <addr> [if <cond>] add a breakpoint, e.g. `break 0x8000100 if r0 == 0x10`
+  delete [n]                  delete breakpoint n, or all of them
+  info                        list breakpoints
+  r, regs                     show the registers
+  set <reg> <value>           write a register (r0-r15, sp, lr, pc, cpsr)
+  x <addr> [len]              hex dump memory
+  poke <addr> <value> [b|h|w] write memory (default w)
+  d, disasm [addr] [count]    disassemble, around the current instruction by default
+  regions                     list the memory regions
+  watch <r|w|rw|x> <addr> [end]  pause when the range is read, written or executed
+  unwatch <n>                 delete watchpoint n
+  watches                     list watchpoints
+  log <region|all> <on|off>   record accesses to a memory region
+  log show [n]                print the last n logged accesses (default 20)
+  log clear                   empty the access log
+  log size <n>                keep at most n accesses in the log
+  q, quit                     exit the emulator
+An empty line repeats the last command.";
+
+#[derive(Clone, Copy)]
+enum Operand {
+    Register(usize),
+    Cpsr,
+    Memory(u32),
+    Value(u32)
+}
+
+#[derive(Clone, Copy)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual
+}
+
+#[derive(Clone, Copy)]
+struct Condition {
+    left: Operand,
+    comparison: Comparison,
+    right: Operand
+}
+
+struct Breakpoint {
+    address: u32,
+    condition: Option<(Condition, String)>
+}
+
+pub enum DebuggerAction {
+    Resume,
+    Quit
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    last_command: String,
+    interrupted: Arc<AtomicBool>
+}
+
+fn parse_register(name: &str) -> Option<usize> {
+    match name {
+        "sp" => Some(13),
+        "lr" => Some(14),
+        "pc" => Some(15),
+        _ => name.strip_prefix('r')?.parse().ok().filter(|register| *register < 16),
+    }
+}
+
+fn parse_number(value: &str) -> Option<u32> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn parse_operand(value: &str) -> Option<Operand> {
+    if value == "cpsr" {
+        Some(Operand::Cpsr)
+    } else if let Some(register) = parse_register(value) {
+        Some(Operand::Register(register))
+    } else if let Some(address) = value.strip_prefix('[').and_then(|value| value.strip_suffix(']')) {
+        parse_number(address).map(Operand::Memory)
+    } else {
+        parse_number(value).map(Operand::Value)
+    }
+}
+
+fn parse_condition(tokens: &[&str]) -> Option<Condition> {
+    if tokens.len() != 3 {
+        return None;
+    }
+    let comparison = match tokens[1] {
+        "==" => Comparison::Equal,
+        "!=" => Comparison::NotEqual,
+        "<" => Comparison::Less,
+        "<=" => Comparison::LessEqual,
+        ">" => Comparison::Greater,
+        ">=" => Comparison::GreaterEqual,
+        _ => return None,
+    };
+    Some(Condition { left: parse_operand(tokens[0])?, comparison, right: parse_operand(tokens[2])? })
+}
+
+fn mode_name(mode: u32) -> &'static str {
+    match mode {
+        0x10 => "usr",
+        0x11 => "fiq",
+        0x12 => "irq",
+        0x13 => "svc",
+        0x17 => "abt",
+        0x1B => "und",
+        0x1F => "sys",
+        _ => "???",
+    }
+}
+
+fn peek(gba: &Gba, address: u32, size: u32) -> Option<u32> {
+    let memory = gba.memory();
+    (0..size).rev().try_fold(0, |value, i| Some((value << 8) | memory.peek_byte(address.wrapping_add(i))? as u32))
+}
+
+fn operand_value(gba: &Gba, operand: Operand) -> u32 {
+    match operand {
+        Operand::Register(register) => gba.cpu().registers()[register],
+        Operand::Cpsr => gba.cpu().cpsr(),
+        Operand::Memory(address) => peek(gba, address, 4).unwrap_or(0),
+        Operand::Value(value) => value,
+    }
+}
+
+impl Condition {
+    fn holds(&self, gba: &Gba) -> bool {
+        let left = operand_value(gba, self.left);
+        let right = operand_value(gba, self.right);
+        match self.comparison {
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+            Comparison::Less => left < right,
+            Comparison::LessEqual => left <= right,
+            Comparison::Greater => left > right,
+            Comparison::GreaterEqual => left >= right,
+        }
+    }
+}
+
+fn describe_access(access: &MemoryAccess) -> String {
+    let kind = match access.kind {
+        AccessKind::Read => "read from",
+        AccessKind::Write => "write to",
+        AccessKind::Execute => "execute at",
+    };
+    format!(
+        "{}-bit {} {:08X} value {:0width$X} by {:08X} at cycle {}",
+        access.width as u32 * 8,
+        kind,
+        access.address,
+        access.value,
+        access.pc,
+        access.cycle,
+        width = access.width as usize * 2
+    )
+}
+
+fn describe_hit(hit: &WatchpointHit) -> String {
+    format!("Watchpoint {}: {}", hit.watchpoint, describe_access(&hit.access))
+}
+
+fn disassemble_at(gba: &Gba, address: u32, thumb: bool) -> String {
+    let size = if thumb { 2 } else { 4 };
+    match peek(gba, address, size) {
+        Some(opcode) if thumb => format!("{:08X}:     {:04X}  {}", address, opcode, disassemble_thumb(opcode as u16, address)),
+        Some(opcode) => format!("{:08X}: {:08X}  {}", address, opcode, disassemble_arm(opcode, address)),
+        None => format!("{:08X}: <unmapped>", address),
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger { breakpoints: Vec::new(), last_command: String::new(), interrupted: Arc::new(AtomicBool::new(false)) }
+    }
+
+    /// Setting the flag stops a running `continue`, `next` or `finish` at the next frame boundary.
+    /// Meant for Ctrl-C handlers and the frontend's event loop.
+    pub fn interrupt_flag(&self) -> Arc<AtomicBool> {
+        self.interrupted.clone()
+    }
+
+    fn breakpoint_hit(&self, gba: &Gba) -> Option<usize> {
+        let (address, _) = gba.cpu().current_instruction()?;
+        self.breakpoints.iter().position(|breakpoint| {
+            breakpoint.address == address
+                && breakpoint.condition.as_ref().is_none_or(|(condition, _)| condition.holds(gba))
+        })
+    }
+
+    fn current_address(gba: &Gba) -> u32 {
+        match gba.cpu().current_instruction() {
+            Some((address, _)) => address,
+            None => gba.cpu().registers()[15],
+        }
+    }
+
+    fn print_location(&self, gba: &Gba) {
+        let address = Self::current_address(gba);
+        println!(
+            "{}    (scanline {}, cycle {})",
+            disassemble_at(gba, address, gba.cpu().is_thumb()),
+            gba.scanline(),
+            gba.cycles()
+        );
+    }
+
+    fn print_registers(&self, gba: &mut Gba) {
+        let registers = *gba.cpu().registers();
+        for (row, values) in registers.chunks(4).enumerate() {
+            let line: Vec<String> = values
+                .iter()
+                .enumerate()
+                .map(|(i, value)| format!("{:>4}: {:08X}", format!("r{}", row * 4 + i), value))
+                .collect();
+            println!("{}", line.join("  "));
+        }
+        let cpsr = gba.cpu().cpsr();
+        let flags: String = [(31, 'N'), (30, 'Z'), (29, 'C'), (28, 'V'), (7, 'I'), (6, 'F'), (5, 'T')]
+            .iter()
+            .map(|(bit, flag)| if cpsr & (1 << bit) != 0 { *flag } else { '-' })
+            .collect();
+        println!(
+            "cpsr: {:08X} [{}] mode {} {}",
+            cpsr,
+            flags,
+            mode_name(cpsr & 0x1F),
+            if gba.cpu().is_thumb() { "THUMB" } else { "ARM" }
+        );
+        println!("spsr: {:08X}", gba.cpu_mut().spsr());
+    }
+
+    // Runs until `stop` returns true, a breakpoint or watchpoint is hit, `limit` instructions
+    // executed or the debugger is interrupted. `on_frame` runs once per frame's worth of cycles.
+    // Returns false if the limit was reached.
+    fn run_until(
+        &self,
+        gba: &mut Gba,
+        limit: usize,
+        stop: impl Fn(&Gba) -> bool,
+        on_frame: &mut dyn FnMut(&mut Gba)
+    ) -> bool {
+        self.interrupted.store(false, Ordering::Relaxed);
+        // Running commands hand control back to the frontend once a frame
+        let mut frame_end = gba.cycles() + CYCLES_PER_FRAME;
+        for executed in 0..limit {
+            if let Err(error) = gba.step() {
+                println!("{}", error);
+                return true;
+            }
+            if let Some(hit) = gba.take_watchpoint_hit() {
+                println!("{}", describe_hit(&hit));
+                return true;
+            }
+            if stop(gba) {
+                return true;
+            }
+            if let Some(index) = self.breakpoint_hit(gba) {
+                println!("Breakpoint {} hit after {} instructions", index, executed + 1);
+                return true;
+            }
+            if gba.cycles() >= frame_end {
+                frame_end = gba.cycles() + CYCLES_PER_FRAME;
+                on_frame(gba);
+                if self.interrupted.swap(false, Ordering::Relaxed) {
+                    println!("Interrupted after {} instructions", executed + 1);
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    fn watch(&self, gba: &mut Gba, tokens: &[&str]) {
+        let (read, write, execute) = match tokens.get(1).copied() {
+            Some("r") => (true, false, false),
+            Some("w") => (false, true, false),
+            Some("rw") => (true, true, false),
+            Some("x") => (false, false, true),
+            _ => {
+                println!("Usage: watch <r|w|rw|x> <addr> [end]");
+                return;
+            }
+        };
+        let Some(start) = tokens.get(2).and_then(|token| parse_number(token)) else {
+            println!("Usage: watch <r|w|rw|x> <addr> [end]");
+            return;
+        };
+        let end = tokens.get(3).and_then(|token| parse_number(token)).unwrap_or(start);
+        let index = gba.memory_mut().monitor().add_watchpoint(Watchpoint { start, end, read, write, execute });
+        println!("Watchpoint {} on {:08X}-{:08X}", index, start, end);
+    }
+
+    fn access_log(&self, gba: &mut Gba, tokens: &[&str]) {
+        let memory = gba.memory_mut();
+        let monitor = memory.monitor();
+        match (tokens.get(1).copied(), tokens.get(2).copied()) {
+            (Some("show"), count) => {
+                let count = count.and_then(parse_number).unwrap_or(20) as usize;
+                let log = monitor.log();
+                for access in log.iter().skip(log.len().saturating_sub(count)) {
+                    println!("{}", describe_access(access));
+                }
+            }
+            (Some("clear"), _) => monitor.clear_log(),
+            (Some("size"), Some(size)) => match parse_number(size) {
+                Some(size) => monitor.set_log_capacity(size as usize),
+                None => println!("Usage: log size <n>"),
+            },
+            (Some(region), Some(state @ ("on" | "off"))) => {
+                let enabled = state == "on";
+                if region == "all" {
+                    for (name, _, _) in MEMORY_REGIONS {
+                        monitor.set_region_logging(name, enabled);
+                    }
+                } else if !monitor.set_region_logging(region, enabled) {
+                    println!("Unknown region `{}`, see `regions`", region);
+                }
+            }
+            _ => println!("Usage: log <region|all> <on|off> | log show [n] | log clear | log size <n>"),
+        }
+    }
+
+    fn dump_memory(&self, gba: &Gba, address: u32, length: u32) {
+        let memory = gba.memory();
+        let start = address & !0xF;
+        let mut line_address = start;
+        while line_address < address.saturating_add(length) {
+            let bytes: Vec<Option<u8>> = (0..16).map(|i| memory.peek_byte(line_address.wrapping_add(i))).collect();
+            let hex: Vec<String> = bytes.iter().map(|byte| byte.map_or("--".to_string(), |byte| format!("{:02X}", byte))).collect();
+            let ascii: String = bytes
+                .iter()
+                .map(|byte| match byte {
+                    Some(byte) if byte.is_ascii_graphic() => *byte as char,
+                    _ => '.',
+                })
+                .collect();
+            println!("{:08X}  {}  {}", line_address, hex.join(" "), ascii);
+            line_address = match line_address.checked_add(16) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+    }
+
+    fn poke(&self, gba: &mut Gba, address: u32, value: u32, size: u32) {
+        let memory = gba.memory_mut();
+        for i in 0..size {
+            if !memory.poke_byte(address.wrapping_add(i), (value >> (i * 8)) as u8) {
+                println!("{:08X} is not mapped", address.wrapping_add(i));
+                return;
+            }
+        }
+    }
+
+    fn execute(&mut self, gba: &mut Gba, command: &str, on_frame: &mut dyn FnMut(&mut Gba)) -> Option<DebuggerAction> {
+        let tokens: Vec<&str> = command.split_whitespace().collect();
+        let argument = |index: usize| tokens.get(index).and_then(|token| parse_number(token));
+        match tokens.first().copied().unwrap_or("") {
+            "" => (),
+            "h" | "help" => println!("{}", HELP),
+            "s" | "step" => {
+                self.run_until(gba, argument(1).unwrap_or(1) as usize, |_| false, on_frame);
+                return Some(DebuggerAction::Resume);
+            }
+            "n" | "next" => {
+                let (address, opcode) = gba.cpu().current_instruction().unwrap_or((0, 0));
+                let return_address = if gba.cpu().is_thumb() {
+                    match opcode & 0xF800 {
+                        // First half of a BL pair, the call happens on the second half
+                        0xF000 => Some(address + 4),
+                        0xF800 => Some(address + 2),
+                        _ => None,
+                    }
+                } else if opcode & 0x0F00_0000 == 0x0B00_0000 {
+                    Some(address + 4)
+                } else {
+                    None
+                };
+                match return_address {
+                    Some(return_address) => {
+                        self.run_until(gba, usize::MAX, |gba| Self::current_address(gba) == return_address, on_frame)
+                    }
+                    None => self.run_until(gba, 1, |_| false, on_frame),
+                };
+                return Some(DebuggerAction::Resume);
+            }
+            "finish" => {
+                let return_address = gba.cpu().registers()[14] & !0x1;
+                self.run_until(gba, usize::MAX, |gba| Self::current_address(gba) == return_address, on_frame);
+                return Some(DebuggerAction::Resume);
+            }
+            "c" | "continue" => {
+                let limit = argument(1).map_or(usize::MAX, |limit| limit as usize);
+                if !self.run_until(gba, limit, |_| false, on_frame) {
+                    println!("Stopped after {} instructions", limit);
+                }
+                return Some(DebuggerAction::Resume);
+            }
+            "b" | "break" => {
+                let Some(address) = argument(1) else {
+                    println!("Usage: break <addr> [if <cond>]");
+                    return None;
+                };
+                let condition = match tokens.get(2) {
+                    Some(&"if") => match parse_condition(&tokens[3..]) {
+                        Some(condition) => Some((condition, tokens[3..].join(" "))),
+                        None => {
+                            println!("Invalid condition, expected e.g. `r0 == 0x10` or `[0x3000000] != 0`");
+                            return None;
+                        }
+                    },
+                    _ => None,
+                };
+                self.breakpoints.push(Breakpoint { address, condition });
+                println!("Breakpoint {} at {:08X}", self.breakpoints.len() - 1, address);
+            }
+            "delete" => match argument(1) {
+                Some(index) if (index as usize) < self.breakpoints.len() => {
+                    self.breakpoints.remove(index as usize);
+                }
+                Some(index) => println!("No breakpoint {}", index),
+                None => self.breakpoints.clear(),
+            },
+            "info" => {
+                for (index, breakpoint) in self.breakpoints.iter().enumerate() {
+                    match &breakpoint.condition {
+                        Some((_, text)) => println!("{}: {:08X} if {}", index, breakpoint.address, text),
+                        None => println!("{}: {:08X}", index, breakpoint.address),
+                    }
+                }
+            }
+            "r" | "regs" => self.print_registers(gba),
+            "set" => match (tokens.get(1).copied(), argument(2)) {
+                (Some("cpsr"), Some(value)) => gba.cpu_mut().set_cpsr(value),
+                (Some(name), Some(value)) => match parse_register(name) {
+                    Some(register) => gba.set_register(register, value),
+                    None => println!("Unknown register: {}", name),
+                },
+                _ => println!("Usage: set <reg> <value>"),
+            },
+            "x" => match argument(1) {
+                Some(address) => self.dump_memory(gba, address, argument(2).unwrap_or(64)),
+                None => println!("Usage: x <addr> [len]"),
+            },
+            "poke" => {
+                let size = match tokens.get(3).copied() {
+                    Some("b") => 1,
+                    Some("h") => 2,
+                    _ => 4,
+                };
+                match (argument(1), argument(2)) {
+                    (Some(address), Some(value)) => self.poke(gba, address, value, size),
+                    _ => println!("Usage: poke <addr> <value> [b|h|w]"),
+                }
+            }
+            "d" | "disasm" => {
+                let thumb = gba.cpu().is_thumb();
+                let size = if thumb { 2 } else { 4 };
+                let count = argument(2).unwrap_or(10);
+                let start = argument(1).unwrap_or_else(|| Self::current_address(gba).wrapping_sub(size * (count / 2)));
+                let current = Self::current_address(gba);
+                for i in 0..count {
+                    let address = start.wrapping_add(i * size);
+                    let marker = if address == current { ">" } else { " " };
+                    println!("{} {}", marker, disassemble_at(gba, address, thumb));
+                }
+            }
+            "regions" => {
+                for (name, start, end) in MEMORY_REGIONS {
+                    println!("{:<8} {:08X}-{:08X}", name, start, end);
+                }
+            }
+            "watch" => self.watch(gba, &tokens),
+            "unwatch" => match argument(1).and_then(|index| gba.memory_mut().monitor().remove_watchpoint(index as usize)) {
+                Some(_) => (),
+                None => println!("Usage: unwatch <n>"),
+            },
+            "watches" => {
+                let memory = gba.memory_mut();
+                for (index, watchpoint) in memory.monitor().watchpoints().iter().enumerate() {
+                    let kinds: String = [(watchpoint.read, 'r'), (watchpoint.write, 'w'), (watchpoint.execute, 'x')]
+                        .iter()
+                        .filter(|(enabled, _)| *enabled)
+                        .map(|(_, kind)| *kind)
+                        .collect();
+                    println!("{}: {:08X}-{:08X} {}", index, watchpoint.start, watchpoint.end, kinds);
+                }
+            }
+            "log" => self.access_log(gba, &tokens),
+            "q" | "quit" => return Some(DebuggerAction::Quit),
+            unknown => println!("Unknown command `{}`, try `help`", unknown),
+        }
+        None
+    }
+
+    /// Reads and executes commands until one of them runs the emulator or quits. `on_frame` is
+    /// called about once per emulated frame while a command runs, to keep the window responsive.
+    pub fn prompt(&mut self, gba: &mut Gba, on_frame: &mut dyn FnMut(&mut Gba)) -> DebuggerAction {
+        self.print_location(gba);
+        let stdin = io::stdin();
+        loop {
+            print!("(dbg) ");
+            let _ = io::stdout().flush();
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                return DebuggerAction::Quit;
+            }
+            let mut command = line.trim().to_string();
+            if command.is_empty() {
+                command = self.last_command.clone();
+            } else {
+                self.last_command = command.clone();
+            }
+            if let Some(action) = self.execute(gba, &command, on_frame) {
+                return action;
+            }
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use std::{fmt, io, path::PathBuf};
+
+#[derive(Debug)]
+pub enum GbaError {
+    Io { path: PathBuf, source: io::Error },
+    InvalidBiosSize(usize),
+    EmptyRom,
+    RomTooLarge(usize),
+    InvalidRomHeader(&'static str),
+    MultibootTooLarge(usize),
+    InvalidArchive(&'static str),
+    ArchiveEntryNotFound(String),
+    InvalidPatch(&'static str),
+    PatchChecksumMismatch { checksum: &'static str, expected: u32, actual: u32 },
+    InvalidSaveSize(usize),
+    InvalidCheat { code: String, reason: &'static str },
+    InvalidCheatList { line: usize, reason: &'static str },
+    UnknownTraceFormat(String),
+    UnknownCpuMode(String),
+    InvalidArgument(String),
+    InvalidState(&'static str),
+    UnsupportedStateVersion(u32),
+    StateRomMismatch { state: String, rom: String },
+    InvalidMovie(&'static str),
+    MovieRomMismatch { movie: String, rom: String },
+    MovieDesync { frame: usize },
+    CpuFault(CpuFault)
+}
+
+impl fmt::Display for GbaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GbaError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            GbaError::InvalidBiosSize(size) => write!(f, "The BIOS has to be 16384 bytes, got {}", size),
+            GbaError::EmptyRom => write!(f, "The ROM is empty"),
+            GbaError::RomTooLarge(size) => write!(f, "ROMs are at most 32 MiB, got {} bytes", size),
+            GbaError::InvalidRomHeader(reason) => write!(f, "Invalid ROM header: {}", reason),
+            GbaError::MultibootTooLarge(size) => write!(f, "Multiboot images are at most 256 KiB, got {} bytes", size),
+            GbaError::InvalidArchive(reason) => write!(f, "Invalid archive: {}", reason),
+            GbaError::ArchiveEntryNotFound(name) => write!(f, "No {} in the archive", name),
+            GbaError::InvalidPatch(reason) => write!(f, "Invalid patch: {}", reason),
+            GbaError::PatchChecksumMismatch { checksum: "source", expected, actual } =>
+                write!(f, "The patch is for a ROM with CRC-32 {:08X}, this one has {:08X}", expected, actual),
+            GbaError::PatchChecksumMismatch { checksum: "patch", expected, actual } =>
+                write!(f, "The patch is corrupt, its CRC-32 should be {:08X}, got {:08X}", expected, actual),
+            GbaError::PatchChecksumMismatch { expected, actual, .. } =>
+                write!(f, "The patched ROM's CRC-32 should be {:08X}, got {:08X}", expected, actual),
+            GbaError::InvalidSaveSize(size) => write!(f, "Saves are 32 or 64 KiB, got {} bytes", size),
+            GbaError::InvalidCheat { code, reason } => write!(f, "Invalid cheat code {}: {}", code, reason),
+            GbaError::InvalidCheatList { line, reason } => write!(f, "Invalid cheat list, line {}: {}", line, reason),
+            GbaError::UnknownTraceFormat(name) => write!(f, "Unknown trace format: {}", name),
+            GbaError::UnknownCpuMode(name) => write!(f, "Unknown CPU mode: {}", name),
+            GbaError::InvalidArgument(message) => write!(f, "{}", message),
+            GbaError::InvalidState(reason) => write!(f, "Invalid save state: {}", reason),
+            GbaError::UnsupportedStateVersion(version) => write!(f, "Unsupported save state version {}", version),
+            GbaError::StateRomMismatch { state, rom } =>
+                write!(f, "The save state is from \"{}\", the loaded ROM is \"{}\"", state, rom),
+            GbaError::InvalidMovie(reason) => write!(f, "Invalid movie: {}", reason),
+            GbaError::MovieRomMismatch { movie, rom } =>
+                write!(f, "The movie was recorded with \"{}\", the loaded ROM is \"{}\"", movie, rom),
+            GbaError::MovieDesync { frame } => write!(f, "Movie playback desynced at frame {}", frame),
+            GbaError::CpuFault(fault) => write!(f, "{}", fault),
+        }
+    }
+}
+
+impl std::error::Error for GbaError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GbaError::Io { source, .. } => Some(source),
+            _ => None
+        }
+    }
+}
+
+/// A state the CPU can't continue from, along with the instruction that led to it.
+#[derive(Clone, Debug)]
+pub struct CpuFault {
+    pub reason: &'static str,
+    pub pc: u32,
+    pub opcode: u32,
+    pub thumb: bool,
+    pub cpsr: u32,
+    pub registers: [u32; 16]
+}
+
+impl fmt::Display for CpuFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (state, opcode_width) = if self.thumb { ("THUMB", 4) } else { ("ARM", 8) };
+        writeln!(
+            f,
+            "CPU fault: {} at {:08X}, opcode {:0width$X} in {} state, CPSR {:08X}",
+            self.reason,
+            self.pc,
+            self.opcode,
+            state,
+            self.cpsr,
+            width = opcode_width
+        )?;
+        for (index, value) in self.registers.iter().enumerate() {
+            let separator = if index % 4 == 3 { "\n" } else { "  " };
+            write!(f, "r{:<2} {:08X}{}", index, value, separator)?;
+        }
+        Ok(())
+    }
+}
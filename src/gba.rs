@@ -1,67 +1,349 @@
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::fs;
+use std::path::{Path, PathBuf};
 
 use crate::arm7::cpu::Cpu;
-use crate::constants::{VISIBLE_H, VISIBLE_V, V_BLANK};
+use crate::arm7::tracer::Tracer;
+use crate::archive;
+use crate::bus::Bus;
+use crate::cartridge::{looks_like_multiboot, RomInfo};
+use crate::cheats::Cheats;
+use crate::constants::{CYCLES_PER_FRAME, VISIBLE_H, VISIBLE_V};
+use crate::error::GbaError;
+use crate::input::{Key, KEY_MASK};
 use crate::memory::Memory;
+use crate::monitor::WatchpointHit;
+use crate::patch;
 use crate::scheduler::{Event, Scheduler, EventType};
+use crate::state::{StateHeader, StateReader, StateWriter};
+use crate::io::{KEYINPUT, VCOUNT};
 use crate::video::Video;
 
+const MULTIBOOT_START: u32 = 0x0200_0000;
+const PATCH_EXTENSIONS: [&str; 3] = ["ips", "ups", "bps"];
+
+/// The whole system, owning the CPU, the memory bus and the peripherals.
 pub struct Gba {
-    memory: Rc<RefCell<Memory>>,
+    memory: Memory,
     cpu: Cpu,
     video: Video,
     scheduler: Scheduler,
+    rom_info: RomInfo,
+    // Patch file `load_rom_file_with` applied to the loaded ROM
+    rom_patch: Option<PathBuf>,
+    cheats: Cheats,
     frames: usize,
     overshot: usize
 }
 
+// The whole emulator has to stay movable to a worker thread
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<Gba>();
+};
+
+impl Default for Gba {
+    fn default() -> Gba {
+        Gba::new()
+    }
+}
+
 impl Gba {
     pub fn new() -> Gba {
-        let memory = Rc::new(RefCell::new(Memory::new()));
-        let mut scheduler = Scheduler::new(Rc::clone(&memory));
-        scheduler.schedule_from_now(Event::new(VISIBLE_H, EventType::HVisibleEnd));
-        scheduler.schedule_from_now(Event::new(VISIBLE_V, EventType::VVisibleEnd));
+        let memory = Memory::new();
+        let now = memory.get_clock_cycles();
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule_from_now(Event::new(VISIBLE_H, EventType::HVisibleEnd), now);
+        scheduler.schedule_from_now(Event::new(VISIBLE_V, EventType::VVisibleEnd), now);
         Gba {
-            memory: Rc::clone(&memory),
-            cpu: Cpu::new(Rc::clone(&memory)),
-            video: Video::new(Rc::clone(&memory)),
+            memory,
+            cpu: Cpu::new(),
+            video: Video::new(),
             scheduler,
+            rom_info: RomInfo::parse(&[]),
+            rom_patch: None,
+            cheats: Cheats::new(),
             frames: 0,
             overshot: 0
         }
     }
 
-    pub fn load_bios(&mut self, bios: Vec<u8>) {
-        self.memory.borrow_mut().load_bios(bios);
+    /// Loads the 16 KiB BIOS image. Without one, BIOS calls are emulated natively.
+    pub fn load_bios(&mut self, bios: Vec<u8>) -> Result<(), GbaError> {
+        self.memory.load_bios(bios)?;
+        self.cpu.set_hle_bios(false);
+        Ok(())
+    }
+
+    /// Accepts up to 32 MiB. The header isn't checked, see `RomInfo::validate`.
+    pub fn load_rom(&mut self, rom: Vec<u8>) -> Result<(), GbaError> {
+        let rom_info = RomInfo::parse(&rom);
+        self.memory.load_rom(rom)?;
+        self.rom_info = rom_info;
+        self.rom_patch = None;
+        self.cheats.forget_rom_patches();
+        Ok(())
+    }
+
+    /// Copies a multiboot image into EWRAM and starts executing it from there.
+    pub fn load_multiboot(&mut self, image: Vec<u8>) -> Result<(), GbaError> {
+        self.memory.load_multiboot(&image)?;
+        self.rom_info = RomInfo::parse(&image);
+        self.rom_patch = None;
+        self.cheats.forget_rom_patches();
+        self.cpu.set_register(&mut self.memory, 15, MULTIBOOT_START);
+        Ok(())
+    }
+
+    pub fn rom_info(&self) -> &RomInfo {
+        &self.rom_info
+    }
+
+    /// The patch file applied when the ROM was loaded from a file.
+    pub fn rom_patch(&self) -> Option<&Path> {
+        self.rom_patch.as_deref()
+    }
+
+    /// Restores the cartridge's battery backed SRAM from a 32 or 64 KiB save.
+    pub fn load_save(&mut self, save: Vec<u8>) -> Result<(), GbaError> {
+        self.memory.load_save(save)
+    }
+
+    pub fn load_bios_file(&mut self, path: impl AsRef<Path>) -> Result<(), GbaError> {
+        self.load_bios(read_file(path.as_ref())?)
+    }
+
+    /// Also takes .zip and .gz files, and multiboot images: .mb files, or files that are neither
+    /// .mb nor .gba which `looks_like_multiboot` accepts. A patch next to the ROM is applied, see
+    /// `load_rom_file_with`.
+    pub fn load_rom_file(&mut self, path: impl AsRef<Path>) -> Result<(), GbaError> {
+        self.load_rom_file_with(path, None, None).map(|_| ())
+    }
+
+    /// Like `load_rom_file`, picking `entry` out of a zip instead of its first .gba file, and
+    /// applying `patch` if given or else the first of `<rom>.ips`, `.ups` and `.bps` (after the
+    /// full file name, then in place of its extension) that exists. The patch is applied in memory,
+    /// returns its path.
+    pub fn load_rom_file_with(
+        &mut self,
+        path: impl AsRef<Path>,
+        entry: Option<&str>,
+        patch: Option<&Path>
+    ) -> Result<Option<PathBuf>, GbaError> {
+        let path = path.as_ref();
+        let file_name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let (mut image, name) = archive::extract(read_file(path)?, &file_name, entry)?;
+        let patch = patch.map(Path::to_path_buf).or_else(|| find_patch(path));
+        if let Some(patch) = &patch {
+            image = patch::apply(&read_file(patch)?, &image)?;
+        }
+        let extension = Path::new(&name).extension().map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+        let multiboot = match extension.as_deref() {
+            Some("mb") => true,
+            Some("gba" | "agb") => false,
+            _ => looks_like_multiboot(&image),
+        };
+        if multiboot {
+            self.load_multiboot(image)?;
+        } else {
+            self.load_rom(image)?;
+        }
+        self.rom_patch = patch.clone();
+        Ok(patch)
+    }
+
+    pub fn load_save_file(&mut self, path: impl AsRef<Path>) -> Result<(), GbaError> {
+        self.load_save(read_file(path.as_ref())?)
+    }
+
+    pub fn cheats(&self) -> &Cheats {
+        &self.cheats
+    }
+
+    /// Changes take effect at the next VBlank.
+    pub fn cheats_mut(&mut self) -> &mut Cheats {
+        &mut self.cheats
+    }
+
+    /// Replaces the cheat list with a cheat file, see `Cheats::load_text`.
+    pub fn load_cheats_file(&mut self, path: impl AsRef<Path>) -> Result<(), GbaError> {
+        let text = read_file(path.as_ref())?;
+        self.cheats.load_text(&String::from_utf8_lossy(&text))
+    }
+
+    pub fn save_cheats_file(&self, path: impl AsRef<Path>) -> Result<(), GbaError> {
+        let path = path.as_ref();
+        fs::write(path, self.cheats.to_text()).map_err(|source| GbaError::Io { path: path.to_path_buf(), source })
+    }
+
+    /// The cartridge SRAM, to be written back to the save file.
+    pub fn save_data(&self) -> &[u8] {
+        self.memory.save_data()
+    }
+
+    /// Snapshots everything needed to resume emulation exactly where it is, except the BIOS and ROM.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        StateHeader::for_rom(&self.rom_info).write(&mut state);
+        self.cpu.save_state(&mut state);
+        self.memory.save_state(&mut state);
+        self.scheduler.save_state(&mut state);
+        self.video.save_state(&mut state);
+        state.write_usize(self.frames);
+        state.write_usize(self.overshot);
+        state.into_inner()
+    }
+
+    /// Restores a `save_state` snapshot taken with the same ROM. Nothing changes if it fails.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), GbaError> {
+        let mut state = StateReader::new(data);
+        StateHeader::read(&mut state)?.check_rom(&self.rom_info)?;
+        let backup = self.save_state();
+        if let Err(error) = self.load_components(&mut state) {
+            let mut backup = StateReader::new(&backup);
+            StateHeader::read(&mut backup)
+                .and_then(|_| self.load_components(&mut backup))
+                .expect("Restoring the state from before the failed load");
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    fn load_components(&mut self, state: &mut StateReader) -> Result<(), GbaError> {
+        self.cpu.load_state(state)?;
+        self.memory.load_state(state)?;
+        self.scheduler.load_state(state)?;
+        self.video.load_state(state)?;
+        self.frames = state.read_usize()?;
+        self.overshot = state.read_usize()?;
+        state.finish()
     }
 
-    pub fn load_rom(&mut self, rom: Vec<u8>) {
-        self.memory.borrow_mut().load_rom(rom);
+    /// Sets every key at once from a mask of `Key::mask` bits that are held down.
+    pub fn set_keys(&mut self, pressed: u16) {
+        self.memory.io_mut().set(KEYINPUT, !pressed & KEY_MASK);
     }
 
-    fn run(&mut self, cycles: usize) {
-        let start_time = self.scheduler.timestamp();
+    pub fn set_key(&mut self, key: Key, pressed: bool) {
+        let keys = if pressed { self.keys() | key.mask() } else { self.keys() & !key.mask() };
+        self.set_keys(keys);
+    }
+
+    /// The held keys as a mask of `Key::mask` bits.
+    pub fn keys(&self) -> u16 {
+        !self.memory.io().get(KEYINPUT) & KEY_MASK
+    }
+
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.cpu.set_tracer(tracer);
+    }
+
+    /// Runs for `cycles` clock cycles. The last instruction can overshoot, the next call makes up for it.
+    /// Fails if the CPU faults, see `Cpu::fault`.
+    pub fn run(&mut self, cycles: usize) -> Result<(), GbaError> {
+        let start_time = self.cycles();
         let next_frame = self.frames + 1;
-        let frame_end = Event::new(cycles - self.overshot, crate::scheduler::EventType::EndFrame);
-        self.scheduler.schedule_from_now(frame_end);
+        let budget = cycles.saturating_sub(self.overshot);
+        let frame_end = Event::new(budget, crate::scheduler::EventType::EndFrame);
+        self.scheduler.schedule_from_now(frame_end, start_time);
         while self.frames != next_frame {
-            while self.scheduler.time_until_next_event() > 0 {
-                self.next()
+            while self.scheduler.time_until_next_event(self.cycles()) > 0 {
+                self.next();
+                self.check_fault()?;
             }
 
             self.handle_events();
         }
-        let end_time = self.scheduler.timestamp();
-        self.overshot = start_time.saturating_sub(end_time).saturating_sub(cycles);
+        let elapsed = self.cycles() - start_time;
+        self.overshot = (self.overshot + elapsed).saturating_sub(cycles);
+        Ok(())
     }
 
-    pub fn frame(&mut self) {
-        self.run(VISIBLE_V + V_BLANK);
+    /// Runs one full frame, 228 scanlines.
+    pub fn frame(&mut self) -> Result<(), GbaError> {
+        self.run(CYCLES_PER_FRAME)
     }
 
+    /// Advances the CPU pipeline once, without handling scheduled events. While the CPU is halted
+    /// the clock skips to the next event instead.
     pub fn next(&mut self) {
-        self.cpu.next();
+        if self.cpu.halted() {
+            let idle = self.scheduler.time_until_next_event(self.cycles());
+            self.memory.add_clock_cycles(idle);
+            return;
+        }
+        self.cpu.next(&mut self.memory);
+        if self.memory.io_mut().take_halt_request() {
+            self.cpu.halt();
+        }
+    }
+
+    /// Runs until exactly one instruction has executed, handling any events that became due.
+    pub fn step(&mut self) -> Result<(), GbaError> {
+        loop {
+            let instruction = self.cpu.current_instruction();
+            if let Some((address, opcode)) = instruction.filter(|_| !self.cpu.halted()) {
+                self.memory.check_execute(address, opcode, self.cpu.is_thumb());
+            }
+            let executes = instruction.is_some() && !self.cpu.halted();
+            self.next();
+            self.check_fault()?;
+            self.handle_events();
+            if executes {
+                return Ok(());
+            }
+        }
+    }
+
+    fn check_fault(&self) -> Result<(), GbaError> {
+        match self.cpu.fault() {
+            Some(fault) => Err(GbaError::CpuFault(fault.clone())),
+            None => Ok(())
+        }
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    /// Writes a register through the CPU, refilling the pipeline when it is the PC.
+    pub fn set_register(&mut self, register: usize, value: u32) {
+        self.cpu.set_register(&mut self.memory, register, value);
+    }
+
+    /// The first watchpoint hit since the last call, checked by debuggers after every `step`.
+    pub fn take_watchpoint_hit(&mut self) -> Option<WatchpointHit> {
+        self.memory.take_watchpoint_hit()
+    }
+
+    /// Frames completed by `frame` and `run` since power on.
+    pub fn frame_count(&self) -> usize {
+        self.frames
+    }
+
+    /// Clock cycles since power on.
+    pub fn cycles(&self) -> usize {
+        self.memory.get_clock_cycles()
+    }
+
+    pub fn scanline(&self) -> u16 {
+        self.memory.io().get(VCOUNT)
+    }
+
+    /// The last frame, `SCREEN_WIDTH` by `SCREEN_HEIGHT` little endian BGR555 pixels.
+    pub fn frame_buffer(&self) -> &[u8] {
+        &self.video.frame_buffer
     }
 
     pub fn get_frame_buffer(&mut self) -> &mut [u8] {
@@ -69,20 +351,39 @@ impl Gba {
     }
 
     fn handle_events(&mut self) {
-        while let Some(event) = self.scheduler.pop() {
+        while let Some(event) = self.scheduler.pop(self.memory.get_clock_cycles()) {
             let new_event = match event.event_type {
                 EventType::EndFrame => {
                     self.frames += 1;
                     None
                 },
-                EventType::HVisibleEnd => Some(self.video.h_visible_end_handler()),
-                EventType::HBlankEnd => Some(self.video.h_blank_end_handler()),
-                EventType::VVisibleEnd => Some(self.video.v_visible_end_handler()),
-                EventType::VBlankEnd => Some(self.video.v_blank_end_handler()),
+                EventType::HVisibleEnd => Some(self.video.h_visible_end_handler(&mut self.memory)),
+                EventType::HBlankEnd => Some(self.video.h_blank_end_handler(&mut self.memory)),
+                EventType::VVisibleEnd => {
+                    let event = self.video.v_visible_end_handler(&mut self.memory);
+                    self.cheats.apply(&mut self.memory);
+                    self.cpu.wake();
+                    Some(event)
+                }
+                EventType::VBlankEnd => Some(self.video.v_blank_end_handler(&mut self.memory)),
             };
-            if new_event.is_some() {
-                self.scheduler.schedule_from_now(new_event.unwrap())
+            if let Some(new_event) = new_event {
+                self.scheduler.schedule_from_now(new_event, self.memory.get_clock_cycles())
             }
         }
     }
 }
+
+fn find_patch(rom: &Path) -> Option<PathBuf> {
+    // With a trailing dot, `with_extension` appends to the full file name
+    let mut appended = rom.as_os_str().to_owned();
+    appended.push(".");
+    let appended = PathBuf::from(appended);
+    PATCH_EXTENSIONS.iter()
+        .flat_map(|extension| [appended.with_extension(extension), rom.with_extension(extension)])
+        .find(|candidate| candidate.is_file())
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, GbaError> {
+    fs::read(path).map_err(|source| GbaError::Io { path: path.to_path_buf(), source })
+}
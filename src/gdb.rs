@@ -0,0 +1,368 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::constants::CYCLES_PER_FRAME;
+use crate::gba::Gba;
+use crate::monitor::{AccessKind, Watchpoint};
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>armv4t</architecture>
+  <feature name="org.gnu.gdb.arm.core">
+    <reg name="r0" bitsize="32" type="uint32"/>
+    <reg name="r1" bitsize="32" type="uint32"/>
+    <reg name="r2" bitsize="32" type="uint32"/>
+    <reg name="r3" bitsize="32" type="uint32"/>
+    <reg name="r4" bitsize="32" type="uint32"/>
+    <reg name="r5" bitsize="32" type="uint32"/>
+    <reg name="r6" bitsize="32" type="uint32"/>
+    <reg name="r7" bitsize="32" type="uint32"/>
+    <reg name="r8" bitsize="32" type="uint32"/>
+    <reg name="r9" bitsize="32" type="uint32"/>
+    <reg name="r10" bitsize="32" type="uint32"/>
+    <reg name="r11" bitsize="32" type="uint32"/>
+    <reg name="r12" bitsize="32" type="uint32"/>
+    <reg name="sp" bitsize="32" type="data_ptr"/>
+    <reg name="lr" bitsize="32"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+    <reg name="cpsr" bitsize="32"/>
+  </feature>
+</target>
+"#;
+
+// Register number of the CPSR in TARGET_XML, R0-R15 come first
+const CPSR_REGISTER: usize = 16;
+// Instructions executed between checks for a Ctrl-C from the client
+const INTERRUPT_POLL_INTERVAL: usize = 4096;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+enum SessionEnd {
+    Detach,
+    Kill
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum BreakpointKind {
+    Software,
+    Hardware
+}
+
+pub struct GdbStub {
+    stream: TcpStream,
+    breakpoints: Vec<(u32, BreakpointKind)>
+}
+
+fn parse_hex(value: &str) -> Option<u32> {
+    u32::from_str_radix(value, 16).ok()
+}
+
+fn decode_hex_bytes(value: &str) -> Option<Vec<u8>> {
+    (0..value.len() / 2).map(|i| u8::from_str_radix(value.get(i * 2..i * 2 + 2)?, 16).ok()).collect()
+}
+
+fn encode_word(value: u32) -> String {
+    value.to_le_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_word(value: &str) -> Option<u32> {
+    let bytes = decode_hex_bytes(value)?;
+    Some(u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?))
+}
+
+impl GdbStub {
+    /// Waits for a debugger to connect on localhost.
+    pub fn listen(port: u16) -> io::Result<GdbStub> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        println!("Waiting for GDB on 127.0.0.1:{}", port);
+        let stub = GdbStub::accept(&listener)?;
+        println!("GDB connected from {}", stub.stream.peer_addr()?);
+        Ok(stub)
+    }
+
+    /// Waits for a debugger to connect on a listener that is already bound.
+    pub fn accept(listener: &TcpListener) -> io::Result<GdbStub> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(GdbStub { stream, breakpoints: Vec::new() })
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.stream.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    // Returns the payload of the next packet, or "\x03" for an interrupt request
+    fn read_packet(&mut self) -> io::Result<String> {
+        loop {
+            match self.read_byte()? {
+                b'$' => (),
+                0x03 => return Ok("\x03".to_string()),
+                _ => continue,
+            }
+            let mut payload = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    b'}' => payload.push(self.read_byte()? ^ 0x20),
+                    byte => payload.push(byte),
+                }
+            }
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let expected = u8::from_str_radix(&String::from_utf8_lossy(&checksum), 16).unwrap_or(0);
+            let actual = payload.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+            if expected == actual {
+                self.stream.write_all(b"+")?;
+                return Ok(String::from_utf8_lossy(&payload).into_owned());
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send_packet(&mut self, payload: &str) -> io::Result<()> {
+        let checksum = payload.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        self.stream.write_all(format!("${}#{:02x}", payload, checksum).as_bytes())?;
+        // The acknowledgement, retransmission is not worth it over a local socket
+        self.read_byte()?;
+        Ok(())
+    }
+
+    fn interrupt_requested(&mut self) -> bool {
+        let mut byte = [0];
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let requested = matches!(self.stream.peek(&mut byte), Ok(1) if byte[0] == 0x03);
+        if requested {
+            let _ = self.stream.read_exact(&mut byte);
+        }
+        let _ = self.stream.set_nonblocking(false);
+        requested
+    }
+
+    fn program_counter(gba: &Gba) -> u32 {
+        match gba.cpu().current_instruction() {
+            Some((address, _)) => address,
+            None => gba.cpu().registers()[15],
+        }
+    }
+
+    fn read_register(gba: &Gba, register: usize) -> Option<u32> {
+        match register {
+            0..=14 => Some(gba.cpu().registers()[register]),
+            15 => Some(Self::program_counter(gba)),
+            CPSR_REGISTER => Some(gba.cpu().cpsr()),
+            _ => None,
+        }
+    }
+
+    fn write_register(gba: &mut Gba, register: usize, value: u32) -> bool {
+        match register {
+            0..=15 => gba.set_register(register, value),
+            CPSR_REGISTER => gba.cpu_mut().set_cpsr(value),
+            _ => return false,
+        }
+        true
+    }
+
+    fn read_memory(gba: &Gba, arguments: &str) -> Option<String> {
+        let (address, length) = arguments.split_once(',')?;
+        let (address, length) = (parse_hex(address)?, parse_hex(length)?);
+        let memory = gba.memory();
+        let mut reply = String::new();
+        for i in 0..length {
+            match memory.peek_byte(address.wrapping_add(i)) {
+                Some(byte) => reply.push_str(&format!("{:02x}", byte)),
+                None if i == 0 => return None,
+                None => break,
+            }
+        }
+        Some(reply)
+    }
+
+    fn write_memory(gba: &mut Gba, arguments: &str) -> Option<()> {
+        let (location, data) = arguments.split_once(':')?;
+        let (address, _) = location.split_once(',')?;
+        let address = parse_hex(address)?;
+        let memory = gba.memory_mut();
+        for (i, byte) in decode_hex_bytes(data)?.into_iter().enumerate() {
+            if !memory.poke_byte(address.wrapping_add(i as u32), byte) {
+                return None;
+            }
+        }
+        Some(())
+    }
+
+    fn target_xml(arguments: &str) -> String {
+        let Some((offset, length)) = arguments.rsplit(':').next().and_then(|range| range.split_once(',')) else {
+            return "E00".to_string();
+        };
+        let (offset, length) = (parse_hex(offset).unwrap_or(0) as usize, parse_hex(length).unwrap_or(0) as usize);
+        let start = offset.min(TARGET_XML.len());
+        let end = (offset + length).min(TARGET_XML.len());
+        let prefix = if end == TARGET_XML.len() { 'l' } else { 'm' };
+        format!("{}{}", prefix, &TARGET_XML[start..end])
+    }
+
+    fn breakpoint(&mut self, gba: &mut Gba, arguments: &str, insert: bool) -> &'static str {
+        let mut fields = arguments.split(',');
+        let (kind, address, length) = (fields.next(), fields.next().and_then(parse_hex), fields.next().and_then(parse_hex));
+        let Some(address) = address else {
+            return "E00";
+        };
+        let (read, write) = match kind {
+            // Software and hardware breakpoints behave the same since we never patch the code, the
+            // kind is only kept for the stop reply
+            Some(kind @ ("0" | "1")) => {
+                let kind = if kind == "0" { BreakpointKind::Software } else { BreakpointKind::Hardware };
+                if insert {
+                    self.breakpoints.push((address, kind));
+                } else if let Some(index) = self.breakpoints.iter().position(|breakpoint| *breakpoint == (address, kind)) {
+                    self.breakpoints.remove(index);
+                }
+                return "OK";
+            }
+            Some("2") => (false, true),
+            Some("3") => (true, false),
+            Some("4") => (true, true),
+            _ => return "",
+        };
+        let watchpoint = Watchpoint {
+            start: address,
+            end: address.wrapping_add(length.unwrap_or(1).max(1) - 1),
+            read,
+            write,
+            execute: false
+        };
+        let memory = gba.memory_mut();
+        let monitor = memory.monitor();
+        if insert {
+            monitor.add_watchpoint(watchpoint);
+        } else {
+            let index = monitor.watchpoints().iter().position(|existing| {
+                (existing.start, existing.end, existing.read, existing.write) == (watchpoint.start, watchpoint.end, read, write)
+            });
+            if let Some(index) = index {
+                monitor.remove_watchpoint(index);
+            }
+        }
+        "OK"
+    }
+
+    // Runs the target and returns the stop reply
+    fn resume(&mut self, gba: &mut Gba, single_step: bool, on_frame: &mut dyn FnMut(&mut Gba)) -> String {
+        let mut next_frame = gba.cycles() + CYCLES_PER_FRAME;
+        let mut executed = 0;
+        loop {
+            if let Err(error) = gba.step() {
+                println!("{}", error);
+                return format!("S{:02x}", SIGILL);
+            }
+            if let Some(hit) = gba.take_watchpoint_hit() {
+                if hit.access.kind == AccessKind::Execute {
+                    return format!("T{:02x}hwbreak:;", SIGTRAP);
+                }
+                // Named after the Z packet that set the watchpoint, not the access that hit it
+                let watchpoint = gba.memory_mut().monitor().watchpoints().get(hit.watchpoint).copied();
+                let kind = match watchpoint.map(|watchpoint| (watchpoint.read, watchpoint.write)) {
+                    Some((true, true)) => "awatch",
+                    Some((true, false)) => "rwatch",
+                    _ => "watch",
+                };
+                return format!("T{:02x}{}:{:x};", SIGTRAP, kind, hit.access.address);
+            }
+            let program_counter = Self::program_counter(gba);
+            if let Some(&(_, kind)) = self.breakpoints.iter().find(|(address, _)| *address == program_counter) {
+                let reason = match kind {
+                    BreakpointKind::Software => "swbreak",
+                    BreakpointKind::Hardware => "hwbreak",
+                };
+                return format!("T{:02x}{}:;", SIGTRAP, reason);
+            }
+            if single_step {
+                return format!("S{:02x}", SIGTRAP);
+            }
+            if gba.cycles() >= next_frame {
+                on_frame(gba);
+                next_frame = gba.cycles() + CYCLES_PER_FRAME;
+            }
+            executed += 1;
+            if executed % INTERRUPT_POLL_INTERVAL == 0 && self.interrupt_requested() {
+                return format!("S{:02x}", SIGINT);
+            }
+        }
+    }
+
+    fn handle_packet(&mut self, gba: &mut Gba, packet: &str, on_frame: &mut dyn FnMut(&mut Gba)) -> Result<String, SessionEnd> {
+        let (command, arguments) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => (0..=CPSR_REGISTER).map(|register| encode_word(Self::read_register(gba, register).unwrap())).collect(),
+            "G" => {
+                for register in 0..=CPSR_REGISTER {
+                    if let Some(value) = arguments.get(register * 8..register * 8 + 8).and_then(decode_word) {
+                        Self::write_register(gba, register, value);
+                    }
+                }
+                "OK".to_string()
+            }
+            "p" => match parse_hex(arguments).and_then(|register| Self::read_register(gba, register as usize)) {
+                Some(value) => encode_word(value),
+                None => "E00".to_string(),
+            },
+            "P" => {
+                let written = arguments
+                    .split_once('=')
+                    .and_then(|(register, value)| Some((parse_hex(register)?, decode_word(value)?)))
+                    .is_some_and(|(register, value)| Self::write_register(gba, register as usize, value));
+                if written { "OK" } else { "E00" }.to_string()
+            }
+            "m" => Self::read_memory(gba, arguments).unwrap_or_else(|| "E01".to_string()),
+            "M" => Self::write_memory(gba, arguments).map_or("E01", |_| "OK").to_string(),
+            "Z" => self.breakpoint(gba, arguments, true).to_string(),
+            "z" => self.breakpoint(gba, arguments, false).to_string(),
+            "c" | "s" => {
+                if let Some(address) = parse_hex(arguments) {
+                    gba.set_register(15, address);
+                }
+                self.resume(gba, command == "s", on_frame)
+            }
+            "H" => "OK".to_string(),
+            "k" => return Err(SessionEnd::Kill),
+            "D" => {
+                let _ = self.send_packet("OK");
+                return Err(SessionEnd::Detach);
+            }
+            "q" if arguments.starts_with("Supported") => "PacketSize=4000;qXfer:features:read+;swbreak+;hwbreak+".to_string(),
+            "q" if arguments.starts_with("Xfer:features:read:target.xml") => Self::target_xml(arguments),
+            "q" if arguments == "Attached" => "1".to_string(),
+            "q" if arguments == "C" => "QC1".to_string(),
+            "q" if arguments == "fThreadInfo" => "m1".to_string(),
+            "q" if arguments == "sThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    /// Serves requests until the client detaches or kills the target.
+    /// `on_frame` is called about once per emulated frame while the target runs.
+    /// Returns true if the client asked to kill the target.
+    pub fn run(&mut self, gba: &mut Gba, on_frame: &mut dyn FnMut(&mut Gba)) -> io::Result<bool> {
+        loop {
+            let packet = self.read_packet()?;
+            if packet == "\x03" {
+                self.send_packet(&format!("S{:02x}", SIGINT))?;
+                continue;
+            }
+            match self.handle_packet(gba, &packet, on_frame) {
+                Ok(reply) => self.send_packet(&reply)?,
+                Err(SessionEnd::Kill) => return Ok(true),
+                Err(SessionEnd::Detach) => return Ok(false),
+            }
+        }
+    }
+}
@@ -0,0 +1,27 @@
+/// The GBA's buttons, in the order of their KEYINPUT bits.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Key {
+    A,
+    B,
+    Select,
+    Start,
+    Right,
+    Left,
+    Up,
+    Down,
+    R,
+    L
+}
+
+impl Key {
+    pub const ALL: [Key; 10] = [
+        Key::A, Key::B, Key::Select, Key::Start, Key::Right, Key::Left, Key::Up, Key::Down, Key::R, Key::L
+    ];
+
+    pub fn mask(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+/// Every key bit of KEYINPUT, which reads 0 for a pressed key.
+pub const KEY_MASK: u16 = 0x3FF;
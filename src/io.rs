@@ -0,0 +1,366 @@
+use crate::error::GbaError;
+use crate::state::{StateReader, StateWriter};
+
+// Register offsets from 0x04000000
+pub const DISPCNT: u32 = 0x000;
+pub const DISPSTAT: u32 = 0x004;
+pub const VCOUNT: u32 = 0x006;
+pub const DMA0CNT_H: u32 = 0x0BA;
+pub const TM0CNT_L: u32 = 0x100;
+pub const KEYINPUT: u32 = 0x130;
+pub const IF: u32 = 0x202;
+pub const WAITCNT: u32 = 0x204;
+pub const HALTCNT: u32 = 0x301;
+
+const IO_SIZE: usize = 0x400;
+const INTERNAL_MEMORY_CONTROL: u32 = 0x800;
+const INTERNAL_MEMORY_CONTROL_MASK: u32 = 0x0F00_002F;
+const DMA_ENABLE: u16 = 0x8000;
+const TIMER_ENABLE: u16 = 0x80;
+
+/// The hardware block behind a register, which picks the side effects of writing it.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IoOwner {
+    Video,
+    Sound,
+    Dma,
+    Timer,
+    Serial,
+    Keypad,
+    Interrupt,
+    System
+}
+
+#[derive(Clone, Copy)]
+struct Register {
+    owner: IoOwner,
+    // Bits visible to reads, 0 for write-only registers
+    read: u16,
+    // Bits the CPU can change, 0 for read-only registers
+    write: u16
+}
+
+const fn register(owner: IoOwner, read: u16, write: u16) -> Option<Register> {
+    Some(Register { owner, read, write })
+}
+
+const REGISTERS: [Option<Register>; IO_SIZE / 2] = register_table();
+
+const fn register_table() -> [Option<Register>; IO_SIZE / 2] {
+    use IoOwner::*;
+    let mut table = [None; IO_SIZE / 2];
+
+    table[0x000 >> 1] = register(Video, 0xFFFF, 0xFFFF);
+    table[0x002 >> 1] = register(Video, 0x0001, 0x0001);
+    table[0x004 >> 1] = register(Video, 0xFF3F, 0xFF38);
+    table[0x006 >> 1] = register(Video, 0x00FF, 0x0000);
+    table[0x008 >> 1] = register(Video, 0xDFFF, 0xDFFF);
+    table[0x00A >> 1] = register(Video, 0xDFFF, 0xDFFF);
+    table[0x00C >> 1] = register(Video, 0xFFFF, 0xFFFF);
+    table[0x00E >> 1] = register(Video, 0xFFFF, 0xFFFF);
+    // Scrolling offsets
+    let mut offset = 0x010;
+    while offset < 0x020 {
+        table[offset >> 1] = register(Video, 0x0000, 0x01FF);
+        offset += 2;
+    }
+    // BG2 and BG3 rotation/scaling parameters and 28 bit reference points
+    let mut offset = 0x020;
+    while offset < 0x040 {
+        let high_reference_half = offset & 0xF >= 0x8 && offset & 0x2 == 0x2;
+        table[offset >> 1] = register(Video, 0x0000, if high_reference_half { 0x0FFF } else { 0xFFFF });
+        offset += 2;
+    }
+    table[0x040 >> 1] = register(Video, 0x0000, 0xFFFF);
+    table[0x042 >> 1] = register(Video, 0x0000, 0xFFFF);
+    table[0x044 >> 1] = register(Video, 0x0000, 0xFFFF);
+    table[0x046 >> 1] = register(Video, 0x0000, 0xFFFF);
+    table[0x048 >> 1] = register(Video, 0x3F3F, 0x3F3F);
+    table[0x04A >> 1] = register(Video, 0x3F3F, 0x3F3F);
+    table[0x04C >> 1] = register(Video, 0x0000, 0xFFFF);
+    table[0x050 >> 1] = register(Video, 0x3FFF, 0x3FFF);
+    table[0x052 >> 1] = register(Video, 0x1F1F, 0x1F1F);
+    table[0x054 >> 1] = register(Video, 0x0000, 0x001F);
+
+    table[0x060 >> 1] = register(Sound, 0x007F, 0x007F);
+    table[0x062 >> 1] = register(Sound, 0xFFC0, 0xFFFF);
+    table[0x064 >> 1] = register(Sound, 0x4000, 0xC7FF);
+    table[0x068 >> 1] = register(Sound, 0xFFC0, 0xFFFF);
+    table[0x06C >> 1] = register(Sound, 0x4000, 0xC7FF);
+    table[0x070 >> 1] = register(Sound, 0x00E0, 0x00E0);
+    table[0x072 >> 1] = register(Sound, 0xE000, 0xE0FF);
+    table[0x074 >> 1] = register(Sound, 0x4000, 0xC7FF);
+    table[0x078 >> 1] = register(Sound, 0xFF00, 0xFF3F);
+    table[0x07C >> 1] = register(Sound, 0x40FF, 0xC0FF);
+    table[0x080 >> 1] = register(Sound, 0xFF77, 0xFF77);
+    table[0x082 >> 1] = register(Sound, 0x770F, 0xFF0F);
+    table[0x084 >> 1] = register(Sound, 0x008F, 0x0080);
+    table[0x088 >> 1] = register(Sound, 0xC3FE, 0xC3FE);
+    // Wave RAM
+    let mut offset = 0x090;
+    while offset < 0x0A0 {
+        table[offset >> 1] = register(Sound, 0xFFFF, 0xFFFF);
+        offset += 2;
+    }
+    // FIFO A and B
+    let mut offset = 0x0A0;
+    while offset < 0x0A8 {
+        table[offset >> 1] = register(Sound, 0x0000, 0xFFFF);
+        offset += 2;
+    }
+
+    // Source, destination, word count and control of the four channels
+    let mut channel = 0;
+    while channel < 4 {
+        let base = 0x0B0 + channel * 12;
+        let source_high = if channel == 0 { 0x07FF } else { 0x0FFF };
+        let destination_high = if channel == 3 { 0x0FFF } else { 0x07FF };
+        table[base >> 1] = register(Dma, 0x0000, 0xFFFF);
+        table[(base + 2) >> 1] = register(Dma, 0x0000, source_high);
+        table[(base + 4) >> 1] = register(Dma, 0x0000, 0xFFFF);
+        table[(base + 6) >> 1] = register(Dma, 0x0000, destination_high);
+        table[(base + 8) >> 1] = register(Dma, 0x0000, if channel == 3 { 0xFFFF } else { 0x3FFF });
+        let control = if channel == 3 { 0xFFE0 } else { 0xF7E0 };
+        table[(base + 10) >> 1] = register(Dma, control, control);
+        channel += 1;
+    }
+
+    // Counter/reload and control of the four timers
+    let mut timer = 0;
+    while timer < 4 {
+        table[(0x100 + timer * 4) >> 1] = register(Timer, 0xFFFF, 0xFFFF);
+        table[(0x102 + timer * 4) >> 1] = register(Timer, 0x00C7, 0x00C7);
+        timer += 1;
+    }
+
+    table[0x120 >> 1] = register(Serial, 0xFFFF, 0xFFFF);
+    table[0x122 >> 1] = register(Serial, 0xFFFF, 0xFFFF);
+    table[0x124 >> 1] = register(Serial, 0xFFFF, 0xFFFF);
+    table[0x126 >> 1] = register(Serial, 0xFFFF, 0xFFFF);
+    table[0x128 >> 1] = register(Serial, 0x7FFF, 0x7FFF);
+    table[0x12A >> 1] = register(Serial, 0xFFFF, 0xFFFF);
+    table[0x134 >> 1] = register(Serial, 0xC1FF, 0xC1FF);
+    table[0x140 >> 1] = register(Serial, 0x0047, 0x0047);
+    table[0x150 >> 1] = register(Serial, 0xFFFF, 0xFFFF);
+    table[0x152 >> 1] = register(Serial, 0xFFFF, 0xFFFF);
+    table[0x154 >> 1] = register(Serial, 0xFFFF, 0xFFFF);
+    table[0x156 >> 1] = register(Serial, 0xFFFF, 0xFFFF);
+    table[0x158 >> 1] = register(Serial, 0x003A, 0x0030);
+
+    table[0x130 >> 1] = register(Keypad, 0x03FF, 0x0000);
+    table[0x132 >> 1] = register(Keypad, 0xC3FF, 0xC3FF);
+
+    table[0x200 >> 1] = register(Interrupt, 0x3FFF, 0x3FFF);
+    table[0x202 >> 1] = register(Interrupt, 0x3FFF, 0x3FFF);
+    table[0x208 >> 1] = register(Interrupt, 0x0001, 0x0001);
+
+    table[0x204 >> 1] = register(System, 0xDFFF, 0x5FFF);
+    // POSTFLG and the write-only HALTCNT
+    table[0x300 >> 1] = register(System, 0x0001, 0x8001);
+
+    table
+}
+
+/// Source, destination and word count copied when a DMA channel gets enabled.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DmaStart {
+    pub source: u32,
+    pub destination: u32,
+    pub count: u16
+}
+
+/// The 0x04000000 I/O page: masks every access by its register and applies write side effects.
+pub struct IoRegisters {
+    registers: [u16; IO_SIZE / 2],
+    timer_reload: [u16; 4],
+    dma_start: [Option<DmaStart>; 4],
+    halt_requested: bool,
+    internal_memory_control: u32
+}
+
+impl Default for IoRegisters {
+    fn default() -> IoRegisters {
+        IoRegisters::new()
+    }
+}
+
+impl IoRegisters {
+    pub fn new() -> IoRegisters {
+        let mut registers = [0; IO_SIZE / 2];
+        registers[(KEYINPUT >> 1) as usize] = 0x03FF;
+        IoRegisters {
+            registers,
+            timer_reload: [0; 4],
+            dma_start: [None; 4],
+            halt_requested: false,
+            internal_memory_control: 0x0D00_0020
+        }
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        for &register in self.registers.iter().chain(&self.timer_reload) {
+            state.write_u16(register);
+        }
+        for dma_start in &self.dma_start {
+            state.write_bool(dma_start.is_some());
+            let DmaStart { source, destination, count } = dma_start.unwrap_or(DmaStart { source: 0, destination: 0, count: 0 });
+            state.write_u32(source);
+            state.write_u32(destination);
+            state.write_u16(count);
+        }
+        state.write_bool(self.halt_requested);
+        state.write_u32(self.internal_memory_control);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), GbaError> {
+        for register in self.registers.iter_mut().chain(&mut self.timer_reload) {
+            *register = state.read_u16()?;
+        }
+        for dma_start in &mut self.dma_start {
+            let pending = state.read_bool()?;
+            let start = DmaStart { source: state.read_u32()?, destination: state.read_u32()?, count: state.read_u16()? };
+            *dma_start = pending.then_some(start);
+        }
+        self.halt_requested = state.read_bool()?;
+        self.internal_memory_control = state.read_u32()?;
+        Ok(())
+    }
+
+    fn register(address: u32) -> Option<Register> {
+        let offset = (address & 0xFF_FFFF) as usize;
+        REGISTERS.get(offset >> 1).copied().flatten()
+    }
+
+    fn is_internal_memory_control(address: u32) -> bool {
+        address & 0xFFFC == INTERNAL_MEMORY_CONTROL
+    }
+
+    /// Reads `size` bytes as the CPU sees them, `None` if any of them is left floating.
+    pub fn read(&self, address: u32, size: usize) -> Option<u32> {
+        if size == 4 {
+            let low = self.read_halfword(address)? as u32;
+            let high = self.read_halfword(address + 2)? as u32;
+            return Some(low | high << 16);
+        }
+        let halfword = self.read_halfword(address & !1)? as u32;
+        Some(if size == 1 { (halfword >> ((address & 1) * 8)) & 0xFF } else { halfword })
+    }
+
+    fn read_halfword(&self, address: u32) -> Option<u16> {
+        if Self::is_internal_memory_control(address) {
+            return Some((self.internal_memory_control >> ((address & 2) * 8)) as u16);
+        }
+        let register = Self::register(address).filter(|register| register.read != 0)?;
+        Some(self.registers[((address & 0x3FF) >> 1) as usize] & register.read)
+    }
+
+    pub fn write(&mut self, address: u32, value: u32, size: usize) {
+        match size {
+            4 => {
+                self.write_halfword(address, value as u16, 0xFFFF);
+                self.write_halfword(address + 2, (value >> 16) as u16, 0xFFFF);
+            }
+            2 => self.write_halfword(address, value as u16, 0xFFFF),
+            _ => {
+                let shift = (address & 1) * 8;
+                self.write_halfword(address & !1, (value as u16 & 0xFF) << shift, 0xFF << shift);
+            }
+        }
+    }
+
+    // `lanes` marks the bytes actually written
+    fn write_halfword(&mut self, address: u32, value: u16, lanes: u16) {
+        if Self::is_internal_memory_control(address) {
+            let shift = (address & 2) * 8;
+            let mask = (lanes as u32) << shift & INTERNAL_MEMORY_CONTROL_MASK;
+            self.internal_memory_control = (self.internal_memory_control & !mask) | ((value as u32) << shift & mask);
+            return;
+        }
+        let Some(register) = Self::register(address) else {
+            return;
+        };
+        let offset = address & 0x3FF;
+        let mask = register.write & lanes;
+        match register.owner {
+            IoOwner::Interrupt => self.write_interrupt(offset, value, mask),
+            IoOwner::Dma => self.write_dma(offset, value, mask),
+            IoOwner::Timer => self.write_timer(offset, value, mask),
+            IoOwner::System => self.write_system(offset, value, mask, lanes),
+            IoOwner::Video | IoOwner::Sound | IoOwner::Serial | IoOwner::Keypad => self.store(offset, value, mask),
+        }
+    }
+
+    fn store(&mut self, offset: u32, value: u16, mask: u16) {
+        let register = &mut self.registers[(offset >> 1) as usize];
+        *register = (*register & !mask) | (value & mask);
+    }
+
+    fn write_interrupt(&mut self, offset: u32, value: u16, mask: u16) {
+        match offset {
+            // Acknowledging an interrupt clears the bits written as 1
+            IF => self.registers[(offset >> 1) as usize] &= !(value & mask),
+            _ => self.store(offset, value, mask),
+        }
+    }
+
+    fn write_dma(&mut self, offset: u32, value: u16, mask: u16) {
+        let enabled = self.registers[(offset >> 1) as usize] & DMA_ENABLE != 0;
+        self.store(offset, value, mask);
+        // The control register is the last of each channel's 12 bytes
+        if (offset - 0x0B0) % 12 == 10 && !enabled && self.registers[(offset >> 1) as usize] & DMA_ENABLE != 0 {
+            self.latch_dma(((offset - DMA0CNT_H) / 12) as usize);
+        }
+    }
+
+    fn latch_dma(&mut self, channel: usize) {
+        let base = (0x0B0 + channel * 12) >> 1;
+        let word = |index: usize| self.registers[index] as u32 | (self.registers[index + 1] as u32) << 16;
+        self.dma_start[channel] = Some(DmaStart {
+            source: word(base),
+            destination: word(base + 2),
+            count: self.registers[base + 4]
+        });
+    }
+
+    fn write_timer(&mut self, offset: u32, value: u16, mask: u16) {
+        let timer = ((offset - TM0CNT_L) / 4) as usize;
+        // Writes set the reload value, the counter is reloaded when the timer starts
+        if offset & 2 == 0 {
+            self.timer_reload[timer] = (self.timer_reload[timer] & !mask) | (value & mask);
+            return;
+        }
+        let enabled = self.registers[(offset >> 1) as usize] & TIMER_ENABLE != 0;
+        self.store(offset, value, mask);
+        if !enabled && self.registers[(offset >> 1) as usize] & TIMER_ENABLE != 0 {
+            self.registers[((offset - 2) >> 1) as usize] = self.timer_reload[timer];
+        }
+    }
+
+    fn write_system(&mut self, offset: u32, value: u16, mask: u16, lanes: u16) {
+        // Any write to HALTCNT halts the CPU, stop mode included
+        if offset == HALTCNT & !1 && lanes & 0xFF00 != 0 {
+            self.halt_requested = true;
+        }
+        self.store(offset, value, mask);
+    }
+
+    /// The transfer latched by the last enable of a DMA channel, if not taken yet.
+    pub fn take_dma_start(&mut self, channel: usize) -> Option<DmaStart> {
+        self.dma_start[channel].take()
+    }
+
+    /// Whether HALTCNT was written since the last call.
+    pub fn take_halt_request(&mut self) -> bool {
+        std::mem::take(&mut self.halt_requested)
+    }
+
+    /// Raw value of a register, for the hardware side.
+    pub fn get(&self, offset: u32) -> u16 {
+        self.registers[(offset >> 1) as usize]
+    }
+
+    /// Sets a register bypassing its CPU masks, for the hardware side.
+    pub fn set(&mut self, offset: u32, value: u16) {
+        self.registers[(offset >> 1) as usize] = value;
+    }
+}
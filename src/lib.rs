@@ -0,0 +1,38 @@
+//! A Game Boy Advance emulator core.
+//!
+//! [`Gba`] is the whole system: load a BIOS and a ROM, feed it the pressed [`Key`]s, run it a frame
+//! at a time and read the frame buffer back. Frontends live in their own binaries, the SDL one is
+//! built with the `sdl` feature.
+//!
+//! There is no sound emulation yet, so there are no audio samples to take: the sound registers read
+//! and write like memory and nothing plays them.
+
+pub mod archive;
+pub mod arm7;
+pub mod bus;
+pub mod cartridge;
+pub mod cheats;
+pub mod error;
+pub mod gba;
+pub mod input;
+pub mod io;
+pub mod memory;
+pub mod monitor;
+pub mod movie;
+pub mod patch;
+pub mod rewind;
+pub mod state;
+pub mod debugger;
+pub mod gdb;
+mod video;
+mod utils;
+mod scheduler;
+mod constants;
+
+pub use cartridge::RomInfo;
+pub use constants::{CYCLES_PER_FRAME, SCREEN_HEIGHT, SCREEN_WIDTH};
+pub use error::GbaError;
+pub use gba::Gba;
+pub use input::Key;
+pub use rewind::Rewind;
+pub use utils::crc32;
@@ -1,20 +1,37 @@
-use constants::{SCREEN_HEIGHT, SCREEN_WIDTH};
-use gba::Gba;
+use dees_nuts::arm7::tracer::{mode_from_name, CpuState, TraceConfig, TraceFormat, Tracer};
+use dees_nuts::cartridge::SaveType;
+use dees_nuts::cheats::{Cheat, CheatFormat};
+use dees_nuts::debugger::{Debugger, DebuggerAction};
+use dees_nuts::gdb::GdbStub;
+use dees_nuts::movie::{Movie, MovieMode, MovieSession};
+use dees_nuts::{Gba, GbaError, Key, Rewind, SCREEN_HEIGHT, SCREEN_WIDTH};
 use sdl2::render::TextureCreator;
 use sdl2::surface::Surface;
 use sdl2::video::{Window, WindowContext};
 use sdl2::{event::Event, render::Canvas};
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Mod};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::atomic::Ordering;
 use std::time::Instant;
-use std::{env, fs};
+use std::{env, fs, process};
 
-mod arm7;
-mod gba;
-mod memory;
-mod video;
-mod utils;
-mod scheduler;
-mod constants;
+fn key_for(keycode: Keycode) -> Option<Key> {
+    match keycode {
+        Keycode::X => Some(Key::A),
+        Keycode::Z => Some(Key::B),
+        Keycode::Backspace => Some(Key::Select),
+        Keycode::Return => Some(Key::Start),
+        Keycode::Right => Some(Key::Right),
+        Keycode::Left => Some(Key::Left),
+        Keycode::Up => Some(Key::Up),
+        Keycode::Down => Some(Key::Down),
+        Keycode::S => Some(Key::R),
+        Keycode::A => Some(Key::L),
+        _ => None
+    }
+}
 
 fn render(gba: &mut Gba, window: &mut Canvas<Window>, texture_creator: &TextureCreator<WindowContext>) {
     let memory_mut = gba.get_frame_buffer();
@@ -29,16 +46,183 @@ fn render(gba: &mut Gba, window: &mut Canvas<Window>, texture_creator: &TextureC
     window.present();
 }
 
+const SLOT_KEYS: [Keycode; 9] = [
+    Keycode::F1, Keycode::F2, Keycode::F3, Keycode::F4, Keycode::F5, Keycode::F6, Keycode::F7, Keycode::F8, Keycode::F9
+];
+
+fn state_path(rom_path: &str, slot: usize) -> String {
+    format!("{}.ss{}", rom_path, slot)
+}
+
+fn save_slot(gba: &Gba, rom_path: &str, slot: usize) {
+    match fs::write(state_path(rom_path, slot), gba.save_state()) {
+        Ok(()) => println!("Saved state {}", slot),
+        Err(error) => println!("Could not save state {}: {}", slot, error),
+    }
+}
+
+fn load_slot(gba: &mut Gba, rom_path: &str, slot: usize) {
+    let path = state_path(rom_path, slot);
+    let result = fs::read(&path)
+        .map_err(|source| GbaError::Io { path: path.into(), source })
+        .and_then(|state| gba.load_state(&state));
+    match result {
+        Ok(()) => println!("Loaded state {}", slot),
+        Err(error) => println!("Could not load state {}: {}", slot, error),
+    }
+}
+
+fn cheat_path(rom_path: &str) -> String {
+    format!("{}.cht", rom_path)
+}
+
+// FORMAT:CODE[,CODE...], raw codes keep their own colon
+fn parse_cheat(value: &str, number: usize) -> Result<Cheat, GbaError> {
+    let (format, codes) = value.split_once(':')
+        .ok_or_else(|| GbaError::InvalidArgument(format!("Expected FORMAT:CODES, got {}", value)))?;
+    let format = CheatFormat::from_name(format)
+        .ok_or_else(|| GbaError::InvalidArgument(format!("Unknown cheat format: {}", format)))?;
+    let codes: Vec<&str> = codes.split(',').collect();
+    Cheat::new(format!("Cheat {}", number), format, &codes)
+}
+
+fn parse_address(value: &str) -> Result<u32, GbaError> {
+    let digits = value.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(digits, 16).map_err(|_| GbaError::InvalidArgument(format!("Invalid address: {}", value)))
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str, what: &str) -> Result<T, GbaError> {
+    value.parse().map_err(|_| GbaError::InvalidArgument(format!("Invalid {}: {}", what, value)))
+}
+
+struct Options {
+    positional: Vec<String>,
+    // Entry to load when the ROM is a zip
+    rom_entry: Option<String>,
+    patch: Option<String>,
+    cheats: Vec<String>,
+    trace: Option<(String, TraceConfig)>,
+    debug: bool,
+    gdb_port: Option<u16>,
+    rewind_interval: usize,
+    rewind_budget: usize,
+    record: Option<String>,
+    play: Option<String>,
+    movie_hash_interval: usize
+}
+
+fn parse_args(args: &[String]) -> Result<Options, GbaError> {
+    let mut positional = Vec::new();
+    let mut rom_entry = None;
+    let mut patch = None;
+    let mut cheats = Vec::new();
+    let mut trace_path = None;
+    let mut config = TraceConfig::default();
+    let mut debug = false;
+    let mut gdb_port = None;
+    let mut rewind_interval = 10;
+    let mut rewind_budget = 64;
+    let mut record = None;
+    let mut play = None;
+    let mut movie_hash_interval = 60;
+    let program = &args[0];
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().ok_or_else(|| GbaError::InvalidArgument(format!("Missing value for {}", arg)));
+        match arg.as_str() {
+            "--rom-entry" => rom_entry = Some(value()?),
+            "--patch" => patch = Some(value()?),
+            "--cheat" => cheats.push(value()?),
+            "--trace" => trace_path = Some(value()?),
+            "--trace-format" => config.format = Some(TraceFormat::from_name(&value()?)?),
+            "--trace-pc" => {
+                let range = value()?;
+                let (start, end) = range.split_once('-').unwrap_or((&range, &range));
+                config.filter.pc_range = Some((parse_address(start)?, parse_address(end)?));
+            }
+            "--trace-mode" => config.filter.mode = Some(mode_from_name(&value()?)?),
+            "--trace-state" => {
+                config.filter.state = match value()?.as_str() {
+                    "arm" => Some(CpuState::Arm),
+                    "thumb" => Some(CpuState::Thumb),
+                    x => return Err(GbaError::InvalidArgument(format!("Unknown CPU state: {}", x))),
+                };
+            }
+            "--trace-ring" => config.ring_buffer = Some(parse_number(&value()?, "ring buffer size")?),
+            "--trace-start" => config.start_pc = Some(parse_address(&value()?)?),
+            "--trace-stop" => config.stop_pc = Some(parse_address(&value()?)?),
+            "--debug" => debug = true,
+            "--gdb" => gdb_port = Some(parse_number(&value()?, "GDB port")?),
+            "--rewind-interval" => rewind_interval = parse_number(&value()?, "rewind interval")?,
+            "--rewind-budget" => rewind_budget = parse_number(&value()?, "rewind budget")?,
+            "--record" => record = Some(value()?),
+            "--play" => play = Some(value()?),
+            "--movie-hash-interval" => movie_hash_interval = parse_number(&value()?, "movie hash interval")?,
+            _ => positional.push(arg.clone()),
+        }
+    }
+    if positional.len() < 2 {
+        return Err(GbaError::InvalidArgument(format!("Usage: {} <bios> <rom> [options]", program)));
+    }
+    Ok(Options {
+        positional,
+        rom_entry,
+        patch,
+        cheats,
+        trace: trace_path.map(|path| (path, config)),
+        debug,
+        gdb_port,
+        rewind_interval,
+        // In MiB
+        rewind_budget: rewind_budget << 20,
+        record,
+        play,
+        movie_hash_interval
+    })
+}
+
 fn main() {
+    if let Err(error) = run() {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), GbaError> {
     let args: Vec<String> = env::args().collect();
-    let path = &args[1];
-    let path2 = &args[2];
+    let options = parse_args(&args)?;
+    let mut gba = Gba::new();
+    gba.load_bios_file(&options.positional[0])?;
+    let rom_path = &options.positional[1];
+    let patch = gba.load_rom_file_with(rom_path, options.rom_entry.as_deref(), options.patch.as_deref().map(Path::new))?;
+    if let Some(patch) = patch {
+        println!("Applied {}", patch.display());
+    }
+    // Cheats from the command line that aren't in <rom>.cht yet are added to it on exit
+    let cheat_file = cheat_path(rom_path);
+    if Path::new(&cheat_file).is_file() {
+        gba.load_cheats_file(&cheat_file)?;
+        println!("Loaded {} cheats from {}", gba.cheats().list().len(), cheat_file);
+    }
+    let mut new_cheats = false;
+    for cheat in &options.cheats {
+        let cheat = parse_cheat(cheat, gba.cheats().list().len() + 1)?;
+        new_cheats |= gba.cheats_mut().add_if_new(cheat);
+    }
+    let rom_info = gba.rom_info();
+    if let Err(error) = rom_info.validate() {
+        eprintln!("Warning: {}", error);
+    }
+    if !matches!(rom_info.save_type, SaveType::None | SaveType::Sram) {
+        eprintln!("Warning: {:?} saves aren't supported yet", rom_info.save_type);
+    }
+    let caption = if rom_info.title.is_empty() { "GBA".to_string() } else { rom_info.title.clone() };
 
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
 
     let mut window = video_subsystem
-        .window("GBA", 240, 160)
+        .window(&caption, 240, 160)
         .build()
         .unwrap()
         .into_canvas()
@@ -49,18 +233,44 @@ fn main() {
 
     let mut event_pump = sdl_context.event_pump().unwrap();
 
-    let mut gba = Gba::new();
+    if let Some((trace_path, config)) = options.trace {
+        let file = File::create(&trace_path).map_err(|source| GbaError::Io { path: trace_path.into(), source })?;
+        gba.set_tracer(Some(Tracer::new(Box::new(BufWriter::new(file)), config)));
+    }
 
-    match fs::read(path) {
-        Ok(x) => gba.load_bios(x),
-        _ => panic!(),
+    if let Some(port) = options.gdb_port {
+        let mut stub = GdbStub::listen(port)
+            .map_err(|source| GbaError::Io { path: format!("GDB server on port {}", port).into(), source })?;
+        let mut on_frame = |gba: &mut Gba| {
+            for _ in event_pump.poll_iter() {}
+            render(gba, &mut window, &texture_creator);
+        };
+        match stub.run(&mut gba, &mut on_frame) {
+            Ok(true) => return Ok(()),
+            Ok(false) => println!("GDB detached"),
+            Err(error) => println!("GDB connection lost: {}", error),
+        }
     }
 
-    match fs::read(path2) {
-        Ok(x) => gba.load_rom(x),
-        _ => panic!(),
+    let mut debugger = options.debug.then(Debugger::new);
+    if let Some(debugger) = &debugger {
+        let interrupted = debugger.interrupt_flag();
+        ctrlc::set_handler(move || interrupted.store(true, Ordering::Relaxed))
+            .map_err(|error| GbaError::InvalidArgument(format!("Could not install the Ctrl-C handler: {}", error)))?;
     }
+    let mut rewind = Rewind::new(options.rewind_interval, options.rewind_budget);
+    let mut held_keys = 0;
+    let mut rewinding = false;
+    let mut movie = match (&options.play, &options.record) {
+        (Some(path), _) => {
+            let data = fs::read(path).map_err(|source| GbaError::Io { path: path.into(), source })?;
+            Some(MovieSession::play(Movie::from_bytes(&data)?, &mut gba)?)
+        }
+        (None, Some(_)) => Some(MovieSession::record(&gba, options.movie_hash_interval)),
+        (None, None) => None,
+    };
 
+    let mut outcome = Ok(());
     'running: loop {
         let start_time = Instant::now();
         for event in event_pump.poll_iter() {
@@ -70,11 +280,98 @@ fn main() {
                     keycode: Some(Keycode::Escape),
                     ..
                 } => break 'running,
+                Event::KeyDown { keycode: Some(Keycode::R), .. } => rewinding = true,
+                Event::KeyUp { keycode: Some(Keycode::R), .. } => rewinding = false,
+                // Takes over a playing movie from the current frame
+                Event::KeyDown { keycode: Some(Keycode::M), .. } => {
+                    if let Some(session) = movie.as_mut().filter(|session| session.mode() != MovieMode::Recording) {
+                        if options.record.is_some() {
+                            session.continue_recording();
+                            println!("Recording from frame {}", session.position());
+                        } else {
+                            println!("Pass --record <path> to take over the movie");
+                        }
+                    }
+                }
+                Event::KeyDown { keycode: Some(keycode), keymod, .. } => {
+                    if let Some(key) = key_for(keycode) {
+                        held_keys |= key.mask();
+                    }
+                    // F1-F9 load a slot, with shift held they save to it
+                    if let Some(slot) = SLOT_KEYS.iter().position(|&slot_key| slot_key == keycode) {
+                        if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                            save_slot(&gba, rom_path, slot + 1);
+                        } else if movie.is_some() {
+                            println!("States can't be loaded while a movie is active");
+                        } else {
+                            load_slot(&mut gba, rom_path, slot + 1);
+                            rewind.clear();
+                        }
+                    }
+                }
+                Event::KeyUp { keycode: Some(keycode), .. } => {
+                    if let Some(key) = key_for(keycode) {
+                        held_keys &= !key.mask();
+                    }
+                }
                 _ => (),
             }
         }
-        gba.frame();
+        match debugger.as_mut() {
+            Some(debugger) => {
+                // Any key or closing the window stops a running command
+                let interrupted = debugger.interrupt_flag();
+                let mut on_frame = |gba: &mut Gba| {
+                    for event in event_pump.poll_iter() {
+                        if matches!(event, Event::KeyDown { .. } | Event::Quit { .. }) {
+                            interrupted.store(true, Ordering::Relaxed);
+                        }
+                    }
+                    render(gba, &mut window, &texture_creator);
+                };
+                if let DebuggerAction::Quit = debugger.prompt(&mut gba, &mut on_frame) {
+                    break 'running;
+                }
+            }
+            None => match movie.as_mut() {
+                Some(session) => {
+                    if let Err(error) = session.frame(&mut gba, held_keys) {
+                        outcome = Err(error);
+                        break 'running;
+                    }
+                }
+                None if rewinding => {
+                    // The history past a snapshot that won't load is no good either
+                    if let Err(error) = rewind.rewind(&mut gba) {
+                        println!("Rewind failed: {}", error);
+                        rewind.clear();
+                        rewinding = false;
+                    }
+                }
+                None => {
+                    gba.set_keys(held_keys);
+                    if let Err(error) = gba.frame() {
+                        outcome = Err(error);
+                        break 'running;
+                    }
+                    rewind.record(&gba);
+                }
+            },
+        }
         render(&mut gba, &mut window, &texture_creator);
         println!("{:#?}", start_time.elapsed());
     }
+    // A fault or a desync still keeps the recording and the new cheats, its error comes first
+    let saved = save_on_exit(&gba, new_cheats.then_some(&cheat_file), movie, options.record);
+    outcome.and(saved)
+}
+
+fn save_on_exit(gba: &Gba, cheat_file: Option<&String>, movie: Option<MovieSession>, record: Option<String>) -> Result<(), GbaError> {
+    if let Some(cheat_file) = cheat_file {
+        gba.save_cheats_file(cheat_file)?;
+    }
+    if let (Some(session), Some(path)) = (movie.filter(|session| session.mode() == MovieMode::Recording), record) {
+        fs::write(&path, session.into_movie().to_bytes()).map_err(|source| GbaError::Io { path: path.into(), source })?;
+    }
+    Ok(())
 }
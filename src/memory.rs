@@ -1,6 +1,8 @@
-use std::{cell::RefCell, ops::{Index, Range, IndexMut}, rc::Rc};
-
-use crate::scheduler::Scheduler;
+use crate::bus::Bus;
+use crate::error::GbaError;
+use crate::state::{StateReader, StateWriter};
+use crate::io::{IoRegisters, DISPCNT, WAITCNT};
+use crate::monitor::{AccessKind, BusMonitor, MemoryAccess, WatchpointHit};
 
 const BIOS_ADDRESS: usize = 0x00000000;
 const BIOS_END: usize = 0x00003FFF;
@@ -9,7 +11,7 @@ const EWRAM_END: usize = 0x0203FFFF;
 const IWRAM_ADDRESS: usize = 0x03000000;
 const IWRAM_END: usize = 0x03007FFF;
 const IO_REGISTERS: usize = 0x04000000;
-const IO_REGISTERS_END: usize = 0x040003FE;
+const IO_REGISTERS_END: usize = 0x040003FF;
 const PALLETE_RAM_ADDRESS: usize = 0x05000000;
 const PALLETE_RAM_END: usize = 0x050003FF;
 const VRAM_ADDRESS: usize = 0x06000000;
@@ -17,12 +19,57 @@ const VRAM_END: usize = 0x06017FFF;
 const OAM_ADRESS: usize = 0x07000000;
 const OAM_END: usize = 0x070003FF;
 const ROM_ADDRESS: usize = 0x08000000;
-const ROM_END: usize = 0x09FFFFFF;
+const ROM_END: usize = 0x0DFFFFFF;
 const SRAM_ADDRESS: usize = 0x0E000000;
 const SRAM_END: usize = 0x0E00FFFF;
 
-const WAITCNT: u32 = 0x4000204;
+pub(crate) const MAX_ROM_SIZE: usize = 0x200_0000;
+pub(crate) const MAX_MULTIBOOT_SIZE: usize = EWRAM_END - EWRAM_ADDRESS + 1;
+// 32 KiB SRAM and 64 KiB flash saves
+const SAVE_SIZES: [usize; 2] = [0x8000, 0x10000];
+
+// Name, first and last address of every region backed by memory
+pub const MEMORY_REGIONS: [(&str, usize, usize); 9] = [
+    ("bios", BIOS_ADDRESS, BIOS_END),
+    ("ewram", EWRAM_ADDRESS, EWRAM_END),
+    ("iwram", IWRAM_ADDRESS, IWRAM_END),
+    ("io", IO_REGISTERS, IO_REGISTERS_END),
+    ("palette", PALLETE_RAM_ADDRESS, PALLETE_RAM_END),
+    ("vram", VRAM_ADDRESS, VRAM_END),
+    ("oam", OAM_ADRESS, OAM_END),
+    ("rom", ROM_ADDRESS, ROM_END),
+    ("sram", SRAM_ADDRESS, SRAM_END),
+];
+
+/// Index into `MEMORY_REGIONS` of the region an address (or one of its mirrors) belongs to.
+pub fn region_index(address: u32) -> Option<usize> {
+    match address >> 24 {
+        0x0 if address as usize <= BIOS_END => Some(0),
+        0x2 => Some(1),
+        0x3 => Some(2),
+        0x4 => Some(3),
+        0x5 => Some(4),
+        0x6 => Some(5),
+        0x7 => Some(6),
+        0x8..=0xD => Some(7),
+        0xE | 0xF => Some(8),
+        _ => None,
+    }
+}
 
+#[derive(Clone, Copy, PartialEq)]
+enum Region {
+    Bios,
+    Ewram,
+    Iwram,
+    Pallete,
+    Vram,
+    Oam,
+    Rom,
+    Sram
+}
+
+// Wait states added to the single cycle of a cartridge access, indexed by the WAITCNT fields
 struct RomCycleCount {
     non_sequential: [usize; 4],
     sequential: [usize; 2]
@@ -54,17 +101,72 @@ const WS2_ROM_CYCLE_COUNT: RomCycleCount = RomCycleCount {
     sequential: [8, 1],
 };
 
+const SRAM_WAIT_STATES: [usize; 4] = [4, 3, 2, 8];
+
+const CODE_PAGE_SHIFT: usize = 8;
+const EWRAM_CODE_PAGES: usize = 0x40000 >> CODE_PAGE_SHIFT;
+const IWRAM_CODE_PAGES: usize = 0x8000 >> CODE_PAGE_SHIFT;
+const VRAM_CODE_PAGES: usize = 0x18000 >> CODE_PAGE_SHIFT;
+
+const PREFETCH_ENABLE: u16 = 0x4000;
+const PREFETCH_CAPACITY: usize = 8;
+
+// Game Pak prefetch buffer, loads the halfwords following the last opcode fetch from ROM
+#[derive(Default)]
+struct Prefetch {
+    active: bool,
+    // Address of the oldest buffered halfword
+    head: u32,
+    count: usize,
+    // Cycles already spent on the halfword after the buffered ones
+    progress: usize
+}
+
+/// Where cached decoded code comes from, writable pages are watched for self-modifying code.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CodePage {
+    ReadOnly,
+    Writable(usize)
+}
+
+/// Bus cycle type of an access, sequential ones continue a burst from the previous address.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Access {
+    NonSequential,
+    Sequential
+}
+
 pub struct Memory {
     bios: Vec<u8>,
     ewram: Vec<u8>,
     iwram: Vec<u8>,
-    io_registers: Vec<u8>,
+    io: IoRegisters,
     pallete_ram: Vec<u8>,
     vram: Vec<u8>,
     oam: Vec<u8>,
     rom: Vec<u8>,
-    last_read: [u32; 3],
-    clock: usize
+    sram: Vec<u8>,
+    prefetch: Prefetch,
+    clock: usize,
+    // Address and state of the last opcode fetch, the executing instruction is derived from it
+    fetch_address: u32,
+    fetch_thumb: bool,
+    // Last opcode fetched from the BIOS, returned for BIOS reads made from outside of it
+    bios_latch: u32,
+    // Writable pages the CPU has cached decoded code from
+    code_pages: Vec<bool>,
+    invalidated_code_pages: Vec<usize>,
+    // Set when read-only memory changed under the code cache
+    code_flush: bool,
+    // WAITCNT the cached code was timed with
+    code_waitcnt: u16,
+    monitor: Option<Box<BusMonitor>>
+}
+
+impl Default for Memory {
+    fn default() -> Memory {
+        Memory::new()
+    }
 }
 
 impl Memory {
@@ -73,209 +175,607 @@ impl Memory {
             bios: vec![0; 0x4000],
             ewram: vec![0; 0x40000],
             iwram: vec![0; 0x8000],
-            io_registers: vec![0; 0x3FF],
+            io: IoRegisters::new(),
             pallete_ram: vec![0; 0x400],
             vram: vec![0; 0x18000],
             oam: vec![0; 0x400],
             rom: Vec::new(),
-            last_read: [0xFFFF_FFFF, 0xFFFF_FFFF, 0xFFFF_FFFF],
-            clock: 0
+            sram: vec![0xFF; 0x10000],
+            prefetch: Prefetch::default(),
+            clock: 0,
+            fetch_address: 0,
+            fetch_thumb: false,
+            bios_latch: 0,
+            code_pages: vec![false; EWRAM_CODE_PAGES + IWRAM_CODE_PAGES + VRAM_CODE_PAGES],
+            invalidated_code_pages: Vec::new(),
+            code_flush: false,
+            code_waitcnt: 0,
+            monitor: None
         }
     }
 
-    pub fn load_bios(&mut self, bios: Vec<u8>) {
+    pub fn load_bios(&mut self, bios: Vec<u8>) -> Result<(), GbaError> {
+        if bios.len() != self.bios.len() {
+            return Err(GbaError::InvalidBiosSize(bios.len()));
+        }
         self.bios.copy_from_slice(&bios);
+        self.code_flush = true;
+        Ok(())
     }
 
-    pub fn load_rom(&mut self, rom: Vec<u8>) {
+    pub fn load_rom(&mut self, rom: Vec<u8>) -> Result<(), GbaError> {
+        if rom.is_empty() {
+            return Err(GbaError::EmptyRom);
+        }
+        if rom.len() > MAX_ROM_SIZE {
+            return Err(GbaError::RomTooLarge(rom.len()));
+        }
         self.rom = rom;
+        self.code_flush = true;
+        Ok(())
     }
 
-    pub fn get_byte(&mut self, address: u32, clock_count: bool) -> u8 {
-        if clock_count {
-            self.update_clock_cycles(address, 0);
+    /// Multiboot images run from EWRAM, the ROM region stays empty.
+    pub fn load_multiboot(&mut self, image: &[u8]) -> Result<(), GbaError> {
+        if image.is_empty() {
+            return Err(GbaError::EmptyRom);
+        }
+        if image.len() > MAX_MULTIBOOT_SIZE {
+            return Err(GbaError::MultibootTooLarge(image.len()));
         }
-        self[address as usize]
+        self.ewram[..image.len()].copy_from_slice(image);
+        self.rom = Vec::new();
+        self.code_flush = true;
+        Ok(())
     }
 
-    pub fn get_halfword(&mut self, address: u32, clock_count: bool) -> u16 {
-        if clock_count {
-            self.update_clock_cycles(address, 1);
+    pub fn load_save(&mut self, save: Vec<u8>) -> Result<(), GbaError> {
+        if !SAVE_SIZES.contains(&save.len()) {
+            return Err(GbaError::InvalidSaveSize(save.len()));
         }
-        u16::from_le_bytes(self[address as usize..address as usize + 2].try_into().unwrap())
+        self.sram[..save.len()].copy_from_slice(&save);
+        Ok(())
+    }
+
+    pub fn save_data(&self) -> &[u8] {
+        &self.sram
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
     }
 
-    pub fn get_word(&mut self, address: u32, clock_count: bool) -> u32 {
-        if clock_count {
-            self.update_clock_cycles(address, 2);
+    // The BIOS and ROM are not part of the state, they are loaded separately
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        for region in [&self.ewram, &self.iwram, &self.pallete_ram, &self.vram, &self.oam, &self.sram] {
+            state.write_bytes(region);
         }
-        u32::from_le_bytes(self[address as usize..address as usize + 4].try_into().unwrap())
+        self.io.save_state(state);
+        state.write_bool(self.prefetch.active);
+        state.write_u32(self.prefetch.head);
+        state.write_usize(self.prefetch.count);
+        state.write_usize(self.prefetch.progress);
+        state.write_usize(self.clock);
+        state.write_u32(self.fetch_address);
+        state.write_bool(self.fetch_thumb);
+        state.write_u32(self.bios_latch);
     }
 
-    pub fn store_byte(&mut self, address: u32, value: u8, clock_count: bool) {
-        if clock_count {
-            self.update_clock_cycles(address, 0);
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), GbaError> {
+        for region in [
+            &mut self.ewram,
+            &mut self.iwram,
+            &mut self.pallete_ram,
+            &mut self.vram,
+            &mut self.oam,
+            &mut self.sram
+        ] {
+            state.read_bytes_into(region)?;
         }
-        self[address as usize] = value;
+        self.io.load_state(state)?;
+        self.prefetch = Prefetch {
+            active: state.read_bool()?,
+            head: state.read_u32()?,
+            count: state.read_usize()?.min(PREFETCH_CAPACITY),
+            progress: state.read_usize()?
+        };
+        self.clock = state.read_usize()?;
+        self.fetch_address = state.read_u32()?;
+        self.fetch_thumb = state.read_bool()?;
+        self.bios_latch = state.read_u32()?;
+        self.code_flush = true;
+        Ok(())
     }
 
-    pub fn store_halfword(&mut self, address: u32, value: u16, clock_count: bool) {
-        if clock_count {
-            self.update_clock_cycles(address, 1);
+    /// Address of the instruction currently executing, derived from the pipeline's last fetch.
+    pub fn executing_pc(&self) -> u32 {
+        if self.fetch_thumb {
+            self.fetch_address.wrapping_sub(4)
+        } else {
+            self.fetch_address.wrapping_sub(8)
         }
-        let address_idx = address as usize;
-        self[address_idx..address_idx + 2].copy_from_slice(&value.to_le_bytes());
     }
 
-    pub fn store_word(&mut self, address: u32, value: u32, clock_count: bool) {
-        if clock_count {
-            self.update_clock_cycles(address, 2);
+    fn monitor_access(&mut self, kind: AccessKind, address: u32, width: u8, value: u32) {
+        let access = MemoryAccess { kind, address, width, value, pc: self.executing_pc(), cycle: self.clock };
+        self.monitor.as_mut().unwrap().record(access);
+    }
+
+    /// Reports an instruction about to execute to the execute watchpoints.
+    pub fn check_execute(&mut self, address: u32, opcode: u32, thumb: bool) {
+        if let Some(monitor) = self.monitor.as_mut() {
+            let width = if thumb { 2 } else { 4 };
+            monitor.record(MemoryAccess { kind: AccessKind::Execute, address, width, value: opcode, pc: address, cycle: self.clock });
         }
-        let address_idx = address as usize;
-        self[address_idx..address_idx + 4].copy_from_slice(&value.to_le_bytes());
     }
 
-    pub fn add_clock_cycles(&mut self, cycles: usize) {
-        self.clock += cycles;
+    pub fn take_watchpoint_hit(&mut self) -> Option<WatchpointHit> {
+        self.monitor.as_mut().and_then(|monitor| monitor.take_hit())
+    }
+
+    /// The watchpoint and access log state, created on first use.
+    pub fn monitor(&mut self) -> &mut BusMonitor {
+        self.monitor.get_or_insert_with(|| Box::new(BusMonitor::new()))
+    }
+
+    fn prefetch_enabled(&self) -> bool {
+        self.io.get(WAITCNT) & PREFETCH_ENABLE != 0
     }
 
-    fn update_clock_cycles(&mut self, address: u32, bit_count: usize) {
-        let is_32 = if bit_count == 32 {
-            2
+    fn fetch_cycles(&mut self, address: u32, bit_count: usize, access: Access) {
+        if !self.prefetch_enabled() || !(0x8..=0xD).contains(&(address >> 24)) {
+            self.update_clock_cycles(address, bit_count, access);
+            return;
+        }
+        let halfwords = if bit_count == 2 { 2 } else { 1 };
+        if self.prefetch.active && self.prefetch.head == address {
+            // Wait for the halfwords still being loaded, a buffered opcode takes a single cycle
+            let (_, sequential_cycles) = self.rom_cycles(address);
+            while self.prefetch.count < halfwords {
+                self.clock += sequential_cycles - self.prefetch.progress;
+                self.prefetch.progress = 0;
+                self.prefetch.count += 1;
+            }
+            self.prefetch.count -= halfwords;
+            self.prefetch.head = address.wrapping_add(halfwords as u32 * 2);
+            self.clock += 1;
+            self.advance_prefetch(1);
         } else {
-            1
+            // Branches and interrupted bursts restart the buffer behind this fetch
+            self.update_clock_cycles(address, bit_count, access);
+            self.prefetch = Prefetch {
+                active: true,
+                head: address.wrapping_add(halfwords as u32 * 2),
+                count: 0,
+                progress: 0
+            };
+        }
+    }
+
+    fn advance_prefetch(&mut self, cycles: usize) {
+        if !self.prefetch.active || !self.prefetch_enabled() {
+            return;
+        }
+        let loading = self.prefetch.head.wrapping_add(self.prefetch.count as u32 * 2);
+        let (_, sequential_cycles) = self.rom_cycles(loading);
+        self.prefetch.progress += cycles;
+        while self.prefetch.progress >= sequential_cycles && self.prefetch.count < PREFETCH_CAPACITY {
+            self.prefetch.progress -= sequential_cycles;
+            self.prefetch.count += 1;
+        }
+        if self.prefetch.count == PREFETCH_CAPACITY {
+            self.prefetch.progress = 0;
+        }
+    }
+
+    // Non-sequential and sequential cycles of a 16 bit cartridge access
+    fn rom_cycles(&self, address: u32) -> (usize, usize) {
+        let waitcnt = self.io.get(WAITCNT) as usize;
+        let (cycle_count, shift) = match address >> 24 {
+            0x8 | 0x9 => (&WS0_ROM_CYCLE_COUNT, 2),
+            0xA | 0xB => (&WS1_ROM_CYCLE_COUNT, 5),
+            _ => (&WS2_ROM_CYCLE_COUNT, 8),
         };
-        match (address >> 24) & 0xF {
-            0x0 => self.clock += BIOS_CYCLE_COUNT[bit_count],
-            0x2 => self.clock += EWRAM_CYCLE_COUNT[bit_count],
-            0x3 => self.clock += IWRAM_CYCLE_COUNT[bit_count],
-            0x4 => self.clock += IO_CYCLE_COUNT[bit_count],
-            0x5 => self.clock += PALLETE_CYCLE_COUNT[bit_count],
-            0x6 => self.clock += VRAM_CYCLE_COUNT[bit_count],
-            0x7 => self.clock += OAM_CYCLE_COUNT[bit_count],
-            0x8 | 0x9 => {
-                if self.last_read.contains(&(address & !0x3)) {
-                    let waitstate = (self.get_halfword(WAITCNT, false) & 0x10) >> 3;
-                    self.clock += is_32 * WS0_ROM_CYCLE_COUNT.sequential[waitstate as usize];
-                } else {
-                    let waitstate = (self.get_halfword(WAITCNT, false) & 0xC) >> 2;
-                    self.clock += WS0_ROM_CYCLE_COUNT.non_sequential[waitstate as usize] + (is_32 - 1) * WS0_ROM_CYCLE_COUNT.sequential[waitstate as usize];
-                }
-            },
-            0xA | 0xB => {
-                if self.last_read.contains(&(address & !0x3)) {
-                    let waitstate = (self.get_halfword(WAITCNT, false) & 0x10) >> 3;
-                    self.clock += is_32 * WS1_ROM_CYCLE_COUNT.sequential[waitstate as usize];
+        (
+            1 + cycle_count.non_sequential[(waitcnt >> shift) & 0x3],
+            1 + cycle_count.sequential[(waitcnt >> (shift + 2)) & 0x1]
+        )
+    }
+
+    fn update_clock_cycles(&mut self, address: u32, bit_count: usize, access: Access) {
+        let cycles = self.access_cycles(address, bit_count, access);
+        self.add_access_cycles(address, cycles);
+    }
+
+    fn access_cycles(&self, address: u32, bit_count: usize, access: Access) -> usize {
+        match address >> 24 {
+            0x0 => BIOS_CYCLE_COUNT[bit_count],
+            0x2 => EWRAM_CYCLE_COUNT[bit_count],
+            0x3 => IWRAM_CYCLE_COUNT[bit_count],
+            0x4 => IO_CYCLE_COUNT[bit_count],
+            0x5 => PALLETE_CYCLE_COUNT[bit_count],
+            0x6 => VRAM_CYCLE_COUNT[bit_count],
+            0x7 => OAM_CYCLE_COUNT[bit_count],
+            0x8..=0xD => {
+                let (non_sequential_cycles, sequential_cycles) = self.rom_cycles(address);
+                // Bursts restart at every 128 KiB page
+                let first = if access == Access::Sequential && address & 0x1_FFFF != 0 {
+                    sequential_cycles
                 } else {
-                    let waitstate = (self.get_halfword(WAITCNT, false) & 0xC) >> 2;
-                    self.clock += WS1_ROM_CYCLE_COUNT.non_sequential[waitstate as usize] + (is_32 - 1) * WS0_ROM_CYCLE_COUNT.sequential[waitstate as usize];
-                }
-            }
-            0xC | 0xD => {
-                if self.last_read.contains(&(address & !0x3)) {
-                    let waitstate = (self.get_halfword(WAITCNT, false) & 0x10) >> 3;
-                    self.clock += is_32 * WS2_ROM_CYCLE_COUNT.sequential[waitstate as usize];
+                    non_sequential_cycles
+                };
+                // The cartridge bus is 16 bit, words take a second sequential access
+                if bit_count == 2 {
+                    first + sequential_cycles
                 } else {
-                    let waitstate = (self.get_halfword(WAITCNT, false) & 0xC) >> 2;
-                    self.clock += WS2_ROM_CYCLE_COUNT.non_sequential[waitstate as usize] + (is_32 - 1) * WS0_ROM_CYCLE_COUNT.sequential[waitstate as usize];
+                    first
                 }
             }
-            _ => self.clock += 1
+            0xE | 0xF => 1 + SRAM_WAIT_STATES[self.io.get(WAITCNT) as usize & 0x3],
+            _ => 1
+        }
+    }
+
+    fn add_access_cycles(&mut self, address: u32, cycles: usize) {
+        self.clock += cycles;
+        if (0x8..=0xD).contains(&(address >> 24)) {
+            // A data access takes over the cartridge bus and stops the buffer
+            self.prefetch.active = false;
+        } else {
+            self.advance_prefetch(cycles);
         }
-        self.last_read = [address & !0x3, (address & !0x3).saturating_add(4), (address & !0x3).saturating_sub(4)];
     }
 
     pub fn get_clock_cycles(&self) -> usize {
         self.clock
     }
-}
 
-impl Index<usize> for Memory {
-    type Output = u8;
+    pub fn io(&self) -> &IoRegisters {
+        &self.io
+    }
+
+    pub fn io_mut(&mut self) -> &mut IoRegisters {
+        &mut self.io
+    }
+
+    pub fn vram(&self) -> &[u8] {
+        &self.vram
+    }
+
+    pub fn pallete_ram(&self) -> &[u8] {
+        &self.pallete_ram
+    }
 
-    fn index(&self, index: usize) -> &Self::Output {
-        let usable_bits = index & 0xFF_FFFF;
-        match index {
-            BIOS_ADDRESS..=BIOS_END => &self.bios[usable_bits],
-            EWRAM_ADDRESS..=EWRAM_END => &self.ewram[usable_bits],
-            IWRAM_ADDRESS..=IWRAM_END => &self.iwram[usable_bits],
-            IO_REGISTERS..=IO_REGISTERS_END => &self.io_registers[usable_bits],
-            PALLETE_RAM_ADDRESS..=PALLETE_RAM_END => &self.pallete_ram[usable_bits],
-            VRAM_ADDRESS..=VRAM_END => &self.vram[usable_bits],
-            OAM_ADRESS..=OAM_END =>  &self.oam[usable_bits],
-            ROM_ADDRESS..=ROM_END => &self.rom[usable_bits],
-            SRAM_ADDRESS..=SRAM_END => todo!(),
-            _ => panic!("Invalid memory address: {:#X}", index),
+    fn decode_address(&self, address: u32) -> Option<(Region, usize)> {
+        let offset = address as usize & 0xFF_FFFF;
+        match address >> 24 {
+            0x0 if offset <= BIOS_END => Some((Region::Bios, offset)),
+            0x2 => Some((Region::Ewram, offset & 0x3_FFFF)),
+            0x3 => Some((Region::Iwram, offset & 0x7FFF)),
+            0x5 => Some((Region::Pallete, offset & 0x3FF)),
+            0x6 => {
+                // 96 KiB repeated every 128 KiB, the upper 32 KiB of each block mirror the OBJ tiles
+                let offset = offset & 0x1_FFFF;
+                Some((Region::Vram, if offset >= 0x1_8000 { offset - 0x8000 } else { offset }))
+            }
+            0x7 => Some((Region::Oam, offset & 0x3FF)),
+            0x8..=0xD if (address as usize & 0x1FF_FFFF) < self.rom.len() => {
+                Some((Region::Rom, address as usize & 0x1FF_FFFF))
+            }
+            0xE | 0xF => Some((Region::Sram, offset & 0xFFFF)),
+            _ => None,
         }
     }
-}
 
-impl IndexMut<usize> for Memory {
-    fn index_mut(&mut self, index: usize) -> &mut u8 {
-        let masked_index = index & 0xFF_FFFF;
-        match index {
-            BIOS_ADDRESS..=BIOS_END => &mut self.bios[masked_index],
-            EWRAM_ADDRESS..=EWRAM_END => &mut self.ewram[masked_index],
-            IWRAM_ADDRESS..=IWRAM_END => &mut self.iwram[masked_index],
-            IO_REGISTERS..=IO_REGISTERS_END => &mut self.io_registers[masked_index],
-            PALLETE_RAM_ADDRESS..=PALLETE_RAM_END => &mut self.pallete_ram[masked_index],
-            VRAM_ADDRESS..=VRAM_END => &mut self.vram[masked_index],
-            OAM_ADRESS..=OAM_END => &mut self.oam[masked_index],
-            ROM_ADDRESS..=ROM_END => &mut self.rom[masked_index],
-            SRAM_ADDRESS..=SRAM_END => todo!(),
-            _ => panic!("Invalid memory address: {:#X}", index),
+    fn region(&self, region: Region) -> &[u8] {
+        match region {
+            Region::Bios => &self.bios,
+            Region::Ewram => &self.ewram,
+            Region::Iwram => &self.iwram,
+            Region::Pallete => &self.pallete_ram,
+            Region::Vram => &self.vram,
+            Region::Oam => &self.oam,
+            Region::Rom => &self.rom,
+            Region::Sram => &self.sram,
         }
     }
-}
 
-impl Index<Range<usize>> for Memory {
-    type Output = [u8];
-
-    fn index(&self, index: Range<usize>) -> &Self::Output {
-        let min = index.start & 0xFF_FFFF;
-        let max = index.end.saturating_sub(1) & 0xFF_FFFF;
-        match index.start {
-            BIOS_ADDRESS..=BIOS_END => &self.bios[min..=max],
-            EWRAM_ADDRESS..=EWRAM_END => &self.ewram[min..=max],
-            IWRAM_ADDRESS..=IWRAM_END => &self.iwram[min..=max],
-            IO_REGISTERS..=IO_REGISTERS_END => &self.io_registers[min..=max],
-            PALLETE_RAM_ADDRESS..=PALLETE_RAM_END => &self.pallete_ram[min..=max],
-            VRAM_ADDRESS..=VRAM_END => &self.vram[min..=max],
-            OAM_ADRESS..=OAM_END => &self.oam[min..=max],
-            ROM_ADDRESS..=ROM_END => {
-                if max < self.rom.len() {
-                    &self.rom[min..=max]
-                } else {
-                    &self.rom[0..=max-min]
+    fn region_mut(&mut self, region: Region) -> &mut [u8] {
+        match region {
+            Region::Bios => &mut self.bios,
+            Region::Ewram => &mut self.ewram,
+            Region::Iwram => &mut self.iwram,
+            Region::Pallete => &mut self.pallete_ram,
+            Region::Vram => &mut self.vram,
+            Region::Oam => &mut self.oam,
+            Region::Rom => &mut self.rom,
+            Region::Sram => &mut self.sram,
+        }
+    }
+
+    // `size` bytes at a mirrored address, `None` if nothing backs them
+    fn read_raw(&self, address: u32, size: usize) -> Option<u32> {
+        if address >> 24 == 0x4 {
+            return self.io.read(address, size);
+        }
+        let (region, offset) = self.decode_address(address)?;
+        if region == Region::Sram {
+            // 8 bit bus, wider reads see the same byte on every lane
+            return Some((self.sram[offset] as u32 * 0x0101_0101) & (u32::MAX >> (32 - size * 8)));
+        }
+        let bytes = self.region(region).get(offset..offset + size)?;
+        Some(bytes.iter().rev().fold(0, |value, byte| (value << 8) | *byte as u32))
+    }
+
+    fn read_bus(&self, address: u32, size: usize) -> u32 {
+        // Only the unmapped half of an I/O word floats
+        if address >> 24 == 0x4 && size == 4 && self.io.read(address, 4).is_none() {
+            let open_bus = self.open_bus();
+            let low = self.io.read(address, 2).unwrap_or(open_bus & 0xFFFF);
+            let high = self.io.read(address + 2, 2).unwrap_or(open_bus >> 16);
+            return low | high << 16;
+        }
+        match self.read_raw(address, size) {
+            Some(value) => value,
+            None => {
+                let word = match address >> 24 {
+                    // Past the end of the cartridge the bus still holds the halfword address
+                    0x8..=0xD => {
+                        let address = address & !3;
+                        ((address >> 1) & 0xFFFF) | (((address + 2) >> 1) & 0xFFFF) << 16
+                    }
+                    _ => self.open_bus(),
+                };
+                (word >> ((address & 3) * 8)) & (u32::MAX >> (32 - size * 8))
+            }
+        }
+    }
+
+    fn read(&self, address: u32, size: usize) -> u32 {
+        // The BIOS can only be read while executing from it
+        if address as usize <= BIOS_END && self.executing_pc() as usize > BIOS_END {
+            return (self.bios_latch >> ((address & 3) * 8)) & (u32::MAX >> (32 - size * 8));
+        }
+        self.read_bus(address, size)
+    }
+
+    /// The value left on the bus by the prefetch, seen by reads of unmapped addresses.
+    fn open_bus(&self) -> u32 {
+        if !self.fetch_thumb {
+            return self.read_raw(self.fetch_address, 4).unwrap_or(0);
+        }
+        let pc = self.executing_pc();
+        let halfword = |offset: u32| self.read_raw(pc.wrapping_add(offset), 2).unwrap_or(0);
+        let (low, high) = match pc >> 24 {
+            // 32 bit buses keep both halves of the last prefetched word
+            0x0 | 0x7 if pc & 2 == 0 => (halfword(4), halfword(6)),
+            0x0 | 0x7 => (halfword(2), halfword(4)),
+            0x3 if pc & 2 == 0 => (halfword(4), halfword(2)),
+            0x3 => (halfword(2), halfword(4)),
+            _ => (halfword(4), halfword(4)),
+        };
+        low | high << 16
+    }
+
+    fn write(&mut self, address: u32, value: u32, size: usize) {
+        if address >> 24 == 0x4 {
+            self.io.write(address, value, size);
+            return;
+        }
+        match self.decode_address(address) {
+            // BIOS and ROM are read only, unmapped writes go nowhere
+            None | Some((Region::Bios | Region::Rom, _)) => (),
+            Some((Region::Sram, offset)) => self.sram[offset] = (value >> ((address & 3) * 8)) as u8,
+            // Byte stores on the 16 bit video buses either fill the whole halfword or are dropped
+            Some((region @ (Region::Pallete | Region::Vram | Region::Oam), offset)) if size == 1 => {
+                let ignored = match region {
+                    Region::Oam => true,
+                    Region::Vram => offset >= self.obj_vram_start(),
+                    _ => false,
+                };
+                if !ignored {
+                    let offset = offset & !1;
+                    self.region_mut(region)[offset..offset + 2].fill(value as u8);
+                    self.invalidate_code(region, offset);
                 }
-            },
-            SRAM_ADDRESS..=SRAM_END => todo!(),
-            _ => panic!("Invalid memory address: {:#X}", index.start),
+            }
+            Some((region, offset)) => {
+                if let Some(bytes) = self.region_mut(region).get_mut(offset..offset + size) {
+                    bytes.copy_from_slice(&value.to_le_bytes()[..size]);
+                    self.invalidate_code(region, offset);
+                }
+            }
         }
     }
-}
 
-impl IndexMut<Range<usize>> for Memory {
-    fn index_mut(&mut self, index: Range<usize>) -> &mut [u8] {
-        let min = index.start & 0xFF_FFFF;
-        let max = index.end.saturating_sub(1) & 0xFF_FFFF;
-        match index.start {
-            BIOS_ADDRESS..=BIOS_END => &mut self.bios[min..=max],
-            EWRAM_ADDRESS..=EWRAM_END => &mut self.ewram[min..=max],
-            IWRAM_ADDRESS..=IWRAM_END => &mut self.iwram[min..=max],
-            IO_REGISTERS..=IO_REGISTERS_END => &mut self.io_registers[min..=max],
-            PALLETE_RAM_ADDRESS..=PALLETE_RAM_END => &mut self.pallete_ram[min..=max],
-            VRAM_ADDRESS..=VRAM_END => &mut self.vram[min..=max],
-            OAM_ADRESS..=OAM_END => &mut self.oam[min..=max],
-            ROM_ADDRESS..=ROM_END => {
-                if max < self.rom.len() {
-                    &mut self.rom[min..=max]
-                } else {
-                    &mut self.rom[0..=max-min]
+    fn writable_code_page(region: Region, offset: usize) -> Option<usize> {
+        let page = offset >> CODE_PAGE_SHIFT;
+        match region {
+            Region::Ewram => Some(page),
+            Region::Iwram => Some(EWRAM_CODE_PAGES + page),
+            Region::Vram => Some(EWRAM_CODE_PAGES + IWRAM_CODE_PAGES + page),
+            _ => None,
+        }
+    }
+
+    fn invalidate_code(&mut self, region: Region, offset: usize) {
+        if let Some(page) = Self::writable_code_page(region, offset) {
+            if self.code_pages[page] {
+                self.code_pages[page] = false;
+                self.invalidated_code_pages.push(page);
+            }
+        }
+    }
+
+    fn code_invalidated(&self) -> bool {
+        self.code_flush || !self.invalidated_code_pages.is_empty() || self.io.get(WAITCNT) != self.code_waitcnt
+    }
+
+    // The bitmap modes 3-5 extend the BG area into the first OBJ tile block
+    fn obj_vram_start(&self) -> usize {
+        if self.io.get(DISPCNT) & 0x7 >= 3 {
+            0x1_4000
+        } else {
+            0x1_0000
+        }
+    }
+
+    /// Reads a byte without advancing the clock, `None` if nothing is mapped there.
+    pub fn peek_byte(&self, address: u32) -> Option<u8> {
+        self.read_raw(address, 1).map(|value| value as u8)
+    }
+
+    /// Writes a byte without advancing the clock, returns false if nothing is mapped there.
+    /// Unlike CPU stores this also patches the BIOS and ROM.
+    pub fn poke_byte(&mut self, address: u32, value: u8) -> bool {
+        if address >> 24 == 0x4 {
+            self.io.write(address, value as u32, 1);
+            return true;
+        }
+        match self.decode_address(address) {
+            Some((region, offset)) => {
+                self.region_mut(region)[offset] = value;
+                if matches!(region, Region::Bios | Region::Rom) {
+                    self.code_flush = true;
                 }
-            },
-            SRAM_ADDRESS..=SRAM_END => todo!(),
-            _ => panic!("Invalid memory address: {:#X}", index.start),
+                self.invalidate_code(region, offset);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Bus for Memory {
+    fn get_byte(&mut self, address: u32, access: Access) -> u8 {
+        self.update_clock_cycles(address, 0, access);
+        let value = self.read(address, 1) as u8;
+        if self.monitor.is_some() {
+            self.monitor_access(AccessKind::Read, address, 1, value as u32);
         }
+        value
     }
-}
\ No newline at end of file
+
+    fn get_halfword(&mut self, address: u32, access: Access) -> u16 {
+        self.update_clock_cycles(address, 1, access);
+        let value = self.read(address, 2) as u16;
+        if self.monitor.is_some() {
+            self.monitor_access(AccessKind::Read, address, 2, value as u32);
+        }
+        value
+    }
+
+    fn get_word(&mut self, address: u32, access: Access) -> u32 {
+        self.update_clock_cycles(address, 2, access);
+        let value = self.read(address, 4);
+        if self.monitor.is_some() {
+            self.monitor_access(AccessKind::Read, address, 4, value);
+        }
+        value
+    }
+
+    fn fetch_halfword(&mut self, address: u32, access: Access) -> u16 {
+        self.fetch_address = address;
+        self.fetch_thumb = true;
+        self.fetch_cycles(address, 1, access);
+        if address as usize <= BIOS_END {
+            self.bios_latch = self.read_bus(address & !3, 4);
+        }
+        self.read_bus(address, 2) as u16
+    }
+
+    fn fetch_word(&mut self, address: u32, access: Access) -> u32 {
+        self.fetch_address = address;
+        self.fetch_thumb = false;
+        self.fetch_cycles(address, 2, access);
+        let value = self.read_bus(address, 4);
+        if address as usize <= BIOS_END {
+            self.bios_latch = value;
+        }
+        value
+    }
+
+    fn fetch_cached(&mut self, address: u32, thumb: bool, access: Access, cycles: Option<usize>) -> bool {
+        if self.code_invalidated() {
+            return false;
+        }
+        self.fetch_address = address;
+        self.fetch_thumb = thumb;
+        match cycles {
+            Some(cycles) => self.add_access_cycles(address, cycles),
+            None => self.fetch_cycles(address, if thumb { 1 } else { 2 }, access),
+        }
+        if address as usize <= BIOS_END {
+            self.bios_latch = self.read_bus(address & !3, 4);
+        }
+        true
+    }
+
+    fn store_byte(&mut self, address: u32, value: u8, access: Access) {
+        self.update_clock_cycles(address, 0, access);
+        if self.monitor.is_some() {
+            self.monitor_access(AccessKind::Write, address, 1, value as u32);
+        }
+        self.write(address, value as u32, 1);
+    }
+
+    fn store_halfword(&mut self, address: u32, value: u16, access: Access) {
+        self.update_clock_cycles(address, 1, access);
+        if self.monitor.is_some() {
+            self.monitor_access(AccessKind::Write, address, 2, value as u32);
+        }
+        self.write(address, value as u32, 2);
+    }
+
+    fn store_word(&mut self, address: u32, value: u32, access: Access) {
+        self.update_clock_cycles(address, 2, access);
+        if self.monitor.is_some() {
+            self.monitor_access(AccessKind::Write, address, 4, value);
+        }
+        self.write(address, value, 4);
+    }
+
+    // The prefetch buffer keeps loading during internal cycles
+    fn add_clock_cycles(&mut self, cycles: usize) {
+        self.clock += cycles;
+        self.advance_prefetch(cycles);
+    }
+
+    fn peek(&self, address: u32, size: usize) -> Option<u32> {
+        self.read_raw(address, size)
+    }
+
+    fn code_page(&self, address: u32) -> Option<CodePage> {
+        match self.decode_address(address)? {
+            (Region::Bios | Region::Rom, _) => Some(CodePage::ReadOnly),
+            (region, offset) => Self::writable_code_page(region, offset).map(CodePage::Writable),
+        }
+    }
+
+    fn code_timing(&self, address: u32, thumb: bool) -> Option<(usize, usize)> {
+        // Fetches through the prefetch buffer take as long as it still needs
+        if self.prefetch_enabled() && (0x8..=0xD).contains(&(address >> 24)) {
+            return None;
+        }
+        let bit_count = if thumb { 1 } else { 2 };
+        Some((
+            self.access_cycles(address, bit_count, Access::NonSequential),
+            self.access_cycles(address, bit_count, Access::Sequential)
+        ))
+    }
+
+    fn mark_code_page(&mut self, page: usize) {
+        self.code_pages[page] = true;
+    }
+
+    fn take_code_invalidations(&mut self) -> Option<Vec<usize>> {
+        if std::mem::take(&mut self.code_flush) || self.io.get(WAITCNT) != self.code_waitcnt {
+            self.code_waitcnt = self.io.get(WAITCNT);
+            self.code_pages.fill(false);
+            self.invalidated_code_pages.clear();
+            None
+        } else {
+            Some(std::mem::take(&mut self.invalidated_code_pages))
+        }
+    }
+}
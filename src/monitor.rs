@@ -0,0 +1,142 @@
+use std::collections::VecDeque;
+
+use crate::memory::{region_index, MEMORY_REGIONS};
+
+const DEFAULT_LOG_CAPACITY: usize = 0x10000;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AccessKind {
+    Read,
+    Write,
+    Execute
+}
+
+/// A single bus access made by the CPU.
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryAccess {
+    pub kind: AccessKind,
+    pub address: u32,
+    // In bytes
+    pub width: u8,
+    pub value: u32,
+    // Address of the instruction that made the access
+    pub pc: u32,
+    pub cycle: usize
+}
+
+#[derive(Clone, Copy)]
+pub struct Watchpoint {
+    // Inclusive range
+    pub start: u32,
+    pub end: u32,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool
+}
+
+impl Watchpoint {
+    fn matches(&self, access: &MemoryAccess) -> bool {
+        let kind_matches = match access.kind {
+            AccessKind::Read => self.read,
+            AccessKind::Write => self.write,
+            AccessKind::Execute => self.execute,
+        };
+        let last_byte = access.address.wrapping_add(access.width as u32 - 1);
+        kind_matches && access.address <= self.end && last_byte >= self.start
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WatchpointHit {
+    pub watchpoint: usize,
+    pub access: MemoryAccess
+}
+
+/// Watchpoints and the per-region access log, checked by `Memory` on every timed access.
+pub struct BusMonitor {
+    watchpoints: Vec<Watchpoint>,
+    hit: Option<WatchpointHit>,
+    logged_regions: [bool; MEMORY_REGIONS.len()],
+    log: VecDeque<MemoryAccess>,
+    log_capacity: usize
+}
+
+impl Default for BusMonitor {
+    fn default() -> BusMonitor {
+        BusMonitor::new()
+    }
+}
+
+impl BusMonitor {
+    pub fn new() -> BusMonitor {
+        BusMonitor {
+            watchpoints: Vec::new(),
+            hit: None,
+            logged_regions: [false; MEMORY_REGIONS.len()],
+            log: VecDeque::new(),
+            log_capacity: DEFAULT_LOG_CAPACITY
+        }
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        self.watchpoints.push(watchpoint);
+        self.watchpoints.len() - 1
+    }
+
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        (index < self.watchpoints.len()).then(|| self.watchpoints.remove(index))
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Enables or disables logging for the region named like in `MEMORY_REGIONS`.
+    pub fn set_region_logging(&mut self, name: &str, enabled: bool) -> bool {
+        match MEMORY_REGIONS.iter().position(|(region, _, _)| *region == name) {
+            Some(index) => {
+                self.logged_regions[index] = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_log_capacity(&mut self, capacity: usize) {
+        self.log_capacity = capacity;
+        while self.log.len() > capacity {
+            self.log.pop_front();
+        }
+    }
+
+    pub fn log(&self) -> &VecDeque<MemoryAccess> {
+        &self.log
+    }
+
+    pub fn clear_log(&mut self) {
+        self.log.clear();
+    }
+
+    /// The first watchpoint hit since the last call.
+    pub fn take_hit(&mut self) -> Option<WatchpointHit> {
+        self.hit.take()
+    }
+
+    pub fn record(&mut self, access: MemoryAccess) {
+        if self.hit.is_none() {
+            if let Some(watchpoint) = self.watchpoints.iter().position(|watchpoint| watchpoint.matches(&access)) {
+                self.hit = Some(WatchpointHit { watchpoint, access });
+            }
+        }
+        // A capacity of 0 turns the log off
+        let logged = self.log_capacity > 0
+            && access.kind != AccessKind::Execute
+            && region_index(access.address).is_some_and(|index| self.logged_regions[index]);
+        if logged {
+            if self.log.len() >= self.log_capacity {
+                self.log.pop_front();
+            }
+            self.log.push_back(access);
+        }
+    }
+}
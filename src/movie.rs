@@ -0,0 +1,231 @@
+use crate::error::GbaError;
+use crate::gba::Gba;
+use crate::state::{StateReader, StateWriter};
+use crate::utils::crc32;
+
+const MAGIC: &[u8; 4] = b"DNMV";
+const MOVIE_VERSION: u32 = 2;
+
+/// What the emulator looks like when the first recorded frame starts.
+#[derive(Clone, Debug)]
+pub enum BootMode {
+    /// A freshly powered on system, with the cartridge save it had.
+    PowerOn { save: Vec<u8> },
+    /// A save state, for movies that continue from the middle of a game.
+    State(Vec<u8>)
+}
+
+/// Recorded input for a ROM: the held keys of every frame, plus hashes of the emulator state that
+/// playback checks to catch desyncs.
+#[derive(Clone, Debug)]
+pub struct Movie {
+    pub rom_title: String,
+    pub rom_crc: u32,
+    /// File name of the patch applied to the ROM, `rom_crc` is of the patched ROM.
+    pub patch: Option<String>,
+    /// The cheat list in cheat file format, playback replaces the system's cheats with it.
+    pub cheats: String,
+    pub boot: BootMode,
+    /// Frames between state hashes, 0 to record none.
+    pub hash_interval: usize,
+    pub frames: Vec<u16>,
+    // Frame count the hash was taken at and the CRC-32 of the save state
+    pub hashes: Vec<(usize, u32)>
+}
+
+impl Movie {
+    /// Starts a movie from `gba` as it is now. Freshly created systems boot from power on, anything
+    /// else is captured as a save state.
+    pub fn new(gba: &Gba, hash_interval: usize) -> Movie {
+        let boot = if gba.cycles() == 0 {
+            BootMode::PowerOn { save: gba.save_data().to_vec() }
+        } else {
+            BootMode::State(gba.save_state())
+        };
+        Movie {
+            rom_title: gba.rom_info().title.clone(),
+            rom_crc: gba.rom_info().crc,
+            patch: gba.rom_patch().and_then(|patch| patch.file_name()).map(|name| name.to_string_lossy().into_owned()),
+            cheats: gba.cheats().to_text(),
+            boot,
+            hash_interval,
+            frames: Vec::new(),
+            hashes: Vec::new()
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut movie = StateWriter::new();
+        movie.write_raw(MAGIC);
+        movie.write_u32(MOVIE_VERSION);
+        movie.write_bytes(self.rom_title.as_bytes());
+        movie.write_u32(self.rom_crc);
+        movie.write_bool(self.patch.is_some());
+        movie.write_bytes(self.patch.as_deref().unwrap_or_default().as_bytes());
+        movie.write_bytes(self.cheats.as_bytes());
+        match &self.boot {
+            BootMode::PowerOn { save } => {
+                movie.write_u8(0);
+                movie.write_bytes(save);
+            }
+            BootMode::State(state) => {
+                movie.write_u8(1);
+                movie.write_bytes(state);
+            }
+        }
+        movie.write_usize(self.hash_interval);
+        movie.write_usize(self.frames.len());
+        for &keys in &self.frames {
+            movie.write_u16(keys);
+        }
+        movie.write_usize(self.hashes.len());
+        for &(frame, hash) in &self.hashes {
+            movie.write_usize(frame);
+            movie.write_u32(hash);
+        }
+        movie.into_inner()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, GbaError> {
+        let mut movie = StateReader::new(data);
+        if movie.read_raw(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Err(GbaError::InvalidMovie("Not a movie"));
+        }
+        if movie.read_u32()? != MOVIE_VERSION {
+            return Err(GbaError::InvalidMovie("Unsupported movie version"));
+        }
+        let rom_title = movie.read_string()?;
+        let rom_crc = movie.read_u32()?;
+        let patched = movie.read_bool()?;
+        let patch = movie.read_string()?;
+        let patch = patched.then_some(patch);
+        let cheats = movie.read_string()?;
+        let boot = match movie.read_u8()? {
+            0 => BootMode::PowerOn { save: movie.read_vec()? },
+            1 => BootMode::State(movie.read_vec()?),
+            _ => return Err(GbaError::InvalidMovie("Unknown boot mode")),
+        };
+        let hash_interval = movie.read_usize()?;
+        let frames = (0..movie.read_usize()?)
+            .map(|_| movie.read_u16())
+            .collect::<Result<Vec<_>, _>>()?;
+        let hashes = (0..movie.read_usize()?)
+            .map(|_| Ok((movie.read_usize()?, movie.read_u32()?)))
+            .collect::<Result<Vec<_>, GbaError>>()?;
+        movie.finish()?;
+        Ok(Movie { rom_title, rom_crc, patch, cheats, boot, hash_interval, frames, hashes })
+    }
+
+    // Puts `gba` where the movie starts, with the cheats it was recorded with
+    fn boot(&self, gba: &mut Gba) -> Result<(), GbaError> {
+        if gba.rom_info().crc != self.rom_crc {
+            let movie = match &self.patch {
+                Some(patch) => format!("{} patched with {}", self.rom_title, patch),
+                None => self.rom_title.clone(),
+            };
+            return Err(GbaError::MovieRomMismatch { movie, rom: gba.rom_info().title.clone() });
+        }
+        gba.cheats_mut().load_text(&self.cheats)?;
+        match &self.boot {
+            BootMode::PowerOn { save } => {
+                if gba.cycles() != 0 {
+                    return Err(GbaError::InvalidMovie("Movies recorded from power on need a freshly created system"));
+                }
+                gba.load_save(save.clone())
+            }
+            BootMode::State(state) => gba.load_state(state),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MovieMode {
+    Recording,
+    Playback,
+    /// Playback ran past the last frame, input comes from the frontend again.
+    Finished
+}
+
+/// Drives a `Gba` frame by frame from a movie, or records the frontend's input into one.
+pub struct MovieSession {
+    movie: Movie,
+    mode: MovieMode,
+    // Frames into the movie
+    position: usize,
+    // Frame count of the system at the start of the movie
+    start_frame: usize
+}
+
+impl MovieSession {
+    pub fn record(gba: &Gba, hash_interval: usize) -> MovieSession {
+        MovieSession {
+            movie: Movie::new(gba, hash_interval),
+            mode: MovieMode::Recording,
+            position: 0,
+            start_frame: gba.frame_count()
+        }
+    }
+
+    /// Checks the movie belongs to the loaded ROM and restores its starting point.
+    pub fn play(movie: Movie, gba: &mut Gba) -> Result<MovieSession, GbaError> {
+        movie.boot(gba)?;
+        Ok(MovieSession { movie, mode: MovieMode::Playback, position: 0, start_frame: gba.frame_count() })
+    }
+
+    pub fn mode(&self) -> MovieMode {
+        self.mode
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Switches playback to recording, dropping the rest of the movie after the current frame.
+    pub fn continue_recording(&mut self) {
+        self.movie.frames.truncate(self.position);
+        let frame = self.start_frame + self.position;
+        self.movie.hashes.retain(|&(hash_frame, _)| hash_frame <= frame);
+        self.mode = MovieMode::Recording;
+    }
+
+    /// Runs one frame with the movie's keys during playback, or with `live_keys` otherwise.
+    /// Playback fails with `GbaError::MovieDesync` as soon as a state hash doesn't match.
+    pub fn frame(&mut self, gba: &mut Gba, live_keys: u16) -> Result<(), GbaError> {
+        if self.mode == MovieMode::Playback && self.position == self.movie.frames.len() {
+            self.mode = MovieMode::Finished;
+        }
+        let keys = match self.mode {
+            MovieMode::Playback => self.movie.frames[self.position],
+            _ => live_keys,
+        };
+        gba.set_keys(keys);
+        gba.frame()?;
+        match self.mode {
+            MovieMode::Finished => return Ok(()),
+            MovieMode::Recording => self.movie.frames.push(keys),
+            MovieMode::Playback => (),
+        }
+        self.position += 1;
+        if self.movie.hash_interval == 0 || !self.position.is_multiple_of(self.movie.hash_interval) {
+            return Ok(());
+        }
+        let frame = gba.frame_count();
+        let hash = crc32(&gba.save_state());
+        if self.mode == MovieMode::Recording {
+            self.movie.hashes.push((frame, hash));
+        } else if let Some(&(_, expected)) = self.movie.hashes.iter().find(|&&(hash_frame, _)| hash_frame == frame) {
+            if expected != hash {
+                return Err(GbaError::MovieDesync { frame: self.position });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn into_movie(self) -> Movie {
+        self.movie
+    }
+}
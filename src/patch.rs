@@ -0,0 +1,231 @@
+use crate::error::GbaError;
+use crate::memory::MAX_ROM_SIZE;
+use crate::utils::crc32;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_END: &[u8] = b"EOF";
+const UPS_MAGIC: &[u8] = b"UPS1";
+const BPS_MAGIC: &[u8] = b"BPS1";
+// Source, target and patch CRC-32s
+const FOOTER_SIZE: usize = 12;
+const OUT_OF_RANGE: GbaError = GbaError::InvalidPatch("The patch copies from outside the data");
+
+/// Applies an IPS, UPS or BPS patch to a copy of `rom`, picking the format from the patch's magic.
+/// UPS and BPS patches are checked against the CRC-32s they carry for the ROM, the result and
+/// themselves.
+pub fn apply(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, GbaError> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(patch, rom)
+    } else if patch.starts_with(UPS_MAGIC) {
+        apply_ups(patch, rom)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(patch, rom)
+    } else {
+        Err(GbaError::InvalidPatch("Not an IPS, UPS or BPS patch"))
+    }
+}
+
+struct PatchReader<'a> {
+    data: &'a [u8],
+    position: usize
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8], position: usize) -> PatchReader<'a> {
+        PatchReader { data, position }
+    }
+
+    fn read(&mut self, length: usize) -> Result<&'a [u8], GbaError> {
+        let bytes = self.data
+            .get(self.position..self.position + length)
+            .ok_or(GbaError::InvalidPatch("The patch is truncated"))?;
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, GbaError> {
+        Ok(self.read(1)?[0])
+    }
+
+    fn read_big_endian(&mut self, length: usize) -> Result<usize, GbaError> {
+        Ok(self.read(length)?.iter().fold(0, |value, &byte| (value << 8) | byte as usize))
+    }
+
+    // UPS and BPS numbers: 7 bits per byte, least significant first, the last byte has bit 7 set
+    // and every byte before it counts one more so each number has a single encoding
+    fn read_number(&mut self) -> Result<usize, GbaError> {
+        let mut value = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let byte = self.read_u8()?;
+            value = (byte as usize & 0x7F)
+                .checked_mul(shift)
+                .and_then(|part| value.checked_add(part))
+                .ok_or(GbaError::InvalidPatch("A number in the patch is too large"))?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_shl(7).ok_or(GbaError::InvalidPatch("A number in the patch is too large"))?;
+            value += shift;
+        }
+    }
+}
+
+// Offsets are 24 bit, a size of 0 marks a run of one repeated byte. An optional 24 bit length
+// after the end marker truncates the ROM
+fn apply_ips(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, GbaError> {
+    let mut output = rom.to_vec();
+    let mut reader = PatchReader::new(patch, IPS_MAGIC.len());
+    loop {
+        if reader.read(IPS_END.len())? == IPS_END {
+            break;
+        }
+        reader.position -= IPS_END.len();
+        let offset = reader.read_big_endian(3)?;
+        let size = reader.read_big_endian(2)?;
+        let (length, run) = if size == 0 {
+            (reader.read_big_endian(2)?, Some(reader.read_u8()?))
+        } else {
+            (size, None)
+        };
+        if offset + length > MAX_ROM_SIZE {
+            return Err(GbaError::InvalidPatch("The patch writes past the largest possible ROM"));
+        }
+        if output.len() < offset + length {
+            output.resize(offset + length, 0);
+        }
+        match run {
+            Some(value) => output[offset..offset + length].fill(value),
+            None => output[offset..offset + length].copy_from_slice(reader.read(length)?),
+        }
+    }
+    if reader.position + 3 == patch.len() {
+        let length = reader.read_big_endian(3)?;
+        output.truncate(length);
+    }
+    Ok(output)
+}
+
+// Checks the footer's patch CRC and the source CRC, returns the CRC the result should have
+fn check_footer(patch: &[u8], rom: &[u8]) -> Result<u32, GbaError> {
+    if patch.len() < UPS_MAGIC.len() + FOOTER_SIZE {
+        return Err(GbaError::InvalidPatch("The patch is truncated"));
+    }
+    let footer = &patch[patch.len() - FOOTER_SIZE..];
+    let checksum = |index: usize| u32::from_le_bytes(footer[index * 4..index * 4 + 4].try_into().unwrap());
+    let (source, target, expected_patch) = (checksum(0), checksum(1), checksum(2));
+    let actual_patch = crc32(&patch[..patch.len() - 4]);
+    if actual_patch != expected_patch {
+        return Err(GbaError::PatchChecksumMismatch { checksum: "patch", expected: expected_patch, actual: actual_patch });
+    }
+    let actual_source = crc32(rom);
+    if actual_source != source {
+        return Err(GbaError::PatchChecksumMismatch { checksum: "source", expected: source, actual: actual_source });
+    }
+    Ok(target)
+}
+
+fn check_target(output: &[u8], target: u32) -> Result<(), GbaError> {
+    let actual = crc32(output);
+    if actual != target {
+        return Err(GbaError::PatchChecksumMismatch { checksum: "target", expected: target, actual });
+    }
+    Ok(())
+}
+
+fn check_size(size: usize) -> Result<(), GbaError> {
+    if size > MAX_ROM_SIZE {
+        return Err(GbaError::InvalidPatch("The patched ROM would be larger than 32 MiB"));
+    }
+    Ok(())
+}
+
+// Hunks skip some bytes, then XOR the ones after them until a zero
+fn apply_ups(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, GbaError> {
+    let target = check_footer(patch, rom)?;
+    let body_end = patch.len() - FOOTER_SIZE;
+    let mut reader = PatchReader::new(&patch[..body_end], UPS_MAGIC.len());
+    let _source_size = reader.read_number()?;
+    let target_size = reader.read_number()?;
+    check_size(target_size)?;
+    let mut output = rom.to_vec();
+    output.resize(target_size, 0);
+    let mut position = 0;
+    while reader.position < body_end {
+        position += reader.read_number()?;
+        loop {
+            let xor = reader.read_u8()?;
+            if xor == 0 {
+                position += 1;
+                break;
+            }
+            if let Some(byte) = output.get_mut(position) {
+                *byte ^= xor;
+            }
+            position += 1;
+        }
+    }
+    check_target(&output, target)?;
+    Ok(output)
+}
+
+// Builds the output from copies out of the ROM, the patch and the output written so far
+fn apply_bps(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, GbaError> {
+    let target = check_footer(patch, rom)?;
+    let body_end = patch.len() - FOOTER_SIZE;
+    let mut reader = PatchReader::new(&patch[..body_end], BPS_MAGIC.len());
+    let _source_size = reader.read_number()?;
+    let target_size = reader.read_number()?;
+    check_size(target_size)?;
+    let metadata_size = reader.read_number()?;
+    reader.read(metadata_size)?;
+
+    let mut output = Vec::with_capacity(target_size);
+    let mut source_offset = 0usize;
+    let mut target_offset = 0usize;
+    while reader.position < body_end {
+        let action = reader.read_number()?;
+        let length = (action >> 2) + 1;
+        if output.len() + length > target_size {
+            return Err(GbaError::InvalidPatch("The patch writes past the target size"));
+        }
+        match action & 3 {
+            // Source read, from the same offset in the ROM
+            0 => {
+                let start = output.len();
+                output.extend_from_slice(rom.get(start..start + length).ok_or(OUT_OF_RANGE)?);
+            }
+            // Target read, straight from the patch
+            1 => output.extend_from_slice(reader.read(length)?),
+            // Source copy
+            2 => {
+                let start = relative_offset(&mut source_offset, &mut reader)?;
+                output.extend_from_slice(rom.get(start..start + length).ok_or(OUT_OF_RANGE)?);
+                source_offset += length;
+            }
+            // Target copy, a byte at a time since it may overlap what it writes
+            _ => {
+                let start = relative_offset(&mut target_offset, &mut reader)?;
+                for index in start..start + length {
+                    let byte = *output.get(index).ok_or(OUT_OF_RANGE)?;
+                    output.push(byte);
+                }
+                target_offset += length;
+            }
+        }
+    }
+    if output.len() != target_size {
+        return Err(GbaError::InvalidPatch("The patch doesn't fill the target size"));
+    }
+    check_target(&output, target)?;
+    Ok(output)
+}
+
+// Copy offsets are stored relative to the end of the last copy, with the sign in the low bit
+fn relative_offset(offset: &mut usize, reader: &mut PatchReader) -> Result<usize, GbaError> {
+    let data = reader.read_number()?;
+    let distance = data >> 1;
+    *offset = if data & 1 != 0 { offset.checked_sub(distance) } else { offset.checked_add(distance) }
+        .ok_or(OUT_OF_RANGE)?;
+    Ok(*offset)
+}
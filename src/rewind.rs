@@ -0,0 +1,181 @@
+use std::collections::VecDeque;
+
+use crate::error::GbaError;
+use crate::gba::Gba;
+
+/// The newest snapshot, kept whole so rewinding to it needs no decoding.
+struct Checkpoint {
+    frame: usize,
+    state: Vec<u8>,
+    // Keys held in each frame run after the snapshot, replayed when rewinding into that stretch
+    keys: Vec<u16>
+}
+
+/// An older snapshot, stored as the run length encoded XOR against the one after it.
+struct Delta {
+    frame: usize,
+    length: usize,
+    encoded: Vec<u8>,
+    keys: Vec<u16>
+}
+
+/// Ring buffer of snapshots taken every `interval` frames that lets the emulator step back a frame
+/// at a time. The oldest snapshots are dropped once they take up more than `budget` bytes.
+pub struct Rewind {
+    interval: usize,
+    budget: usize,
+    newest: Option<Checkpoint>,
+    // Oldest first
+    deltas: VecDeque<Delta>,
+    delta_bytes: usize
+}
+
+impl Rewind {
+    pub fn new(interval: usize, budget: usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            newest: None,
+            deltas: VecDeque::new(),
+            delta_bytes: 0
+        }
+    }
+
+    /// Call after every emulated frame, with the keys that were held during it still set.
+    pub fn record(&mut self, gba: &Gba) {
+        let frame = gba.frame_count();
+        if let Some(newest) = self.newest.as_mut() {
+            newest.keys.push(gba.keys());
+            if frame < newest.frame + self.interval {
+                return;
+            }
+        }
+        let checkpoint = Checkpoint { frame, state: gba.save_state(), keys: Vec::new() };
+        if let Some(previous) = self.newest.replace(checkpoint) {
+            let newest = &self.newest.as_ref().unwrap().state;
+            let delta = Delta {
+                frame: previous.frame,
+                length: previous.state.len(),
+                encoded: encode_delta(&previous.state, newest),
+                keys: previous.keys
+            };
+            self.delta_bytes += delta.encoded.len();
+            self.deltas.push_back(delta);
+        }
+        while self.used_bytes() > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.delta_bytes -= delta.encoded.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Steps `gba` back one frame. Returns false when there is nothing older left to go back to.
+    pub fn rewind(&mut self, gba: &mut Gba) -> Result<bool, GbaError> {
+        let target = match gba.frame_count().checked_sub(1) {
+            Some(target) => target,
+            None => return Ok(false),
+        };
+        while self.newest.as_ref().is_some_and(|newest| newest.frame > target) {
+            if !self.pop_newest() {
+                return Ok(false);
+            }
+        }
+        let newest = match self.newest.as_mut() {
+            Some(newest) => newest,
+            None => return Ok(false),
+        };
+        gba.load_state(&newest.state)?;
+        newest.keys.truncate(target - newest.frame);
+        for &keys in &newest.keys {
+            gba.set_keys(keys);
+            gba.frame()?;
+        }
+        Ok(true)
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.delta_bytes = 0;
+    }
+
+    pub fn used_bytes(&self) -> usize {
+        self.delta_bytes + self.newest.as_ref().map_or(0, |newest| newest.state.len())
+    }
+
+    // Replaces the newest snapshot with the one before it
+    fn pop_newest(&mut self) -> bool {
+        let delta = match self.deltas.pop_back() {
+            Some(delta) => delta,
+            None => return false,
+        };
+        self.delta_bytes -= delta.encoded.len();
+        let newest = self.newest.take().unwrap();
+        self.newest = Some(Checkpoint {
+            frame: delta.frame,
+            state: decode_delta(&newest.state, &delta.encoded, delta.length),
+            keys: delta.keys
+        });
+        true
+    }
+}
+
+// Alternating runs of unchanged and changed bytes, each run length as a LEB128 varint followed by
+// the XORed bytes of the changed run
+fn encode_delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let length = old.len().max(new.len());
+    let xor = |index: usize| old.get(index).copied().unwrap_or(0) ^ new.get(index).copied().unwrap_or(0);
+    let mut encoded = Vec::new();
+    let mut index = 0;
+    while index < length {
+        let unchanged = (index..length).take_while(|&i| xor(i) == 0).count();
+        index += unchanged;
+        let changed = (index..length).take_while(|&i| xor(i) != 0).count();
+        write_varint(&mut encoded, unchanged);
+        write_varint(&mut encoded, changed);
+        encoded.extend((index..index + changed).map(xor));
+        index += changed;
+    }
+    encoded
+}
+
+fn decode_delta(new: &[u8], encoded: &[u8], length: usize) -> Vec<u8> {
+    let mut old = new.to_vec();
+    old.resize(old.len().max(length), 0);
+    let mut position = 0;
+    let mut index = 0;
+    while position < encoded.len() {
+        index += read_varint(encoded, &mut position);
+        let changed = read_varint(encoded, &mut position);
+        for (byte, xor) in old[index..index + changed].iter_mut().zip(&encoded[position..position + changed]) {
+            *byte ^= xor;
+        }
+        position += changed;
+        index += changed;
+    }
+    old.truncate(length);
+    old
+}
+
+fn write_varint(output: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        output.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+fn read_varint(input: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = input[*position];
+        *position += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
@@ -1,8 +1,9 @@
-use std::{cell::RefCell, collections::BinaryHeap, rc::Rc};
+use std::collections::BinaryHeap;
 
-use crate::memory::Memory;
+use crate::error::GbaError;
+use crate::state::{StateReader, StateWriter};
 
-#[derive(PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum EventType {
     EndFrame,
     HVisibleEnd,
@@ -17,6 +18,16 @@ pub struct Event {
     pub event_type: EventType,
 }
 
+impl EventType {
+    const ALL: [EventType; 5] = [
+        EventType::EndFrame,
+        EventType::HVisibleEnd,
+        EventType::HBlankEnd,
+        EventType::VVisibleEnd,
+        EventType::VBlankEnd
+    ];
+}
+
 impl PartialOrd for Event {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         other.timestamp.partial_cmp(&self.timestamp)
@@ -42,34 +53,50 @@ impl Event {
 }
 
 pub struct Scheduler {
-    queue: BinaryHeap<Event>,
-    memory: Rc<RefCell<Memory>>
+    queue: BinaryHeap<Event>
 }
 
 impl Scheduler {
-    pub fn new(memory: Rc<RefCell<Memory>>) -> Self {
-        Scheduler { queue: BinaryHeap::new(), memory }
+    pub fn new() -> Self {
+        Scheduler { queue: BinaryHeap::new() }
     }
 
     pub fn schedule(&mut self, event: Event) {
         
     }
 
-    pub fn schedule_from_now(&mut self, mut event: Event) {
-        event.add_timestamp(self.timestamp());
+    // `now` is the current clock cycle count
+    pub fn schedule_from_now(&mut self, mut event: Event, now: usize) {
+        event.add_timestamp(now);
         self.queue.push(event);
     }
 
-    pub fn timestamp(&self) -> usize {
-        self.memory.borrow().get_clock_cycles()
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_usize(self.queue.len());
+        for event in &self.queue {
+            state.write_usize(event.timestamp);
+            state.write_u8(event.event_type as u8);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), GbaError> {
+        self.queue.clear();
+        for _ in 0..state.read_usize()? {
+            let timestamp = state.read_usize()?;
+            let event_type = *EventType::ALL
+                .get(state.read_u8()? as usize)
+                .ok_or(GbaError::InvalidState("Unknown scheduler event"))?;
+            self.queue.push(Event::new(timestamp, event_type));
+        }
+        Ok(())
     }
 
-    pub fn time_until_next_event(&self) -> usize {
-        self.queue.peek().unwrap().timestamp.saturating_sub(self.memory.borrow().get_clock_cycles())
+    pub fn time_until_next_event(&self, now: usize) -> usize {
+        self.queue.peek().unwrap().timestamp.saturating_sub(now)
     }
 
-    pub fn pop(&mut self) -> Option<Event> {
-        if self.queue.peek().unwrap().timestamp <= self.timestamp() {
+    pub fn pop(&mut self, now: usize) -> Option<Event> {
+        if self.queue.peek().unwrap().timestamp <= now {
             self.queue.pop()
         } else {
             None
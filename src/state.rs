@@ -0,0 +1,197 @@
+use crate::cartridge::RomInfo;
+use crate::error::GbaError;
+
+const MAGIC: &[u8; 4] = b"DNSS";
+/// Bumped whenever the layout of any component changes, states from other versions are rejected.
+pub const STATE_VERSION: u32 = 4;
+
+/// Identifies the emulator and the game a save state was taken from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateHeader {
+    pub version: u32,
+    pub emulator_version: String,
+    pub rom_title: String,
+    pub rom_crc: u32
+}
+
+impl StateHeader {
+    pub fn for_rom(rom_info: &RomInfo) -> StateHeader {
+        StateHeader {
+            version: STATE_VERSION,
+            emulator_version: env!("CARGO_PKG_VERSION").to_string(),
+            rom_title: rom_info.title.clone(),
+            rom_crc: rom_info.crc
+        }
+    }
+
+    /// Reads just the header, e.g. to label save slots.
+    pub fn parse(data: &[u8]) -> Result<StateHeader, GbaError> {
+        StateHeader::read(&mut StateReader::new(data))
+    }
+
+    pub(crate) fn write(&self, state: &mut StateWriter) {
+        state.write_raw(MAGIC);
+        state.write_u32(self.version);
+        state.write_bytes(self.emulator_version.as_bytes());
+        state.write_bytes(self.rom_title.as_bytes());
+        state.write_u32(self.rom_crc);
+    }
+
+    pub(crate) fn read(state: &mut StateReader) -> Result<StateHeader, GbaError> {
+        if state.read_raw(MAGIC.len())? != MAGIC {
+            return Err(GbaError::InvalidState("Not a save state"));
+        }
+        let version = state.read_u32()?;
+        if version != STATE_VERSION {
+            return Err(GbaError::UnsupportedStateVersion(version));
+        }
+        Ok(StateHeader {
+            version,
+            emulator_version: state.read_string()?,
+            rom_title: state.read_string()?,
+            rom_crc: state.read_u32()?
+        })
+    }
+
+    pub(crate) fn check_rom(&self, rom_info: &RomInfo) -> Result<(), GbaError> {
+        if self.rom_crc != rom_info.crc {
+            return Err(GbaError::StateRomMismatch { state: self.rom_title.clone(), rom: rom_info.title.clone() });
+        }
+        Ok(())
+    }
+}
+
+/// Little endian serialization of the emulator's components.
+pub(crate) struct StateWriter {
+    data: Vec<u8>
+}
+
+impl StateWriter {
+    pub(crate) fn new() -> StateWriter {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub(crate) fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub(crate) fn write_raw(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub(crate) fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub(crate) fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub(crate) fn write_u16(&mut self, value: u16) {
+        self.write_raw(&value.to_le_bytes());
+    }
+
+    pub(crate) fn write_u32(&mut self, value: u32) {
+        self.write_raw(&value.to_le_bytes());
+    }
+
+    pub(crate) fn write_usize(&mut self, value: usize) {
+        self.write_raw(&(value as u64).to_le_bytes());
+    }
+
+    pub(crate) fn write_words(&mut self, values: &[u32]) {
+        for &value in values {
+            self.write_u32(value);
+        }
+    }
+
+    pub(crate) fn write_option_u32(&mut self, value: Option<u32>) {
+        self.write_bool(value.is_some());
+        self.write_u32(value.unwrap_or(0));
+    }
+
+    // Length prefixed, so regions that changed size are caught on load
+    pub(crate) fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.write_raw(bytes);
+    }
+}
+
+pub(crate) struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize
+}
+
+impl<'a> StateReader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, position: 0 }
+    }
+
+    pub(crate) fn read_raw(&mut self, length: usize) -> Result<&'a [u8], GbaError> {
+        let bytes = self.data
+            .get(self.position..self.position + length)
+            .ok_or(GbaError::InvalidState("The state is truncated"))?;
+        self.position += length;
+        Ok(bytes)
+    }
+
+    pub(crate) fn read_u8(&mut self) -> Result<u8, GbaError> {
+        Ok(self.read_raw(1)?[0])
+    }
+
+    pub(crate) fn read_bool(&mut self) -> Result<bool, GbaError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub(crate) fn read_u16(&mut self) -> Result<u16, GbaError> {
+        Ok(u16::from_le_bytes(self.read_raw(2)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn read_u32(&mut self) -> Result<u32, GbaError> {
+        Ok(u32::from_le_bytes(self.read_raw(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn read_usize(&mut self) -> Result<usize, GbaError> {
+        Ok(u64::from_le_bytes(self.read_raw(8)?.try_into().unwrap()) as usize)
+    }
+
+    pub(crate) fn read_words(&mut self, values: &mut [u32]) -> Result<(), GbaError> {
+        for value in values {
+            *value = self.read_u32()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read_option_u32(&mut self) -> Result<Option<u32>, GbaError> {
+        let present = self.read_bool()?;
+        let value = self.read_u32()?;
+        Ok(present.then_some(value))
+    }
+
+    /// Fills `bytes` from a length prefixed block, which has to be exactly as long.
+    pub(crate) fn read_bytes_into(&mut self, bytes: &mut [u8]) -> Result<(), GbaError> {
+        let length = self.read_u32()? as usize;
+        if length != bytes.len() {
+            return Err(GbaError::InvalidState("A memory region has the wrong size"));
+        }
+        bytes.copy_from_slice(self.read_raw(length)?);
+        Ok(())
+    }
+
+    pub(crate) fn read_vec(&mut self) -> Result<Vec<u8>, GbaError> {
+        let length = self.read_u32()? as usize;
+        Ok(self.read_raw(length)?.to_vec())
+    }
+
+    pub(crate) fn read_string(&mut self) -> Result<String, GbaError> {
+        let length = self.read_u32()? as usize;
+        Ok(String::from_utf8_lossy(self.read_raw(length)?).into_owned())
+    }
+
+    pub(crate) fn finish(&self) -> Result<(), GbaError> {
+        if self.position != self.data.len() {
+            return Err(GbaError::InvalidState("The state has trailing data"));
+        }
+        Ok(())
+    }
+}
@@ -1,87 +1,83 @@
-use std::{cell::RefCell, rc::Rc};
-
 use crate::constants::*;
+use crate::error::GbaError;
+use crate::io::{DISPCNT, DISPSTAT, VCOUNT};
 use crate::memory::Memory;
 use crate::scheduler::{Event, EventType};
-
-const DISPCNT: u32 = 0x4000000;
-const DISPSTAT: u32 = 0x4000004;
-const VCOUNT: u32 = 0x4000006;
+use crate::state::{StateReader, StateWriter};
 
 pub struct Video {
-    memory: Rc<RefCell<Memory>>,
     pub frame_buffer: [u8; SCREEN_WIDTH * SCREEN_HEIGHT * 2]
 }
 
 impl Video {
-    pub fn new(memory: Rc<RefCell<Memory>>) -> Self {
+    pub fn new() -> Self {
         Self {
-            memory,
             frame_buffer: [0; SCREEN_WIDTH * SCREEN_HEIGHT * 2]
         }
     }
 
-    pub fn h_visible_end_handler(&mut self) -> Event {
-        let mut memory = self.memory.borrow_mut();
-        let dispstat = memory.get_halfword(DISPSTAT, false) | 0x2;
-        memory.store_halfword(DISPSTAT, dispstat, false);
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.frame_buffer);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), GbaError> {
+        state.read_bytes_into(&mut self.frame_buffer)
+    }
+
+    pub fn h_visible_end_handler(&mut self, memory: &mut Memory) -> Event {
+        let dispstat = memory.io().get(DISPSTAT) | 0x2;
+        memory.io_mut().set(DISPSTAT, dispstat);
 
         Event::new(H_BLANK, EventType::HBlankEnd)
     }
 
-    pub fn h_blank_end_handler(&mut self) -> Event {
-        let mut vcount = self.memory.borrow_mut().get_halfword(VCOUNT, false);
+    pub fn h_blank_end_handler(&mut self, memory: &mut Memory) -> Event {
+        let mut vcount = memory.io().get(VCOUNT);
         if vcount < 160 {
-            self.render_line();
+            self.render_line(memory);
         }
-        let mut memory = self.memory.borrow_mut();
-        let dispstat = memory.get_halfword(DISPSTAT, false) & !0x2;
+        let dispstat = memory.io().get(DISPSTAT) & !0x2;
         vcount = (vcount + 1) % 228;
-        memory.store_halfword(DISPSTAT, dispstat, false);
-        memory.store_halfword(VCOUNT, vcount, false);
-        drop(memory);
+        memory.io_mut().set(DISPSTAT, dispstat);
+        memory.io_mut().set(VCOUNT, vcount);
 
         Event::new(VISIBLE_H, EventType::HVisibleEnd)
     }
 
-    pub fn v_visible_end_handler(&mut self) -> Event {
-        let mut memory = self.memory.borrow_mut();
-        let dispstat = memory.get_halfword(DISPSTAT, false) | 0x1;
-        memory.store_halfword(DISPSTAT, dispstat, false);
+    pub fn v_visible_end_handler(&mut self, memory: &mut Memory) -> Event {
+        let dispstat = memory.io().get(DISPSTAT) | 0x1;
+        memory.io_mut().set(DISPSTAT, dispstat);
 
         Event::new(V_BLANK, EventType::VBlankEnd)
     }
 
-    pub fn v_blank_end_handler(&mut self) -> Event {
-        let mut memory = self.memory.borrow_mut();
-        let dispstat = memory.get_halfword(DISPSTAT, false) & !0x1;
-        memory.store_halfword(DISPSTAT, dispstat, false);
+    pub fn v_blank_end_handler(&mut self, memory: &mut Memory) -> Event {
+        let dispstat = memory.io().get(DISPSTAT) & !0x1;
+        memory.io_mut().set(DISPSTAT, dispstat);
 
         Event::new(VISIBLE_V, EventType::VVisibleEnd)
     }
 
-    pub fn render_line(&mut self) {
-        let video_mode = self.memory.borrow_mut().get_halfword(0x0400_0000, false) & 0x7;
+    pub fn render_line(&mut self, memory: &Memory) {
+        let video_mode = memory.io().get(DISPCNT) & 0x7;
         match video_mode {
-            0x3 => self.video_mode_3(),
-            0x4 => self.video_mode_4(),
+            0x3 => self.video_mode_3(memory),
+            0x4 => self.video_mode_4(memory),
             _ => ()
         }
     }
 
-    fn video_mode_3(&mut self) {
-        let mut memory = self.memory.borrow_mut();
-        let line = memory.get_halfword(VCOUNT, false) as usize * SCREEN_WIDTH * 2;
-        self.frame_buffer[line..(line + SCREEN_WIDTH * 2)].copy_from_slice(&memory[(0x0600_0000 | line)..(0x600_0000 | (line + SCREEN_WIDTH * 2))]);
+    fn video_mode_3(&mut self, memory: &Memory) {
+        let line = memory.io().get(VCOUNT) as usize * SCREEN_WIDTH * 2;
+        self.frame_buffer[line..(line + SCREEN_WIDTH * 2)].copy_from_slice(&memory.vram()[line..(line + SCREEN_WIDTH * 2)]);
     }
 
-    fn video_mode_4(&mut self) {
-        let mut memory = self.memory.borrow_mut();
-        let line = memory.get_halfword(VCOUNT, false) as usize * SCREEN_WIDTH;
+    fn video_mode_4(&mut self, memory: &Memory) {
+        let line = memory.io().get(VCOUNT) as usize * SCREEN_WIDTH;
         let frame_buffer_line = line * 2;
-        let bg_memory_start = 0x600_0000 | ((memory.get_halfword(DISPCNT, false) as usize & 0x10) >> 4) * 0xA000 + line;
-        let pallete = &memory[0x500_0000..0x500_01FF];
-        for (vertical_line, entry) in memory[bg_memory_start..(bg_memory_start + SCREEN_WIDTH)].iter().enumerate() {
+        let bg_memory_start = ((memory.io().get(DISPCNT) as usize & 0x10) >> 4) * 0xA000 + line;
+        let pallete = memory.pallete_ram();
+        for (vertical_line, entry) in memory.vram()[bg_memory_start..(bg_memory_start + SCREEN_WIDTH)].iter().enumerate() {
             let color = pallete[(*entry as usize) * 2] as u16 | ((pallete[(*entry as usize) * 2 + 1] as u16) << 8);
             self.frame_buffer[frame_buffer_line + vertical_line * 2] = color as u8;
             self.frame_buffer[frame_buffer_line + vertical_line * 2 + 1] = color as u8;
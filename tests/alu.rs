@@ -0,0 +1,302 @@
+// Randomized tests of the data processing instructions against a plain reference model of the
+// ARM7TDMI's barrel shifter and ALU. Every case is one instruction run through the CPU, with the
+// result and NZCV compared to the model. ALU_TEST_SEED picks a different random sequence.
+
+use std::env;
+
+use dees_nuts::arm7::cpu::{Cpu, CpuSnapshot};
+
+mod common;
+use common::MockBus;
+
+const SYSTEM_MODE: u32 = 0x1F;
+const FLAGS: u32 = 0xF000_0000;
+const NOP: u32 = 0xE1A0_0000;
+const PC: u32 = 0x0800_0000;
+const RANDOM_CASES: usize = 100_000;
+
+const EDGE_VALUES: [u32; 8] = [0, 1, 2, 0x7FFF_FFFF, 0x8000_0000, 0x8000_0001, 0xFFFF_FFFE, 0xFFFF_FFFF];
+// Shift amounts by register, only the low byte counts
+const EDGE_REGISTER_SHIFTS: [u32; 10] = [0, 1, 4, 31, 32, 33, 63, 64, 255, 0x100];
+
+// xorshift64*, good enough to spread cases and reproducible from the seed
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 32) as u32
+    }
+
+    fn below(&mut self, bound: u32) -> u32 {
+        self.next() % bound
+    }
+
+    // Half of the values come from the edges, where flags tend to go wrong
+    fn value(&mut self) -> u32 {
+        match self.below(2) {
+            0 => EDGE_VALUES[self.below(EDGE_VALUES.len() as u32) as usize],
+            _ => self.next(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Operand2 {
+    // 8 bit value and rotation in steps of 2
+    Immediate { value: u32, rotation: u32 },
+    ImmediateShift { rm: usize, shift_type: u32, amount: u32 },
+    RegisterShift { rm: usize, shift_type: u32, rs: usize }
+}
+
+#[derive(Clone, Debug)]
+struct Case {
+    opcode: u32,
+    set_flags: bool,
+    rn: usize,
+    rd: usize,
+    operand_2: Operand2,
+    registers: [u32; 16],
+    cpsr: u32
+}
+
+impl Case {
+    fn encode(&self) -> u32 {
+        let operand_2 = match self.operand_2 {
+            Operand2::Immediate { value, rotation } => (1 << 25) | (rotation << 8) | value,
+            Operand2::ImmediateShift { rm, shift_type, amount } => (amount << 7) | (shift_type << 5) | rm as u32,
+            Operand2::RegisterShift { rm, shift_type, rs } => ((rs as u32) << 8) | (shift_type << 5) | (1 << 4) | rm as u32,
+        };
+        0xE000_0000
+            | (self.opcode << 21)
+            | ((self.set_flags as u32) << 20)
+            | ((self.rn as u32) << 16)
+            | ((self.rd as u32) << 12)
+            | operand_2
+    }
+}
+
+// Value and carry out of the barrel shifter
+fn reference_shift(shift_type: u32, value: u32, amount: u32, by_register: bool, carry: bool) -> (u32, bool) {
+    let bit = |index: u32| (value >> index) & 1 != 0;
+    if by_register && amount == 0 {
+        return (value, carry);
+    }
+    match shift_type {
+        // LSL
+        0 => match amount {
+            0 => (value, carry),
+            1..=31 => (value << amount, bit(32 - amount)),
+            32 => (0, bit(0)),
+            _ => (0, false),
+        },
+        // LSR, an immediate of 0 means 32
+        1 => match if amount == 0 { 32 } else { amount } {
+            amount @ 1..=31 => (value >> amount, bit(amount - 1)),
+            32 => (0, bit(31)),
+            _ => (0, false),
+        },
+        // ASR, an immediate of 0 means 32
+        2 => match if amount == 0 { 32 } else { amount } {
+            amount @ 1..=31 => (((value as i32) >> amount) as u32, bit(amount - 1)),
+            _ => (((value as i32) >> 31) as u32, bit(31)),
+        },
+        // ROR, an immediate of 0 is RRX
+        _ if amount == 0 => (((carry as u32) << 31) | (value >> 1), bit(0)),
+        _ => match amount % 32 {
+            0 => (value, bit(31)),
+            amount => (value.rotate_right(amount), bit(amount - 1)),
+        },
+    }
+}
+
+// Registers and CPSR after the instruction
+fn reference(case: &Case) -> ([u32; 16], u32) {
+    let carry = case.cpsr & (1 << 29) != 0;
+    // R15 reads 12 bytes ahead when the shift amount comes from a register
+    let mut inputs = case.registers;
+    inputs[15] = PC + 8;
+    let (operand_2, shifter_carry) = match case.operand_2 {
+        Operand2::Immediate { value, rotation } => {
+            let result = value.rotate_right(rotation * 2);
+            (result, if rotation == 0 { carry } else { result >> 31 != 0 })
+        }
+        Operand2::ImmediateShift { rm, shift_type, amount } =>
+            reference_shift(shift_type, inputs[rm], amount, false, carry),
+        Operand2::RegisterShift { rm, shift_type, rs } => {
+            inputs[15] = PC + 12;
+            reference_shift(shift_type, inputs[rm], inputs[rs] & 0xFF, true, carry)
+        }
+    };
+    let operand_1 = inputs[case.rn];
+
+    let add = |a: u32, b: u32, carry_in: bool| {
+        let wide = a as u64 + b as u64 + carry_in as u64;
+        let result = wide as u32;
+        let overflow = (!(a ^ b) & (a ^ result)) >> 31 != 0;
+        (result, wide >> 32 != 0, overflow)
+    };
+    // a - b - !carry_in is a + !b + carry_in
+    let subtract = |a: u32, b: u32, carry_in: bool| add(a, !b, carry_in);
+    let logical = |result: u32| (result, shifter_carry, case.cpsr & (1 << 28) != 0);
+    let (result, carry_out, overflow) = match case.opcode {
+        0x0 | 0x8 => logical(operand_1 & operand_2),
+        0x1 | 0x9 => logical(operand_1 ^ operand_2),
+        0x2 | 0xA => subtract(operand_1, operand_2, true),
+        0x3 => subtract(operand_2, operand_1, true),
+        0x4 | 0xB => add(operand_1, operand_2, false),
+        0x5 => add(operand_1, operand_2, carry),
+        0x6 => subtract(operand_1, operand_2, carry),
+        0x7 => subtract(operand_2, operand_1, carry),
+        0xC => logical(operand_1 | operand_2),
+        0xD => logical(operand_2),
+        0xE => logical(operand_1 & !operand_2),
+        _ => logical(!operand_2),
+    };
+
+    let mut registers = case.registers;
+    registers[15] = PC + 12;
+    if !(0x8..=0xB).contains(&case.opcode) {
+        registers[case.rd] = result;
+    }
+    let mut cpsr = case.cpsr;
+    if case.set_flags {
+        let flags = ((result >> 31) << 31)
+            | (((result == 0) as u32) << 30)
+            | ((carry_out as u32) << 29)
+            | ((overflow as u32) << 28);
+        cpsr = (cpsr & !FLAGS) | flags;
+    }
+    (registers, cpsr)
+}
+
+fn execute(case: &Case) -> ([u32; 16], u32) {
+    let mut registers = case.registers;
+    registers[15] = PC + 8;
+    let mut cpu = Cpu::new();
+    cpu.restore(&CpuSnapshot {
+        registers,
+        cpsr: case.cpsr,
+        spsr: [0; 5],
+        user_registers: [0; 7],
+        fiq_registers: [0; 7],
+        supervisor_registers: [0; 2],
+        abort_registers: [0; 2],
+        irq_registers: [0; 2],
+        undefined_registers: [0; 2],
+        pipeline: [case.encode(), NOP]
+    });
+    cpu.next(&mut MockBus::new(Vec::new()));
+    (*cpu.registers(), cpu.cpsr())
+}
+
+fn check(case: &Case) {
+    let (expected_registers, expected_cpsr) = reference(case);
+    let (registers, cpsr) = execute(case);
+    assert!(
+        registers == expected_registers && cpsr == expected_cpsr,
+        "{:08X} {:X?}\nexpected R{} = {:08X}, NZCV {:04b}\n     got R{} = {:08X}, NZCV {:04b}",
+        case.encode(),
+        case,
+        case.rd,
+        expected_registers[case.rd],
+        expected_cpsr >> 28,
+        case.rd,
+        registers[case.rd],
+        cpsr >> 28
+    );
+}
+
+// Test opcodes without S are the PSR transfers, so those always set flags
+fn sets_flags(opcode: u32, set_flags: bool) -> bool {
+    set_flags || (0x8..=0xB).contains(&opcode)
+}
+
+fn random_case(random: &mut Random) -> Case {
+    let opcode = random.below(16);
+    let mut registers = [0; 16];
+    for register in registers.iter_mut().take(15) {
+        *register = random.value();
+    }
+    // R15 shows up as an operand now and then, Rs = R15 is unpredictable
+    let register = |random: &mut Random| random.below(16) as usize;
+    let operand_2 = match random.below(3) {
+        0 => Operand2::Immediate { value: random.below(0x100), rotation: random.below(16) },
+        1 => Operand2::ImmediateShift { rm: register(random), shift_type: random.below(4), amount: random.below(32) },
+        _ => {
+            let rs = random.below(15) as usize;
+            registers[rs] = match random.below(2) {
+                0 => EDGE_REGISTER_SHIFTS[random.below(EDGE_REGISTER_SHIFTS.len() as u32) as usize],
+                _ => random.next(),
+            };
+            Operand2::RegisterShift { rm: register(random), shift_type: random.below(4), rs }
+        }
+    };
+    Case {
+        opcode,
+        set_flags: sets_flags(opcode, random.below(2) == 0),
+        rn: register(random),
+        rd: random.below(15) as usize,
+        operand_2,
+        registers,
+        cpsr: (random.next() & FLAGS) | SYSTEM_MODE
+    }
+}
+
+#[test]
+fn random_data_processing() {
+    let seed = env::var("ALU_TEST_SEED").ok().and_then(|seed| seed.parse().ok()).unwrap_or(0x5EED_1234_ABCD_0001);
+    let mut random = Random(seed);
+    for _ in 0..RANDOM_CASES {
+        check(&random_case(&mut random));
+    }
+}
+
+// Every opcode and shift type with the amounts that have special meanings
+#[test]
+fn shifter_edge_cases() {
+    for opcode in 0..16 {
+        for shift_type in 0..4 {
+            for &value in &EDGE_VALUES {
+                for carry in [0, 1 << 29] {
+                    let mut registers = [0; 16];
+                    registers[1] = 0x8000_0000;
+                    registers[2] = value;
+                    let mut case = Case {
+                        opcode,
+                        set_flags: sets_flags(opcode, true),
+                        rn: 1,
+                        rd: 0,
+                        operand_2: Operand2::ImmediateShift { rm: 2, shift_type, amount: 0 },
+                        registers,
+                        cpsr: carry | SYSTEM_MODE
+                    };
+                    for amount in [0, 1, 31] {
+                        case.operand_2 = Operand2::ImmediateShift { rm: 2, shift_type, amount };
+                        check(&case);
+                    }
+                    for &amount in &EDGE_REGISTER_SHIFTS {
+                        case.registers[3] = amount;
+                        case.operand_2 = Operand2::RegisterShift { rm: 2, shift_type, rs: 3 };
+                        check(&case);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// R15 reads as the instruction's address plus 8, or plus 12 with a register specified shift
+#[test]
+fn program_counter_as_operand() {
+    for opcode in [0x2, 0x4, 0xD] {
+        for operand_2 in [
+            Operand2::ImmediateShift { rm: 15, shift_type: 0, amount: 0 },
+            Operand2::RegisterShift { rm: 15, shift_type: 0, rs: 3 }
+        ] {
+            check(&Case { opcode, set_flags: false, rn: 15, rd: 0, operand_2, registers: [0; 16], cpsr: SYSTEM_MODE });
+        }
+    }
+}
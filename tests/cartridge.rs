@@ -0,0 +1,83 @@
+use dees_nuts::cartridge::SaveType;
+use dees_nuts::RomInfo;
+
+// A header with a valid checksum for the title and game code, the logo left blank
+fn header(entry_branch: u32) -> Vec<u8> {
+    let mut rom = vec![0; 0xC0];
+    rom[0..4].copy_from_slice(&entry_branch.to_le_bytes());
+    rom[0xA0..0xA8].copy_from_slice(b"TESTGAME");
+    rom[0xAC..0xB0].copy_from_slice(b"ATSE");
+    rom[0xB0..0xB2].copy_from_slice(b"01");
+    rom[0xB2] = 0x96;
+    let sum = rom[0xA0..0xBD].iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    rom[0xBD] = 0u8.wrapping_sub(sum).wrapping_sub(0x19);
+    rom
+}
+
+#[test]
+fn header_checksum_covers_0xa0_to_0xbc() {
+    let rom = header(0xEA00_002E);
+    let info = RomInfo::parse(&rom);
+    assert_eq!((info.title.as_str(), info.game_code.as_str(), info.maker_code.as_str()), ("TESTGAME", "ATSE", "01"));
+    assert!(info.checksum_valid);
+
+    for offset in [0xA0, 0xB2, 0xBC] {
+        let mut changed = rom.clone();
+        changed[offset] ^= 1;
+        assert!(!RomInfo::parse(&changed).checksum_valid, "changed {:#X}", offset);
+    }
+    // Bytes outside the range don't count
+    let mut changed = rom.clone();
+    changed[0xBE] = 0xFF;
+    assert!(RomInfo::parse(&changed).checksum_valid);
+
+    let mut changed = rom;
+    changed[0xBD] ^= 1;
+    assert!(!RomInfo::parse(&changed).checksum_valid);
+}
+
+#[test]
+fn entry_branch_decodes_to_an_offset_from_the_start_of_the_rom() {
+    // b 0xC0, the usual jump over the header
+    assert_eq!(RomInfo::parse(&header(0xEA00_002E)).entry_offset, Some(0xC0));
+    // b 0x08, right after the instruction's pipeline
+    assert_eq!(RomInfo::parse(&header(0xEA00_0000)).entry_offset, Some(0x08));
+    // b . and a backwards branch wrap below the start
+    assert_eq!(RomInfo::parse(&header(0xEAFF_FFFE)).entry_offset, Some(0));
+    assert_eq!(RomInfo::parse(&header(0xEAFF_FFFC)).entry_offset, Some(0xFFFF_FFF8));
+    // Conditional branches and other instructions aren't an entry point
+    assert_eq!(RomInfo::parse(&header(0x0A00_002E)).entry_offset, None);
+    assert_eq!(RomInfo::parse(&header(0xE3A0_0001)).entry_offset, None);
+    assert_eq!(RomInfo::parse(&[]).entry_offset, None);
+}
+
+#[test]
+fn save_type_comes_from_the_library_string() {
+    let with_library = |library: &[u8]| {
+        let mut rom = header(0xEA00_002E);
+        // Near misses before the real string
+        rom.extend_from_slice(b"FLASH SRAM_ EEPROM_X");
+        rom.extend_from_slice(library);
+        RomInfo::parse(&rom).save_type
+    };
+    assert_eq!(with_library(b""), SaveType::None);
+    assert_eq!(with_library(b"EEPROM_V124"), SaveType::Eeprom);
+    assert_eq!(with_library(b"SRAM_V113"), SaveType::Sram);
+    assert_eq!(with_library(b"SRAM_F_V100"), SaveType::Sram);
+    assert_eq!(with_library(b"FLASH_V126"), SaveType::Flash64K);
+    assert_eq!(with_library(b"FLASH512_V131"), SaveType::Flash64K);
+    assert_eq!(with_library(b"FLASH1M_V103"), SaveType::Flash128K);
+    // Cut off at the end of the ROM
+    assert_eq!(with_library(b"FLASH1M"), SaveType::None);
+}
+
+#[test]
+fn the_game_database_overrides_the_library_string() {
+    let mut rom = header(0xEA00_002E);
+    rom[0xAC..0xB0].copy_from_slice(b"AXVE");
+    rom.extend_from_slice(b"SRAM_V113");
+    let info = RomInfo::parse(&rom);
+    assert!(info.known_game);
+    assert_eq!(info.save_type, SaveType::Flash128K);
+    assert!(info.hardware.rtc);
+}
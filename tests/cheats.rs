@@ -0,0 +1,125 @@
+use dees_nuts::bus::Bus;
+use dees_nuts::cheats::{Cheat, CheatFormat};
+use dees_nuts::{Gba, GbaError};
+
+const TEA_DELTA: u32 = 0x9E37_79B9;
+const GAMESHARK_SEEDS: [u32; 4] = [0x09F4_FBBD, 0x9681_884A, 0x3520_27E9, 0xF3DE_E5A7];
+const ACTION_REPLAY3_SEEDS: [u32; 4] = [0x7AA9_648F, 0x7FAE_6994, 0xC0EF_AAD5, 0x4271_2C57];
+
+// TEA with 32 rounds, the inverse of what the cheat devices do to their codes
+fn encrypt((mut op1, mut op2): (u32, u32), seeds: &[u32; 4]) -> (u32, u32) {
+    let mut sum = 0u32;
+    for _ in 0..32 {
+        sum = sum.wrapping_add(TEA_DELTA);
+        op1 = op1.wrapping_add(
+            (op2 << 4).wrapping_add(seeds[0]) ^ op2.wrapping_add(sum) ^ (op2 >> 5).wrapping_add(seeds[1])
+        );
+        op2 = op2.wrapping_add(
+            (op1 << 4).wrapping_add(seeds[2]) ^ op1.wrapping_add(sum) ^ (op1 >> 5).wrapping_add(seeds[3])
+        );
+    }
+    (op1, op2)
+}
+
+fn encrypted_code(op1: u32, op2: u32, seeds: &[u32; 4]) -> String {
+    let (op1, op2) = encrypt((op1, op2), seeds);
+    format!("{:08X} {:08X}", op1, op2)
+}
+
+// b .
+fn gba() -> Gba {
+    let mut gba = Gba::new();
+    gba.load_rom(0xEAFF_FFFEu32.to_le_bytes().to_vec()).unwrap();
+    gba
+}
+
+#[test]
+fn the_test_side_tea_matches_the_reference_vector() {
+    assert_eq!(encrypt((0, 0), &[0; 4]), (0x41EA_3A0A, 0x94BA_A940));
+}
+
+#[test]
+fn encrypted_codes_decrypt_and_apply() {
+    let mut gba = gba();
+    // A 32 bit write to 0x03000000
+    let gameshark = encrypted_code(0x2300_0000, 0x1234_5678, &GAMESHARK_SEEDS);
+    gba.cheats_mut().add(Cheat::new("GameShark", CheatFormat::GameShark, &[gameshark]).unwrap());
+    // A 32 bit write to 0x03000010, the region nibble sits below the code type
+    let action_replay = encrypted_code(0x0430_0010, 0x9ABC_DEF0, &ACTION_REPLAY3_SEEDS);
+    gba.cheats_mut().add(Cheat::new("Action Replay", CheatFormat::ActionReplay3, &[action_replay]).unwrap());
+    gba.frame().unwrap();
+    assert_eq!(gba.memory().peek(0x0300_0000, 4), Some(0x1234_5678));
+    assert_eq!(gba.memory().peek(0x0300_0010, 4), Some(0x9ABC_DEF0));
+}
+
+#[test]
+fn codebreaker_writes_and_conditions() {
+    let mut gba = gba();
+    let codes = [
+        "83000020 1234",
+        // Only writes the next code when the halfword at 0x03000020 is 0x1234
+        "73000020 1234",
+        "33000030 0056",
+        "73000020 4321",
+        "33000031 0078",
+    ];
+    gba.cheats_mut().add(Cheat::new("CodeBreaker", CheatFormat::CodeBreaker, &codes).unwrap());
+    // The write to 0x03000020 runs first, so the condition after it holds on the same frame
+    gba.frame().unwrap();
+    assert_eq!(gba.memory().peek(0x0300_0020, 2), Some(0x1234));
+    assert_eq!(gba.memory().peek(0x0300_0030, 2), Some(0x0056));
+}
+
+#[test]
+fn unsupported_codes_are_rejected() {
+    let reseed = encrypted_code(0xDEAD_FACE, 0x1234_5678, &GAMESHARK_SEEDS);
+    assert!(matches!(Cheat::new("Reseed", CheatFormat::GameShark, &[reseed]), Err(GbaError::InvalidCheat { .. })));
+    let reseed = encrypted_code(0xDEAD_FACE, 0x1234_5678, &ACTION_REPLAY3_SEEDS);
+    assert!(matches!(Cheat::new("Reseed", CheatFormat::ActionReplay3, &[reseed]), Err(GbaError::InvalidCheat { .. })));
+    assert!(matches!(
+        Cheat::new("Encrypted", CheatFormat::CodeBreaker, &["9123ABCD 4567"]),
+        Err(GbaError::InvalidCheat { .. })
+    ));
+    assert!(matches!(Cheat::new("Raw", CheatFormat::Raw, &["3000000:123"]), Err(GbaError::InvalidCheat { .. })));
+}
+
+#[test]
+fn rom_patches_are_undone_when_the_cheat_is_disabled() {
+    let mut gba = gba();
+    gba.cheats_mut().add(Cheat::new("Patch", CheatFormat::Raw, &["08000000:00000000"]).unwrap());
+    gba.frame().unwrap();
+    assert_eq!(gba.memory().peek(0x0800_0000, 4), Some(0));
+    gba.cheats_mut().get_mut(0).unwrap().enabled = false;
+    gba.frame().unwrap();
+    assert_eq!(gba.memory().peek(0x0800_0000, 4), Some(0xEAFF_FFFE));
+}
+
+#[test]
+fn cheat_lists_round_trip_through_text() {
+    let mut gba = gba();
+    let cheats = gba.cheats_mut();
+    cheats.add(Cheat::new("Money", CheatFormat::Raw, &["03000000:FFFF"]).unwrap());
+    let mut disabled = Cheat::new("Lives", CheatFormat::CodeBreaker, &["33000004  0009"]).unwrap();
+    disabled.enabled = false;
+    cheats.add(disabled);
+    let text = cheats.to_text();
+
+    let mut loaded = self::gba();
+    loaded.cheats_mut().load_text(&text).unwrap();
+    let list = loaded.cheats().list();
+    assert_eq!(list.len(), 2);
+    assert_eq!((list[0].name.as_str(), list[0].enabled, list[0].codes()), ("Money", true, &["03000000:FFFF".to_string()][..]));
+    assert_eq!((list[1].name.as_str(), list[1].enabled, list[1].codes()), ("Lives", false, &["33000004 0009".to_string()][..]));
+    assert_eq!(loaded.cheats().to_text(), text);
+}
+
+#[test]
+fn the_same_codes_are_only_added_once() {
+    let mut gba = gba();
+    let cheats = gba.cheats_mut();
+    assert!(cheats.add_if_new(Cheat::new("Cheat 1", CheatFormat::Raw, &["03000000:01"]).unwrap()));
+    // Same codes under another name and with other spacing
+    assert!(!cheats.add_if_new(Cheat::new("Cheat 2", CheatFormat::Raw, &[" 03000000:01 "]).unwrap()));
+    assert!(cheats.add_if_new(Cheat::new("Cheat 2", CheatFormat::Raw, &["03000000:02"]).unwrap()));
+    assert_eq!(cheats.list().len(), 2);
+}
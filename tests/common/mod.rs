@@ -0,0 +1,130 @@
+// Shared by the CPU tests, a bus that answers from a list of expected transactions.
+
+#![allow(dead_code)]
+
+use dees_nuts::bus::Bus;
+use dees_nuts::memory::{Access, CodePage};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Kind {
+    Fetch,
+    Read,
+    Write
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Transaction {
+    pub kind: Kind,
+    pub size: usize,
+    pub address: u32,
+    pub data: u32
+}
+
+// The ARM7TDMI cycle types: non-sequential and sequential bus accesses, internal cycles
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Cycle {
+    N,
+    S,
+    I
+}
+
+// Serves reads from the transactions the case expects and records everything the CPU does
+pub struct MockBus {
+    expected: Vec<Transaction>,
+    used: Vec<bool>,
+    pub transactions: Vec<Transaction>,
+    pub cycles: Vec<Cycle>
+}
+
+impl MockBus {
+    pub fn new(expected: Vec<Transaction>) -> MockBus {
+        MockBus { used: vec![false; expected.len()], expected, transactions: Vec::new(), cycles: Vec::new() }
+    }
+
+    fn access(&mut self, access: Access) {
+        self.cycles.push(match access {
+            Access::NonSequential => Cycle::N,
+            Access::Sequential => Cycle::S,
+        });
+    }
+
+    // Reads the case doesn't list return 0
+    fn read(&mut self, kind: Kind, size: usize, address: u32, access: Access) -> u32 {
+        self.access(access);
+        let matching = (0..self.expected.len()).find(|&index| {
+            let transaction = self.expected[index];
+            !self.used[index] && transaction.kind == kind && transaction.size == size && transaction.address == address
+        });
+        let data = matching.map_or(0, |index| {
+            self.used[index] = true;
+            self.expected[index].data
+        });
+        self.transactions.push(Transaction { kind, size, address, data });
+        data
+    }
+
+    fn write(&mut self, size: usize, address: u32, data: u32, access: Access) {
+        self.access(access);
+        self.transactions.push(Transaction { kind: Kind::Write, size, address, data });
+    }
+}
+
+impl Bus for MockBus {
+    fn get_byte(&mut self, address: u32, access: Access) -> u8 {
+        self.read(Kind::Read, 1, address, access) as u8
+    }
+
+    fn get_halfword(&mut self, address: u32, access: Access) -> u16 {
+        self.read(Kind::Read, 2, address, access) as u16
+    }
+
+    fn get_word(&mut self, address: u32, access: Access) -> u32 {
+        self.read(Kind::Read, 4, address, access)
+    }
+
+    fn store_byte(&mut self, address: u32, value: u8, access: Access) {
+        self.write(1, address, value as u32, access);
+    }
+
+    fn store_halfword(&mut self, address: u32, value: u16, access: Access) {
+        self.write(2, address, value as u32, access);
+    }
+
+    fn store_word(&mut self, address: u32, value: u32, access: Access) {
+        self.write(4, address, value, access);
+    }
+
+    fn fetch_halfword(&mut self, address: u32, access: Access) -> u16 {
+        self.read(Kind::Fetch, 2, address, access) as u16
+    }
+
+    fn fetch_word(&mut self, address: u32, access: Access) -> u32 {
+        self.read(Kind::Fetch, 4, address, access)
+    }
+
+    fn fetch_cached(&mut self, _address: u32, _thumb: bool, _access: Access, _cycles: Option<usize>) -> bool {
+        unreachable!("The mock bus has no cacheable code");
+    }
+
+    fn add_clock_cycles(&mut self, cycles: usize) {
+        self.cycles.extend(std::iter::repeat_n(Cycle::I, cycles));
+    }
+
+    fn peek(&self, _address: u32, _size: usize) -> Option<u32> {
+        None
+    }
+
+    fn code_page(&self, _address: u32) -> Option<CodePage> {
+        None
+    }
+
+    fn code_timing(&self, _address: u32, _thumb: bool) -> Option<(usize, usize)> {
+        None
+    }
+
+    fn mark_code_page(&mut self, _page: usize) { }
+
+    fn take_code_invalidations(&mut self) -> Option<Vec<usize>> {
+        None
+    }
+}
@@ -0,0 +1,135 @@
+// Checks the S/N/I cycles of single instructions against the ARM7TDMI timings. The fetch that starts
+// an instruction is typed by the one before it, so each case counts from after its own fetch up to
+// and including the fetch of the instruction that follows: an STR is "NN" the way the manual has it,
+// with its second N showing up as the following fetch.
+
+use dees_nuts::arm7::cpu::{Cpu, CpuSnapshot};
+
+mod common;
+use common::{Cycle, MockBus};
+
+const SYSTEM_MODE: u32 = 0x1F;
+const THUMB_BIT: u32 = 0x20;
+const ARM_NOP: u32 = 0xE1A0_0000;
+const THUMB_NOP: u32 = 0x46C0;
+
+fn cycles(opcode: u32, thumb: bool, registers: &[(usize, u32)]) -> String {
+    let mut snapshot = CpuSnapshot {
+        registers: [0; 16],
+        cpsr: if thumb { SYSTEM_MODE | THUMB_BIT } else { SYSTEM_MODE },
+        spsr: [0; 5],
+        user_registers: [0; 7],
+        fiq_registers: [0; 7],
+        supervisor_registers: [0; 2],
+        abort_registers: [0; 2],
+        irq_registers: [0; 2],
+        undefined_registers: [0; 2],
+        pipeline: [opcode, if thumb { THUMB_NOP } else { ARM_NOP }]
+    };
+    snapshot.registers[15] = if thumb { 0x0800_0004 } else { 0x0800_0008 };
+    for &(register, value) in registers {
+        snapshot.registers[register] = value;
+    }
+    let mut cpu = Cpu::new();
+    cpu.set_hle_bios(false);
+    cpu.restore(&snapshot);
+    let mut bus = MockBus::new(Vec::new());
+    cpu.next(&mut bus);
+    assert!(cpu.fault().is_none(), "{:08X} faulted", opcode);
+    // The instruction after it only adds its fetch
+    cpu.next(&mut bus);
+    bus.cycles[1..].iter().map(|cycle| match cycle {
+        Cycle::N => 'N',
+        Cycle::S => 'S',
+        Cycle::I => 'I',
+    }).collect()
+}
+
+fn arm(opcode: u32, registers: &[(usize, u32)]) -> String {
+    cycles(opcode, false, registers)
+}
+
+fn thumb(opcode: u16, registers: &[(usize, u32)]) -> String {
+    cycles(opcode as u32, true, registers)
+}
+
+const IWRAM: u32 = 0x0300_0000;
+
+#[test]
+fn arm_data_processing_and_branches() {
+    // mov r0, #5
+    assert_eq!(arm(0xE3A0_0005, &[]), "S");
+    // add r0, r1, r2, lsl r3
+    assert_eq!(arm(0xE081_0312, &[]), "IS");
+    // b, bl and bx refill the pipeline with an N and an S fetch
+    assert_eq!(arm(0xEA00_0000, &[]), "NSS");
+    assert_eq!(arm(0xEB00_0000, &[]), "NSS");
+    assert_eq!(arm(0xE12F_FF10, &[(0, 0x0800_0100)]), "NSS");
+    // mov pc, r0
+    assert_eq!(arm(0xE1A0_F000, &[(0, 0x0800_0100)]), "NSS");
+}
+
+#[test]
+fn arm_loads_and_stores() {
+    // ldr r0, [r1]
+    assert_eq!(arm(0xE591_0000, &[(1, IWRAM)]), "NIS");
+    // ldr pc, [r1]
+    assert_eq!(arm(0xE591_F000, &[(1, IWRAM)]), "NINSS");
+    // str r0, [r1]
+    assert_eq!(arm(0xE581_0000, &[(1, IWRAM)]), "NN");
+    // ldrh r0, [r1] and strh r0, [r1]
+    assert_eq!(arm(0xE1D1_00B0, &[(1, IWRAM)]), "NIS");
+    assert_eq!(arm(0xE1C1_00B0, &[(1, IWRAM)]), "NN");
+    // swp r0, r1, [r2]
+    assert_eq!(arm(0xE102_0091, &[(2, IWRAM)]), "NNIS");
+}
+
+#[test]
+fn arm_block_transfers() {
+    // ldmia r1, {r0, r2, r3}
+    assert_eq!(arm(0xE891_000D, &[(1, IWRAM)]), "NSSIS");
+    // ldmia r1, {r0, pc}
+    assert_eq!(arm(0xE891_8001, &[(1, IWRAM)]), "NSINSS");
+    // stmia r1, {r0, r2, r3}
+    assert_eq!(arm(0xE881_000D, &[(1, IWRAM)]), "NSSN");
+    // stmia r1, {r0}
+    assert_eq!(arm(0xE881_0001, &[(1, IWRAM)]), "NN");
+}
+
+#[test]
+fn arm_multiplies_stop_early_on_small_operands() {
+    // mul r0, r1, r2 takes one internal cycle per significant byte of r2
+    let mul = 0xE000_0291;
+    assert_eq!(arm(mul, &[(2, 0x0000_0012)]), "IS");
+    assert_eq!(arm(mul, &[(2, 0x0000_1234)]), "IIS");
+    assert_eq!(arm(mul, &[(2, 0x0012_3456)]), "IIIS");
+    assert_eq!(arm(mul, &[(2, 0x1234_5678)]), "IIIIS");
+    // Leading ones count as unused too
+    assert_eq!(arm(mul, &[(2, 0xFFFF_FF00)]), "IS");
+    // mla r0, r1, r2, r3 adds one for the accumulate
+    assert_eq!(arm(0xE020_3291, &[(2, 0x0000_1234)]), "IIIS");
+    // umull r0, r1, r2, r3 only skips leading zeros, smull also skips leading ones
+    assert_eq!(arm(0xE081_0392, &[(3, 0x0000_0012)]), "IIS");
+    assert_eq!(arm(0xE081_0392, &[(3, 0xFFFF_FF00)]), "IIIIIS");
+    assert_eq!(arm(0xE0C1_0392, &[(3, 0xFFFF_FF00)]), "IIS");
+    // umlal r0, r1, r2, r3
+    assert_eq!(arm(0xE0A1_0392, &[(3, 0x0000_0012)]), "IIIS");
+}
+
+#[test]
+fn thumb_instructions() {
+    // mov r0, #5
+    assert_eq!(thumb(0x2005, &[]), "S");
+    // lsl r0, r1
+    assert_eq!(thumb(0x4088, &[]), "IS");
+    // mul r0, r1 depends on r0
+    assert_eq!(thumb(0x4348, &[(0, 0x0000_1234)]), "IIS");
+    // ldr r0, [r1] and str r0, [r1]
+    assert_eq!(thumb(0x6808, &[(1, IWRAM)]), "NIS");
+    assert_eq!(thumb(0x6008, &[(1, IWRAM)]), "NN");
+    // push {r0, r1} and pop {r0, r1}
+    assert_eq!(thumb(0xB403, &[(13, IWRAM + 0x100)]), "NSN");
+    assert_eq!(thumb(0xBC03, &[(13, IWRAM + 0x100)]), "NSIS");
+    // b
+    assert_eq!(thumb(0xE000, &[]), "NSS");
+}
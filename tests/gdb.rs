@@ -0,0 +1,115 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+use dees_nuts::gdb::GdbStub;
+use dees_nuts::Gba;
+
+// mov r0, #1; mov r1, #2; add r2, r0, r1; b .
+const PROGRAM: [u32; 4] = [0xE3A0_0001, 0xE3A0_1002, 0xE080_2001, 0xEAFF_FFFE];
+
+struct Client {
+    stream: TcpStream
+}
+
+impl Client {
+    fn read_byte(&mut self) -> u8 {
+        let mut byte = [0];
+        self.stream.read_exact(&mut byte).unwrap();
+        byte[0]
+    }
+
+    // Sends a packet and returns the reply, acknowledging both ways like GDB does
+    fn request(&mut self, payload: &str) -> String {
+        let checksum = payload.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        self.stream.write_all(format!("${}#{:02x}", payload, checksum).as_bytes()).unwrap();
+        assert_eq!(self.read_byte(), b'+', "the stub rejected {}", payload);
+        assert_eq!(self.read_byte(), b'$');
+        let mut reply = Vec::new();
+        loop {
+            match self.read_byte() {
+                b'#' => break,
+                byte => reply.push(byte),
+            }
+        }
+        let checksum = [self.read_byte(), self.read_byte()];
+        let expected = u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap();
+        assert_eq!(reply.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)), expected);
+        self.stream.write_all(b"+").unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+}
+
+fn register(registers: &str, index: usize) -> u32 {
+    let bytes: Vec<u8> = (0..4).map(|i| u8::from_str_radix(&registers[index * 8 + i * 2..index * 8 + i * 2 + 2], 16).unwrap()).collect();
+    u32::from_le_bytes(bytes.try_into().unwrap())
+}
+
+fn run_session(program: &[u32], script: impl FnOnce(&mut Client) + Send + 'static) {
+    let mut gba = Gba::new();
+    gba.load_rom(program.iter().flat_map(|word| word.to_le_bytes()).collect()).unwrap();
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let address = listener.local_addr().unwrap();
+    let client = thread::spawn(move || {
+        let mut client = Client { stream: TcpStream::connect(address).unwrap() };
+        script(&mut client);
+        assert_eq!(client.request("D"), "OK");
+    });
+    let mut stub = GdbStub::accept(&listener).unwrap();
+    let killed = stub.run(&mut gba, &mut |_| ()).unwrap();
+    client.join().unwrap();
+    assert!(!killed);
+}
+
+#[test]
+fn packet_exchange_over_a_local_socket() {
+    run_session(&PROGRAM, |client| {
+        let supported = client.request("qSupported:swbreak+;hwbreak+");
+        assert!(supported.contains("swbreak+") && supported.contains("hwbreak+"), "{}", supported);
+
+        let registers = client.request("g");
+        assert_eq!(registers.len(), 17 * 8);
+        assert_eq!(register(&registers, 15), 0x0800_0000);
+
+        // Writing every register back with r3 changed
+        let mut changed = registers.clone();
+        changed.replace_range(3 * 8..4 * 8, "78563412");
+        assert_eq!(client.request(&format!("G{}", changed)), "OK");
+        assert_eq!(register(&client.request("g"), 3), 0x1234_5678);
+
+        assert_eq!(client.request("M2000000,4:efbeadde"), "OK");
+        assert_eq!(client.request("m2000000,4"), "efbeadde");
+        assert_eq!(client.request("m8000000,4"), "0100a0e3");
+
+        // The breakpoint on the add stops after the two moves
+        assert_eq!(client.request("Z0,8000008,4"), "OK");
+        assert_eq!(client.request("c"), "T05swbreak:;");
+        let registers = client.request("g");
+        assert_eq!((register(&registers, 0), register(&registers, 1), register(&registers, 2)), (1, 2, 0));
+        assert_eq!(register(&registers, 15), 0x0800_0008);
+
+        // Removed, so stepping over it runs the add
+        assert_eq!(client.request("z0,8000008,4"), "OK");
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(register(&client.request("g"), 2), 3);
+
+        assert_eq!(client.request("Z1,800000c,4"), "OK");
+        assert_eq!(client.request("c"), "T05hwbreak:;");
+    });
+}
+
+#[test]
+fn watchpoints_report_the_kind_they_were_set_with() {
+    // mov r0, #0x2000000; str r1, [r0]; ldr r2, [r0]; ldr r3, [r0]; b .
+    let program = [0xE3A0_0402, 0xE580_1000, 0xE590_2000, 0xE590_3000, 0xEAFF_FFFE];
+    run_session(&program, |client| {
+        assert_eq!(client.request("Z2,2000000,4"), "OK");
+        assert_eq!(client.request("c"), "T05watch:2000000;");
+        assert_eq!(client.request("z2,2000000,4"), "OK");
+        assert_eq!(client.request("Z4,2000000,4"), "OK");
+        assert_eq!(client.request("c"), "T05awatch:2000000;");
+        assert_eq!(client.request("z4,2000000,4"), "OK");
+        assert_eq!(client.request("Z3,2000000,4"), "OK");
+        assert_eq!(client.request("c"), "T05rwatch:2000000;");
+    });
+}
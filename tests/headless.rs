@@ -0,0 +1,28 @@
+use std::process::Command;
+use std::{env, fs};
+
+// Counts VBlanks in a word at 0x03000000:
+// mov r0, #0x3000000
+// loop: swi 0x05 (VBlankIntrWait); ldr r1, [r0]; add r1, r1, #1; str r1, [r0]; b loop
+const PROGRAM: [u32; 6] = [0xE3A0_0403, 0xEF05_0000, 0xE590_1000, 0xE281_1001, 0xE580_1000, 0xEAFF_FFFA];
+
+// Runs the program for `frames` frames until the counter reaches `count`, returns the exit code
+fn run_until_count(frames: usize, count: u32) -> i32 {
+    let rom = env::temp_dir().join(format!("dees_nuts_vblank_wait_{}.gba", std::process::id()));
+    fs::write(&rom, PROGRAM.iter().flat_map(|word| word.to_le_bytes()).collect::<Vec<u8>>()).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_headless"))
+        .arg(&rom)
+        .args(["--frames", &frames.to_string(), "--until-mem", &format!("3000000={:x}", count)])
+        .output()
+        .unwrap()
+        .status;
+    fs::remove_file(&rom).unwrap();
+    status.code().unwrap()
+}
+
+#[test]
+fn vblank_intr_wait_waits_for_the_next_vblank() {
+    // One increment per frame
+    assert_eq!(run_until_count(5, 4), 0);
+    assert_eq!(run_until_count(5, 100), 3);
+}
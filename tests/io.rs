@@ -0,0 +1,104 @@
+use dees_nuts::io::{DmaStart, IoRegisters, DISPSTAT, DMA0CNT_H, HALTCNT, IF, KEYINPUT, VCOUNT};
+use dees_nuts::Gba;
+
+const IO: u32 = 0x0400_0000;
+
+#[test]
+fn writing_ones_to_if_acknowledges_those_interrupts() {
+    let mut io = IoRegisters::new();
+    io.set(IF, 0x0025);
+    io.write(IO + IF, 0x0021, 2);
+    assert_eq!(io.get(IF), 0x0004);
+    // Bytes outside the written lane are left alone
+    io.set(IF, 0x0101);
+    io.write(IO + IF + 1, 0x01, 1);
+    assert_eq!(io.get(IF), 0x0001);
+    // Bits past the 14 interrupt sources can't be acknowledged
+    io.set(IF, 0xC000);
+    io.write(IO + IF, 0xFFFF, 2);
+    assert_eq!(io.get(IF), 0xC000);
+}
+
+#[test]
+fn reads_and_writes_go_through_the_register_masks() {
+    let mut io = IoRegisters::new();
+    // DISPSTAT: the status flags are read-only, the bits between them don't exist
+    io.set(DISPSTAT, 0x0007);
+    io.write(IO + DISPSTAT, 0xFFF8, 2);
+    assert_eq!(io.get(DISPSTAT), 0xFF3F);
+    io.set(DISPSTAT, 0xFFFF);
+    assert_eq!(io.read(IO + DISPSTAT, 2), Some(0xFF3F));
+
+    // VCOUNT and KEYINPUT ignore writes
+    io.set(VCOUNT, 0x00A0);
+    io.write(IO + VCOUNT, 0x0012, 2);
+    assert_eq!(io.read(IO + VCOUNT, 2), Some(0x00A0));
+    io.write(IO + KEYINPUT, 0x0000, 2);
+    assert_eq!(io.read(IO + KEYINPUT, 2), Some(0x03FF));
+
+    // The scrolling offsets are write-only and 9 bits wide
+    io.write(IO + 0x010, 0xFFFF, 2);
+    assert_eq!(io.get(0x010), 0x01FF);
+    assert_eq!(io.read(IO + 0x010, 2), None);
+
+    // Byte reads pick their lane, unmapped registers float
+    assert_eq!(io.read(IO + KEYINPUT + 1, 1), Some(0x03));
+    assert_eq!(io.read(IO + 0x0E0, 2), None);
+}
+
+#[test]
+fn enabling_a_dma_channel_latches_its_transfer() {
+    let mut io = IoRegisters::new();
+    // DMA1: source, destination, count
+    io.write(IO + 0x0BC, 0x0300_0000, 4);
+    io.write(IO + 0x0C0, 0x0600_0000, 4);
+    io.write(IO + 0x0C4, 0x0010, 2);
+    assert_eq!(io.take_dma_start(1), None);
+    // The high byte of DMA1CNT_H holds the enable bit
+    io.write(IO + DMA0CNT_H + 12 + 1, 0x80, 1);
+    let start = DmaStart { source: 0x0300_0000, destination: 0x0600_0000, count: 0x0010 };
+    assert_eq!(io.take_dma_start(1), Some(start));
+    assert_eq!(io.take_dma_start(1), None);
+    // Only the enable edge latches, not writes while the channel runs
+    io.write(IO + DMA0CNT_H + 12, 0x8000, 2);
+    assert_eq!(io.take_dma_start(1), None);
+    io.write(IO + DMA0CNT_H + 12, 0x0000, 2);
+    io.write(IO + DMA0CNT_H + 12, 0x8000, 2);
+    assert_eq!(io.take_dma_start(1), Some(start));
+    assert_eq!(io.take_dma_start(0), None);
+}
+
+#[test]
+fn writing_haltcnt_halts_until_vblank() {
+    let mut io = IoRegisters::new();
+    // POSTFLG shares the halfword but doesn't halt
+    io.write(IO + HALTCNT - 1, 0x01, 1);
+    assert!(!io.take_halt_request());
+    io.write(IO + HALTCNT - 1, 0x0001, 2);
+    assert!(io.take_halt_request());
+    assert!(!io.take_halt_request());
+
+    // mov r0, #0x4000000; strb r0, [r0, #0x301]; b .
+    let program = [0xE3A0_0301u32, 0xE5C0_0301, 0xEAFF_FFFE];
+    let mut gba = Gba::new();
+    gba.load_rom(program.iter().flat_map(|word| word.to_le_bytes()).collect()).unwrap();
+    gba.step().unwrap();
+    assert!(!gba.cpu().halted());
+    gba.step().unwrap();
+    assert!(gba.cpu().halted());
+    gba.frame().unwrap();
+    assert!(!gba.cpu().halted());
+}
+
+#[test]
+fn word_reads_keep_the_open_bus_on_their_unmapped_half() {
+    // mov r0, #0x4000000; ldr r1, [r0, #0x84]; b .
+    let program = [0xE3A0_0301u32, 0xE590_1084, 0xEAFF_FFFE, 0xDEAD_BEEF];
+    let mut gba = Gba::new();
+    gba.load_rom(program.iter().flat_map(|word| word.to_le_bytes()).collect()).unwrap();
+    // SOUNDCNT_X at 0x84 reads its master enable, 0x86 isn't mapped
+    gba.memory_mut().io_mut().write(IO + 0x084, 0x0080, 2);
+    gba.step().unwrap();
+    gba.step().unwrap();
+    assert_eq!(gba.cpu().registers()[1], 0xDEAD_0080);
+}
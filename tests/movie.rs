@@ -0,0 +1,120 @@
+use dees_nuts::cheats::{Cheat, CheatFormat};
+use dees_nuts::movie::{Movie, MovieMode, MovieSession};
+use dees_nuts::{Gba, GbaError};
+
+// Adds 1 plus KEYINPUT to a word in IWRAM forever, so the state depends on every frame's keys:
+// mov r0, #0x3000000; mov r1, #0x4000000
+// loop: ldr r2, [r0]; add r2, r2, #1; ldr r3, [r1, #0x130]; add r2, r2, r3; str r2, [r0]; b loop
+const PROGRAM: [u32; 8] = [0xE3A0_0403, 0xE3A0_1301, 0xE590_2000, 0xE282_2001, 0xE591_3130, 0xE082_2003, 0xE580_2000, 0xEAFF_FFF9];
+
+fn gba() -> Gba {
+    let mut gba = Gba::new();
+    gba.load_rom(PROGRAM.iter().flat_map(|word| word.to_le_bytes()).collect()).unwrap();
+    gba
+}
+
+fn keys(frame: usize) -> u16 {
+    (frame * 37 % 0x400) as u16
+}
+
+// Records `frames` frames into a movie that went through its file format
+fn record(gba: &mut Gba, frames: usize) -> Movie {
+    let mut session = MovieSession::record(gba, 4);
+    for frame in 0..frames {
+        session.frame(gba, keys(frame)).unwrap();
+    }
+    Movie::from_bytes(&session.into_movie().to_bytes()).unwrap()
+}
+
+#[test]
+fn playback_reproduces_a_recording_from_power_on() {
+    let mut recorded = gba();
+    let movie = record(&mut recorded, 20);
+    assert_eq!(movie.frames.len(), 20);
+    assert_eq!(movie.hashes.len(), 5);
+
+    let mut played = gba();
+    let mut session = MovieSession::play(movie, &mut played).unwrap();
+    for _ in 0..20 {
+        // The movie's keys win over the live ones
+        session.frame(&mut played, 0x3FF).unwrap();
+    }
+    assert_eq!(played.save_state(), recorded.save_state());
+    session.frame(&mut played, 0).unwrap();
+    assert_eq!(session.mode(), MovieMode::Finished);
+}
+
+#[test]
+fn playback_from_a_save_state_and_desyncs() {
+    let mut recorded = gba();
+    for frame in 0..3 {
+        recorded.set_keys(keys(frame));
+        recorded.frame().unwrap();
+    }
+    let mut movie = record(&mut recorded, 12);
+
+    let mut played = gba();
+    let mut session = MovieSession::play(movie.clone(), &mut played).unwrap();
+    for _ in 0..12 {
+        session.frame(&mut played, 0).unwrap();
+    }
+    assert_eq!(played.save_state(), recorded.save_state());
+
+    movie.frames[5] ^= 1;
+    let mut played = gba();
+    let mut session = MovieSession::play(movie, &mut played).unwrap();
+    let error = (0..12).find_map(|_| session.frame(&mut played, 0).err());
+    assert!(matches!(error, Some(GbaError::MovieDesync { frame: 8 })), "{:?}", error);
+}
+
+#[test]
+fn movies_bring_their_cheats_and_check_the_rom() {
+    let mut recorded = gba();
+    recorded.cheats_mut().add(Cheat::new("Counter", CheatFormat::Raw, &["03000004:00000007"]).unwrap());
+    let movie = record(&mut recorded, 8);
+    assert!(movie.cheats.contains("03000004:00000007"));
+    assert_eq!(movie.patch, None);
+
+    // Playback replaces whatever cheats were there
+    let mut played = gba();
+    played.cheats_mut().add(Cheat::new("Other", CheatFormat::Raw, &["03000008:01"]).unwrap());
+    let mut session = MovieSession::play(movie.clone(), &mut played).unwrap();
+    for _ in 0..8 {
+        session.frame(&mut played, 0).unwrap();
+    }
+    assert_eq!(played.cheats().list().len(), 1);
+    assert_eq!(played.save_state(), recorded.save_state());
+
+    let mut other = Gba::new();
+    other.load_rom(vec![0xFE, 0xFF, 0xFF, 0xEA]).unwrap();
+    assert!(matches!(MovieSession::play(movie, &mut other), Err(GbaError::MovieRomMismatch { .. })));
+}
+
+#[test]
+fn movies_tell_multiboot_images_apart() {
+    let image = |title: &[u8]| {
+        let mut image: Vec<u8> = PROGRAM.iter().flat_map(|word| word.to_le_bytes()).collect();
+        image.resize(0x100, 0);
+        image[0xA0..0xA0 + title.len()].copy_from_slice(title);
+        image
+    };
+    let mut recorded = Gba::new();
+    recorded.load_multiboot(image(b"FIRST")).unwrap();
+    let movie = record(&mut recorded, 4);
+    assert_eq!(movie.rom_title, "FIRST");
+
+    let mut other = Gba::new();
+    other.load_multiboot(image(b"SECOND")).unwrap();
+    match MovieSession::play(movie.clone(), &mut other) {
+        Err(GbaError::MovieRomMismatch { movie, rom }) => assert_eq!((movie.as_str(), rom.as_str()), ("FIRST", "SECOND")),
+        _ => panic!("played a movie of another multiboot image"),
+    }
+
+    let mut same = Gba::new();
+    same.load_multiboot(image(b"FIRST")).unwrap();
+    let mut session = MovieSession::play(movie, &mut same).unwrap();
+    for _ in 0..4 {
+        session.frame(&mut same, 0).unwrap();
+    }
+    assert_eq!(same.save_state(), recorded.save_state());
+}
@@ -0,0 +1,128 @@
+use dees_nuts::{crc32, patch, GbaError};
+
+const ROM: [u8; 8] = [0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17];
+
+// UPS and BPS numbers, the inverse of the reader's decoding
+fn number(mut value: usize) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let low = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(low | 0x80);
+            return bytes;
+        }
+        bytes.push(low);
+        value -= 1;
+    }
+}
+
+// Appends the source, target and patch CRC-32s
+fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+    patch.extend_from_slice(&crc32(source).to_le_bytes());
+    patch.extend_from_slice(&crc32(target).to_le_bytes());
+    let checksum = crc32(&patch);
+    patch.extend_from_slice(&checksum.to_le_bytes());
+    patch
+}
+
+#[test]
+fn ips_copies_runs_and_grows() {
+    let mut ips = b"PATCH".to_vec();
+    // 2 bytes at 1
+    ips.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]);
+    // A run of 4 0xCC at 6, past the end of the ROM
+    ips.extend_from_slice(&[0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x04, 0xCC]);
+    ips.extend_from_slice(b"EOF");
+    assert_eq!(
+        patch::apply(&ips, &ROM).unwrap(),
+        [0x10, 0xAA, 0xBB, 0x13, 0x14, 0x15, 0xCC, 0xCC, 0xCC, 0xCC]
+    );
+
+    // The length after the end marker truncates
+    ips.extend_from_slice(&[0x00, 0x00, 0x03]);
+    assert_eq!(patch::apply(&ips, &ROM).unwrap(), [0x10, 0xAA, 0xBB]);
+}
+
+#[test]
+fn ips_without_an_end_marker_is_truncated() {
+    let ips = b"PATCH\x00\x00\x01\x00\x02\xAA".to_vec();
+    assert!(matches!(patch::apply(&ips, &ROM), Err(GbaError::InvalidPatch(_))));
+}
+
+#[test]
+fn ups_xors_hunks_and_resizes() {
+    let target = [0x10, 0x11, 0x02, 0x03, 0x14, 0x15, 0x06, 0x17, 0x00, 0x42];
+    let mut ups = b"UPS1".to_vec();
+    ups.extend(number(ROM.len()));
+    ups.extend(number(target.len()));
+    // Skip 2, XOR 2 bytes, then the zero ends the hunk and leaves byte 4 alone
+    ups.extend(number(2));
+    ups.extend_from_slice(&[0x10, 0x10, 0x00]);
+    // Skip 1 from byte 5, XOR byte 6, the zero leaves byte 7 alone
+    ups.extend(number(1));
+    ups.extend_from_slice(&[0x10, 0x00]);
+    // Skip 1 from byte 8, byte 9 is past the ROM so it XORs the zero fill
+    ups.extend(number(1));
+    ups.extend_from_slice(&[0x42, 0x00]);
+    let ups = with_footer(ups, &ROM, &target);
+    assert_eq!(patch::apply(&ups, &ROM).unwrap(), target);
+}
+
+#[test]
+fn bps_copies_from_the_rom_the_patch_and_its_own_output() {
+    let target = [0x10, 0x11, 0xAA, 0x16, 0x17, 0x12, 0x13, 0x13, 0x13, 0x13, 0x13];
+    let action = |kind: usize, length: usize| number(((length - 1) << 2) | kind);
+    let mut bps = b"BPS1".to_vec();
+    bps.extend(number(ROM.len()));
+    bps.extend(number(target.len()));
+    // No metadata
+    bps.extend(number(0));
+    // Source read of 2
+    bps.extend(action(0, 2));
+    // Target read of 1
+    bps.extend(action(1, 1));
+    bps.push(0xAA);
+    // Source copy of 2 from 6, then of 2 from 2 which is 6 back from where the last copy ended
+    bps.extend(action(2, 2));
+    bps.extend(number(6 << 1));
+    bps.extend(action(2, 2));
+    bps.extend(number((6 << 1) | 1));
+    // Target copy of 4 from the byte before, overlapping what it writes
+    bps.extend(action(3, 4));
+    bps.extend(number(6 << 1));
+    let bps = with_footer(bps, &ROM, &target);
+    assert_eq!(patch::apply(&bps, &ROM).unwrap(), target);
+}
+
+#[test]
+fn checksums_are_checked() {
+    let target = [0x10, 0x11, 0x02];
+    let mut ups = b"UPS1".to_vec();
+    ups.extend(number(ROM.len()));
+    ups.extend(number(target.len()));
+    ups.extend(number(2));
+    ups.extend_from_slice(&[0x10, 0x00]);
+    let body = ups.clone();
+    let ups = with_footer(ups, &ROM, &target);
+    assert_eq!(patch::apply(&ups, &ROM).unwrap(), target);
+
+    let other_rom = [0; 8];
+    assert!(matches!(
+        patch::apply(&ups, &other_rom),
+        Err(GbaError::PatchChecksumMismatch { checksum: "source", .. })
+    ));
+
+    let wrong_target = with_footer(body, &ROM, &[0x10, 0x11, 0x12]);
+    assert!(matches!(
+        patch::apply(&wrong_target, &ROM),
+        Err(GbaError::PatchChecksumMismatch { checksum: "target", .. })
+    ));
+
+    let mut damaged = ups;
+    damaged[6] ^= 1;
+    assert!(matches!(
+        patch::apply(&damaged, &ROM),
+        Err(GbaError::PatchChecksumMismatch { checksum: "patch", .. })
+    ));
+}
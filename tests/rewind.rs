@@ -0,0 +1,68 @@
+use dees_nuts::{Gba, Rewind};
+
+// Adds 1 plus KEYINPUT to a word in IWRAM forever, so every frame changes the state and the keys matter:
+// mov r0, #0x3000000; mov r1, #0x4000000
+// loop: ldr r2, [r0]; add r2, r2, #1; ldr r3, [r1, #0x130]; add r2, r2, r3; str r2, [r0]; b loop
+const PROGRAM: [u32; 8] = [0xE3A0_0403, 0xE3A0_1301, 0xE590_2000, 0xE282_2001, 0xE591_3130, 0xE082_2003, 0xE580_2000, 0xEAFF_FFF9];
+
+fn gba() -> Gba {
+    let mut gba = Gba::new();
+    gba.load_rom(PROGRAM.iter().flat_map(|word| word.to_le_bytes()).collect()).unwrap();
+    gba
+}
+
+// Runs `frames` frames with changing keys, returns the state after each of them
+fn run(gba: &mut Gba, rewind: &mut Rewind, frames: usize) -> Vec<Vec<u8>> {
+    (0..frames).map(|frame| {
+        gba.set_keys((frame * 37 % 0x400) as u16);
+        gba.frame().unwrap();
+        rewind.record(gba);
+        gba.save_state()
+    }).collect()
+}
+
+#[test]
+fn rewinding_restores_every_earlier_frame_exactly() {
+    let mut gba = gba();
+    let mut rewind = Rewind::new(4, usize::MAX);
+    let states = run(&mut gba, &mut rewind, 24);
+    // Back through the checkpoints, the deltas between them and the frames replayed in between
+    for expected in states.iter().rev().skip(1) {
+        assert!(rewind.rewind(&mut gba).unwrap());
+        assert_eq!(&gba.save_state(), expected, "frame {}", gba.frame_count());
+    }
+    assert!(!rewind.rewind(&mut gba).unwrap());
+    assert_eq!(gba.frame_count(), 1);
+}
+
+#[test]
+fn recording_resumes_after_a_rewind() {
+    let mut gba = gba();
+    let mut rewind = Rewind::new(3, usize::MAX);
+    run(&mut gba, &mut rewind, 20);
+    for _ in 0..8 {
+        assert!(rewind.rewind(&mut gba).unwrap());
+    }
+    let states = run(&mut gba, &mut rewind, 10);
+    for expected in states.iter().rev().skip(1) {
+        assert!(rewind.rewind(&mut gba).unwrap());
+        assert_eq!(&gba.save_state(), expected);
+    }
+}
+
+#[test]
+fn the_oldest_snapshots_go_once_over_budget() {
+    let mut gba = gba();
+    // Room for the newest snapshot and a few small deltas
+    let budget = gba.save_state().len() + 256;
+    let mut rewind = Rewind::new(1, budget);
+    run(&mut gba, &mut rewind, 60);
+    assert!(rewind.used_bytes() <= budget, "{} bytes used of {}", rewind.used_bytes(), budget);
+
+    let mut rewound = 0;
+    while rewind.rewind(&mut gba).unwrap() {
+        rewound += 1;
+    }
+    assert!(rewound > 0 && rewound < 59, "rewound {} frames", rewound);
+    assert_eq!(gba.frame_count(), 60 - rewound);
+}
@@ -0,0 +1,35 @@
+use dees_nuts::Gba;
+
+// mov r0, #0x3000000; ldmia r0, {r1-r8}; b .-4, slow enough that most runs end past their budget
+const PROGRAM: [u32; 3] = [0xE3A0_0403, 0xE890_01FE, 0xEAFF_FFFD];
+
+#[test]
+fn runs_make_up_for_the_cycles_the_last_one_overshot() {
+    let mut gba = Gba::new();
+    gba.load_rom(PROGRAM.iter().flat_map(|word| word.to_le_bytes()).collect()).unwrap();
+    let start = gba.cycles();
+    for run in 1..=1000 {
+        gba.run(7).unwrap();
+        let elapsed = gba.cycles() - start;
+        // Never behind, and never ahead by more than the longest instruction
+        assert!(elapsed >= run * 7 && elapsed < run * 7 + 32, "{} cycles after {} runs", elapsed, run);
+    }
+}
+
+// Copies mov r2, #1 and bx lr to IWRAM and calls it, overwrites the mov with mov r2, #2 and
+// calls it again, keeping both results in r4 and r5
+const SELF_MODIFYING_PROGRAM: [u32; 16] = [
+    0xE3A0_0403, 0xE59F_1028, 0xE59F_3028, 0xE880_000A, 0xE1A0_E00F, 0xE12F_FF10, 0xE1A0_4002, 0xE59F_1018,
+    0xE580_1000, 0xE1A0_E00F, 0xE12F_FF10, 0xE1A0_5002, 0xEAFF_FFFE, 0xE3A0_2001, 0xE12F_FF1E, 0xE3A0_2002
+];
+
+#[test]
+fn rewritten_code_runs_the_new_instruction() {
+    let mut gba = Gba::new();
+    gba.load_rom(SELF_MODIFYING_PROGRAM.iter().flat_map(|word| word.to_le_bytes()).collect()).unwrap();
+    for _ in 0..32 {
+        gba.step().unwrap();
+    }
+    assert_eq!(gba.cpu().registers()[4], 1);
+    assert_eq!(gba.cpu().registers()[5], 2);
+}
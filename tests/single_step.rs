@@ -0,0 +1,193 @@
+// Runs the CPU one instruction at a time against JSON test vectors in the SingleStepTests ARM7TDMI
+// format: each case has the registers before and after the instruction plus every bus transaction
+// it makes. A few hand written cases live in tests/data/single_step, point SINGLE_STEP_TESTS at a
+// directory of the full (decompressed) suite to run that instead.
+
+use std::{env, fs, path::PathBuf};
+
+use dees_nuts::arm7::cpu::{Cpu, CpuSnapshot};
+use serde_json::Value;
+
+mod common;
+use common::{Kind, MockBus, Transaction};
+
+const USER_MODE: u32 = 0x10;
+const FIQ_MODE: u32 = 0x11;
+const IRQ_MODE: u32 = 0x12;
+const SUPERVISOR_MODE: u32 = 0x13;
+const ABORT_MODE: u32 = 0x17;
+const UNDEFINED_MODE: u32 = 0x1B;
+const SYSTEM_MODE: u32 = 0x1F;
+
+// Stop listing failures after this many, one broken instruction tends to fail thousands of cases
+const MAX_REPORTED_FAILURES: usize = 20;
+
+fn number(value: &Value) -> u32 {
+    value.as_u64().expect("Expected a number") as u32
+}
+
+fn words<const N: usize>(value: &Value) -> [u32; N] {
+    let values: Vec<u32> = value.as_array().expect("Expected an array").iter().map(number).collect();
+    values.try_into().expect("Array has the wrong length")
+}
+
+// The format lists R8-R14 of user mode only through R, so they're unknown while in FIQ mode
+fn parse_state(state: &Value) -> CpuSnapshot {
+    let registers: [u32; 16] = words(&state["R"]);
+    let cpsr = number(&state["CPSR"]);
+    let mut user_registers = [0; 7];
+    if cpsr & 0x1F != FIQ_MODE {
+        user_registers.copy_from_slice(&registers[8..15]);
+    }
+    CpuSnapshot {
+        registers,
+        cpsr,
+        spsr: words(&state["SPSR"]),
+        user_registers,
+        fiq_registers: words(&state["R_fiq"]),
+        supervisor_registers: words(&state["R_svc"]),
+        abort_registers: words(&state["R_abt"]),
+        irq_registers: words(&state["R_irq"]),
+        undefined_registers: words(&state["R_und"]),
+        pipeline: words(&state["pipeline"])
+    }
+}
+
+fn parse_transactions(transactions: &Value) -> Vec<Transaction> {
+    transactions.as_array().expect("Expected an array").iter().map(|transaction| Transaction {
+        kind: match number(&transaction["kind"]) {
+            0 => Kind::Fetch,
+            1 => Kind::Read,
+            2 => Kind::Write,
+            kind => panic!("Unknown transaction kind {}", kind),
+        },
+        size: number(&transaction["size"]) as usize,
+        address: number(&transaction["addr"]),
+        data: number(&transaction["data"])
+    }).collect()
+}
+
+// The banks of the current mode only mirror R, so they're left out of the comparison
+fn differences(expected: &CpuSnapshot, actual: &CpuSnapshot) -> Vec<String> {
+    let mode = expected.cpsr & 0x1F;
+    let mut differences = Vec::new();
+    let mut compare = |name: &str, expected: &[u32], actual: &[u32]| {
+        if expected != actual {
+            differences.push(format!("{}: expected {:08X?}, got {:08X?}", name, expected, actual));
+        }
+    };
+    compare("R", &expected.registers, &actual.registers);
+    compare("CPSR", &[expected.cpsr], &[actual.cpsr]);
+    compare("SPSR", &expected.spsr, &actual.spsr);
+    compare("pipeline", &expected.pipeline, &actual.pipeline);
+    if mode != FIQ_MODE {
+        compare("R_fiq", &expected.fiq_registers, &actual.fiq_registers);
+    }
+    for (bank_mode, name, expected, actual) in [
+        (SUPERVISOR_MODE, "R_svc", &expected.supervisor_registers, &actual.supervisor_registers),
+        (ABORT_MODE, "R_abt", &expected.abort_registers, &actual.abort_registers),
+        (IRQ_MODE, "R_irq", &expected.irq_registers, &actual.irq_registers),
+        (UNDEFINED_MODE, "R_und", &expected.undefined_registers, &actual.undefined_registers)
+    ] {
+        if mode != bank_mode {
+            compare(name, expected, actual);
+        }
+    }
+    differences
+}
+
+// Returns what went wrong, nothing if the case passed
+fn run_case(case: &Value) -> Vec<String> {
+    let initial = parse_state(&case["initial"]);
+    let expected_final = parse_state(&case["final"]);
+    let transactions = parse_transactions(&case["transactions"]);
+
+    let mut cpu = Cpu::new();
+    cpu.set_hle_bios(false);
+    cpu.restore(&initial);
+    let mut bus = MockBus::new(transactions.clone());
+    cpu.next(&mut bus);
+
+    let mut problems = differences(&expected_final, &cpu.snapshot());
+    if let Some(fault) = cpu.fault() {
+        problems.push(format!("CPU fault: {}", fault));
+    }
+    if bus.transactions != transactions {
+        problems.push(format!("transactions: expected {:X?}, got {:X?}", transactions, bus.transactions));
+    }
+    problems
+}
+
+fn test_files() -> Vec<PathBuf> {
+    let directory = env::var_os("SINGLE_STEP_TESTS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/data/single_step"));
+    let mut files: Vec<PathBuf> = fs::read_dir(&directory)
+        .unwrap_or_else(|error| panic!("{}: {}", directory.display(), error))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+        .collect();
+    files.sort();
+    files
+}
+
+#[test]
+fn single_step_vectors() {
+    let mut cases = 0;
+    let mut failures = 0;
+    for path in test_files() {
+        let text = fs::read_to_string(&path).unwrap();
+        let file: Value = serde_json::from_str(&text).unwrap_or_else(|error| panic!("{}: {}", path.display(), error));
+        for (index, case) in file.as_array().expect("Expected an array of cases").iter().enumerate() {
+            cases += 1;
+            let problems = run_case(case);
+            if problems.is_empty() {
+                continue;
+            }
+            failures += 1;
+            if failures <= MAX_REPORTED_FAILURES {
+                let opcode = case["opcode"].as_u64().unwrap_or(0);
+                eprintln!("{} case {} (opcode {:08X}):", path.display(), index, opcode);
+                for problem in problems {
+                    eprintln!("    {}", problem);
+                }
+            }
+        }
+    }
+    assert!(cases > 0, "No test cases found");
+    assert_eq!(failures, 0, "{} of {} cases failed", failures, cases);
+}
+
+#[test]
+fn snapshot_round_trips_through_every_mode() {
+    for mode in [USER_MODE, FIQ_MODE, IRQ_MODE, SUPERVISOR_MODE, ABORT_MODE, UNDEFINED_MODE, SYSTEM_MODE] {
+        let mut snapshot = CpuSnapshot {
+            registers: std::array::from_fn(|index| index as u32),
+            cpsr: mode,
+            spsr: [0x11, 0x12, 0x13, 0x17, 0x1B],
+            user_registers: std::array::from_fn(|index| 0x100 + index as u32),
+            fiq_registers: std::array::from_fn(|index| 0x200 + index as u32),
+            supervisor_registers: [0x300, 0x301],
+            abort_registers: [0x400, 0x401],
+            irq_registers: [0x500, 0x501],
+            undefined_registers: [0x600, 0x601],
+            pipeline: [0xE1A0_0000, 0xE1A0_0000]
+        };
+        let mut cpu = Cpu::new();
+        cpu.restore(&snapshot);
+        // Registers of the current mode always read back as R, and R8-R12 are shared outside FIQ mode
+        let registers = snapshot.registers;
+        match mode {
+            FIQ_MODE => snapshot.fiq_registers.copy_from_slice(&registers[8..15]),
+            IRQ_MODE => snapshot.irq_registers.copy_from_slice(&registers[13..15]),
+            SUPERVISOR_MODE => snapshot.supervisor_registers.copy_from_slice(&registers[13..15]),
+            ABORT_MODE => snapshot.abort_registers.copy_from_slice(&registers[13..15]),
+            UNDEFINED_MODE => snapshot.undefined_registers.copy_from_slice(&registers[13..15]),
+            _ => snapshot.user_registers[5..].copy_from_slice(&registers[13..15]),
+        }
+        if mode != FIQ_MODE {
+            snapshot.user_registers[..5].copy_from_slice(&registers[8..13]);
+        }
+        assert_eq!(cpu.snapshot(), snapshot, "mode {:02X}", mode);
+    }
+}
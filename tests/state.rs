@@ -0,0 +1,71 @@
+use dees_nuts::cheats::{Cheat, CheatFormat};
+use dees_nuts::{crc32, Gba, GbaError};
+
+// mov r0, #1; add r0, r0, #1; b .-4, then a word of data
+const PROGRAM: [u32; 4] = [0xE3A0_0001, 0xE280_0001, 0xEAFF_FFFD, 0];
+
+fn gba_with(program: &[u32]) -> Gba {
+    let mut gba = Gba::new();
+    gba.load_rom(program.iter().flat_map(|word| word.to_le_bytes()).collect()).unwrap();
+    gba
+}
+
+#[test]
+fn crc32_matches_the_standard_check_value() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    assert_eq!(crc32(b""), 0);
+}
+
+#[test]
+fn states_round_trip() {
+    let mut gba = gba_with(&PROGRAM);
+    gba.frame().unwrap();
+    let state = gba.save_state();
+    let registers = *gba.cpu().registers();
+    gba.frame().unwrap();
+    gba.load_state(&state).unwrap();
+    assert_eq!(*gba.cpu().registers(), registers);
+    assert_eq!(gba.save_state(), state);
+}
+
+#[test]
+fn states_identify_the_rom_as_loaded_not_as_patched_by_cheats() {
+    let mut gba = gba_with(&PROGRAM);
+    gba.cheats_mut().add(Cheat::new("Patch", CheatFormat::Raw, &["0800000C:1234"]).unwrap());
+    gba.frame().unwrap();
+    assert_eq!(gba.memory().peek_byte(0x0800_000C), Some(0x34));
+    let crc = gba.rom_info().crc;
+    let state = gba.save_state();
+    assert_eq!(gba.rom_info().crc, crc);
+    gba.load_state(&state).unwrap();
+
+    let mut other = gba_with(&[0xE3A0_0002, 0xEAFF_FFFE]);
+    assert!(matches!(other.load_state(&state), Err(GbaError::StateRomMismatch { .. })));
+}
+
+// b . followed by a header whose title tells the images apart
+fn multiboot_image(title: &[u8]) -> Vec<u8> {
+    let mut image = vec![0; 0x100];
+    image[0..4].copy_from_slice(&0xEAFF_FFFEu32.to_le_bytes());
+    image[0xA0..0xA0 + title.len()].copy_from_slice(title);
+    image
+}
+
+#[test]
+fn states_tell_multiboot_images_apart() {
+    let mut gba = Gba::new();
+    gba.load_multiboot(multiboot_image(b"FIRST")).unwrap();
+    gba.frame().unwrap();
+    let state = gba.save_state();
+
+    let mut other = Gba::new();
+    other.load_multiboot(multiboot_image(b"SECOND")).unwrap();
+    match other.load_state(&state) {
+        Err(GbaError::StateRomMismatch { state, rom }) => assert_eq!((state.as_str(), rom.as_str()), ("FIRST", "SECOND")),
+        _ => panic!("loaded a state from another multiboot image"),
+    }
+
+    let mut same = Gba::new();
+    same.load_multiboot(multiboot_image(b"FIRST")).unwrap();
+    same.load_state(&state).unwrap();
+}
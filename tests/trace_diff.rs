@@ -0,0 +1,137 @@
+use std::process::Command;
+use std::{env, fs};
+
+const SYSTEM_MODE: u32 = 0x1F;
+const SUPERVISOR_MODE: u32 = 0x13;
+
+// R0-R15, CPSR and SPSR of one instruction, the PC 8 past mov r0, #0 at 0x08000000 + 4 * index
+fn record(index: u32) -> [u32; 18] {
+    let mut values = [0; 18];
+    values[15] = 0x0800_0008 + 4 * index;
+    values[16] = SYSTEM_MODE;
+    values
+}
+
+fn binary(records: &[[u32; 18]]) -> Vec<u8> {
+    records.iter().flatten().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+// The reference layout, which has no SPSR
+fn reference(records: &[[u32; 18]]) -> Vec<u8> {
+    let mut text = String::new();
+    for values in records {
+        for value in &values[..16] {
+            text.push_str(&format!("{:08X} ", value));
+        }
+        text.push_str(&format!("cpsr: {:08X} | E3A00000: mov r0, #0x0\n", values[16]));
+    }
+    text.into_bytes()
+}
+
+// Runs trace_diff on the two traces, returns the exit code and what it printed
+fn diff(name: &str, ours: Vec<u8>, theirs: Vec<u8>, options: &[&str]) -> (i32, String) {
+    let directory = env::temp_dir();
+    let ours_path = directory.join(format!("dees_nuts_{}_{}_ours.trace", name, std::process::id()));
+    let theirs_path = directory.join(format!("dees_nuts_{}_{}_reference.trace", name, std::process::id()));
+    fs::write(&ours_path, ours).unwrap();
+    fs::write(&theirs_path, theirs).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_trace_diff"))
+        .arg(&ours_path)
+        .arg(&theirs_path)
+        .args(options)
+        .output()
+        .unwrap();
+    fs::remove_file(ours_path).unwrap();
+    fs::remove_file(theirs_path).unwrap();
+    (output.status.code().unwrap(), String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn identical_traces_match() {
+    let records: Vec<[u32; 18]> = (0..3).map(record).collect();
+    let (code, report) = diff("identical", binary(&records), binary(&records), &[]);
+    assert_eq!(code, 0);
+    assert_eq!(report.trim(), "Traces match (3 instructions)");
+}
+
+#[test]
+fn the_first_mismatch_is_reported_with_its_context() {
+    let ours: Vec<[u32; 18]> = (0..6).map(record).collect();
+    let mut theirs = ours.clone();
+    theirs[2][3] = 0x1234;
+    theirs[4][5] = 1;
+    let (code, report) = diff("mismatch", binary(&ours), binary(&theirs), &["--context", "1"]);
+    assert_eq!(code, 1);
+    let lines: Vec<&str> = report.lines().collect();
+    assert_eq!(lines[0], "First divergence at instruction #2: r3 is 00000000, reference has 00001234");
+    // One instruction either side, the later mismatch is out of the context
+    assert!(lines[2].starts_with("  #1        ours: 08000004:"), "{}", report);
+    assert!(lines[4].starts_with("> #2        ours: 08000008:"), "{}", report);
+    assert_eq!(lines[6].trim(), "differs: r3=00000000/00001234");
+    assert!(lines[7].starts_with("  #3        ours: 0800000C:"), "{}", report);
+    assert_eq!(lines.len(), 9);
+}
+
+#[test]
+fn masked_registers_are_skipped_everywhere() {
+    let ours: Vec<[u32; 18]> = (0..3).map(record).collect();
+    let mut theirs = ours.clone();
+    theirs[1][3] = 0x1234;
+    let (code, _) = diff("masked", binary(&ours), binary(&theirs), &["--mask", "r3"]);
+    assert_eq!(code, 0);
+
+    // The differs line leaves the masked register out too
+    theirs[1][14] = 0x0800_0100;
+    let (code, report) = diff("masked_context", binary(&ours), binary(&theirs), &["--mask", "r3,cpsr"]);
+    assert_eq!(code, 1);
+    assert!(report.starts_with("First divergence at instruction #1: r14 is 00000000, reference has 08000100"), "{}", report);
+    assert!(report.contains("differs: r14=00000000/08000100\n"), "{}", report);
+    // lr is another name for r14
+    let (code, _) = diff("masked_alias", binary(&ours), binary(&theirs), &["--mask", "r3,lr"]);
+    assert_eq!(code, 0);
+}
+
+#[test]
+fn undefined_spsrs_can_be_ignored() {
+    let ours: Vec<[u32; 18]> = (0..2).map(record).collect();
+    let mut theirs = ours.clone();
+    // Garbage in the SPSR of system mode, which has none
+    theirs[1][17] = 0xDEAD;
+    let (code, report) = diff("spsr", binary(&ours), binary(&theirs), &[]);
+    assert_eq!(code, 1);
+    assert!(report.starts_with("First divergence at instruction #1: spsr"), "{}", report);
+    let (code, _) = diff("spsr_ignored", binary(&ours), binary(&theirs), &["--ignore-undefined-spsr"]);
+    assert_eq!(code, 0);
+
+    // Supervisor mode has an SPSR, so it still counts
+    let mut ours = ours;
+    ours[1][16] = SUPERVISOR_MODE;
+    theirs[1][16] = SUPERVISOR_MODE;
+    let (code, _) = diff("spsr_defined", binary(&ours), binary(&theirs), &["--ignore-undefined-spsr"]);
+    assert_eq!(code, 1);
+}
+
+#[test]
+fn binary_traces_compare_against_reference_logs() {
+    let ours: Vec<[u32; 18]> = (0..3).map(record).collect();
+    let mut theirs = ours.clone();
+    // The reference layout has no SPSR to compare
+    theirs[2][17] = 0xDEAD;
+    let (code, report) = diff("formats", binary(&ours), reference(&theirs), &[]);
+    assert_eq!(code, 0, "{}", report);
+
+    theirs[2][0] = 7;
+    let (code, report) = diff("formats_mismatch", binary(&ours), reference(&theirs), &[]);
+    assert_eq!(code, 1);
+    assert!(report.starts_with("First divergence at instruction #2: r0 is 00000000, reference has 00000007"), "{}", report);
+    // The opcode comes from the reference line
+    assert!(report.contains("ref:  08000008: E3A00000  "), "{}", report);
+}
+
+#[test]
+fn traces_of_different_lengths_match_up_to_the_shorter_one() {
+    let records: Vec<[u32; 18]> = (0..4).map(record).collect();
+    let (code, report) = diff("lengths", binary(&records[..3]), binary(&records), &[]);
+    assert_eq!(code, 1);
+    assert_eq!(report.trim(), "Traces match for 3 instructions, but ours has 3 and the reference has 4");
+}